@@ -0,0 +1,56 @@
+//throughput of SendStateMachine and RecvController over the in-memory transport
+//of tftp_protocol::testutil; no sockets involved
+//  cargo bench --features testutil
+
+#[macro_use]
+extern crate num_derive;
+
+use std::io::Cursor;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+//the binary crate has no lib target; the protocol modules are compiled into the bench
+#[allow(dead_code, unused_macros, unused_imports)]
+#[path = "../src"]
+mod src {
+    pub mod tlog;
+    pub mod tftp_protocol;
+}
+use src::{tlog, tftp_protocol};
+
+use tftp_protocol::testutil::{transfer_counted, LossyChannel};
+
+const DATA_LEN:    usize                  = 1024 * 1024;
+const BLKSIZE:     usize                  = 1024;
+const WINDOWSIZES: [usize; 4]             = [1, 4, 16, 32];
+const LOSS:        [(&str, usize); 2]     = [("lossless", 0), ("loss-2pct", 50)];   //every nth DATA is dropped
+
+fn run(data: &[u8], windowsize: usize, drop_nth: usize) -> usize {
+    let mut reader = Cursor::new(data);
+    let (received, retransmits) = transfer_counted(&mut reader, BLKSIZE, windowsize, 0, LossyChannel::new(drop_nth, false), LossyChannel::lossless()).unwrap();
+    assert_eq!(received.len(), data.len());
+    return retransmits;
+}
+
+fn windowsize(c: &mut Criterion) {
+    let data: Vec<u8> = (0..DATA_LEN).map(|x| x as u8).collect();
+
+    for (name, drop_nth) in LOSS {
+        let mut group = c.benchmark_group(format!("windowsize/{}", name));
+        group.throughput(Throughput::Bytes(DATA_LEN as u64));
+        group.sample_size(10);
+
+        for windowsize in WINDOWSIZES {
+            //the channel drops deterministically; the count is the same for every iteration
+            println!("windowsize/{}/{}: retransmits = {}", name, windowsize, run(&data, windowsize, drop_nth));
+
+            group.bench_with_input(BenchmarkId::from_parameter(windowsize), &windowsize, |b, &windowsize| {
+                b.iter(|| run(&data, windowsize, drop_nth));
+            });
+        }
+        group.finish();
+    }
+}
+
+criterion_group!(benches, windowsize);
+criterion_main!(benches);
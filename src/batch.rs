@@ -0,0 +1,82 @@
+use std::{io::{self, ErrorKind}, net::{SocketAddr, UdpSocket}};
+
+//--batch-sends: the frames of a window are handed to the kernel with one sendmmsg call
+//instead of one send per frame; other platforms fall back to the loop.
+//remote is None for a connected socket
+#[cfg(target_os = "linux")]
+pub fn send_batch(socket: &UdpSocket, remote: Option<SocketAddr>, frames: &[Vec<u8>]) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let addr = remote.map(socket2::SockAddr::from);
+
+    let mut iovecs: Vec<libc::iovec> = frames.iter().map(|x| libc::iovec {
+        iov_base: x.as_ptr() as *mut libc::c_void,
+        iov_len:  x.len(),
+    }).collect();
+
+    let mut msgs: Vec<libc::mmsghdr> = iovecs.iter_mut().map(|x| {
+        let mut msg: libc::mmsghdr = unsafe { std::mem::zeroed() };
+        if let Some(addr) = &addr {
+            msg.msg_hdr.msg_name    = addr.as_ptr() as *mut libc::c_void;
+            msg.msg_hdr.msg_namelen = addr.len();
+        }
+        msg.msg_hdr.msg_iov    = x as *mut libc::iovec;
+        msg.msg_hdr.msg_iovlen = 1;
+        msg
+    }).collect();
+
+    //the kernel may take only a part of the batch; the rest is sent with the next call
+    let mut sent = 0;
+    while sent < msgs.len() {
+        let result = unsafe {
+            libc::sendmmsg(socket.as_raw_fd(), msgs[sent..].as_mut_ptr(), (msgs.len() - sent) as libc::c_uint, 0)
+        };
+        if result < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(err);
+        }
+        sent += result as usize;
+    }
+
+    return Ok(());
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn send_batch(socket: &UdpSocket, remote: Option<SocketAddr>, frames: &[Vec<u8>]) -> io::Result<()> {
+    for i_frame in frames {
+        match remote {
+            Some(remote) => socket.send_to(i_frame, remote)?,
+            None         => socket.send(i_frame)?,
+        };
+    }
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn batch_arrives_in_order() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        receiver.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+
+        let frames: Vec<Vec<u8>> = (0..40u8).map(|x| vec![x; 1 + x as usize]).collect();
+        send_batch(&sender, Some(receiver.local_addr().unwrap()), &frames).unwrap();
+
+        sender.connect(receiver.local_addr().unwrap()).unwrap();
+        send_batch(&sender, None, &frames[..2]).unwrap();
+
+        let mut buf = [0u8; 64];
+        for i_frame in frames.iter().chain(frames[..2].iter()) {
+            let (len, src) = receiver.recv_from(&mut buf).unwrap();
+            assert_eq!(src, sender.local_addr().unwrap());
+            assert_eq!(&buf[..len], &i_frame[..]);
+        }
+    }
+}
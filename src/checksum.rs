@@ -0,0 +1,82 @@
+use md5::Md5;
+use sha2::{Digest, Sha256};
+
+pub const MD5_HEX_LEN:    usize = 32;
+pub const SHA256_HEX_LEN: usize = 64;
+
+pub enum Checksum {
+    Md5(Md5),
+    Sha256(Sha256),
+}
+
+impl Checksum {
+    pub fn new_sha256() -> Checksum {
+        Checksum::Sha256(Sha256::new())
+    }
+
+    pub fn from_name(name: &str) -> Option<Checksum> {
+        return match name {
+            "md5"    => Some(Checksum::Md5(Md5::new())),
+            "sha256" => Some(Checksum::Sha256(Sha256::new())),
+            _        => None,
+        };
+    }
+
+    //the algorithm is derived from the length of the expected hex string
+    pub fn for_expected(expected: &str) -> Option<Checksum> {
+        if !expected.chars().all(|x| x.is_ascii_hexdigit()) {
+            return None;
+        }
+
+        return match expected.len() {
+            MD5_HEX_LEN    => Some(Checksum::Md5(Md5::new())),
+            SHA256_HEX_LEN => Some(Checksum::Sha256(Sha256::new())),
+            _              => None,
+        };
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Checksum::Md5(x)    => x.update(data),
+            Checksum::Sha256(x) => x.update(data),
+        }
+    }
+
+    pub fn finalize_hex(self) -> String {
+        return match self {
+            Checksum::Md5(x)    => format!("{:x}", x.finalize()),
+            Checksum::Sha256(x) => format!("{:x}", x.finalize()),
+        };
+    }
+
+    pub fn name(&self) -> &'static str {
+        return match self {
+            Checksum::Md5(_)    => "md5",
+            Checksum::Sha256(_) => "sha256",
+        };
+    }
+}
+
+//feeds everything read from inner into the checksum
+pub struct ChecksumReader<'a> {
+    inner:    &'a mut dyn std::io::Read,
+    checksum: Checksum,
+}
+
+impl<'a> ChecksumReader<'a> {
+    pub fn new(inner: &'a mut dyn std::io::Read, checksum: Checksum) -> ChecksumReader<'a> {
+        ChecksumReader { inner: inner, checksum: checksum }
+    }
+
+    pub fn into_checksum(self) -> Checksum {
+        return self.checksum;
+    }
+}
+
+impl<'a> std::io::Read for ChecksumReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.checksum.update(&buf[0..len]);
+        return Ok(len);
+    }
+}
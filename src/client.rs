@@ -1,21 +1,55 @@
-use std::{time::{Duration}, fs::File, io::{Read, Write}, path::{PathBuf}, str::FromStr, env};
+use std::{time::{Duration, Instant}, fs::{self, File}, io::{Read, Write, ErrorKind}, path::{Path, PathBuf}, str::FromStr, env};
 
 use clap::ArgMatches;
-use std::net::UdpSocket;
+use flate2::{Compression, read::DeflateEncoder, write::DeflateDecoder};
+use std::net::{UdpSocket, SocketAddr, SocketAddrV6, ToSocketAddrs, IpAddr, Ipv4Addr, Ipv6Addr};
+use crate::batch;
+use crate::checksum::{Checksum, ChecksumReader};
 use crate::{tftp_protocol::{Opcode,PacketBuilder, 
-    TransferMode, Timeout, RECV_TIMEOUT, self, DEFAULT_BLOCKSIZE, 
-    PACKET_SIZE_MAX, PacketParser, DEFAULT_WINDOWSIZE, BLKSIZE_STR, WINDOW_STR, filter_extended_options, RecvStateMachine, SendStateMachine, SendAction, SEND_RECV_BLOCK_TIMEOUT, RecvController}, tlog};
+    TransferMode, Timeout, RECV_TIMEOUT, HANDSHAKE_TIMEOUT, RETRY_COUNT, self, DEFAULT_BLOCKSIZE, 
+    DATA_OFFSET, PacketParser, DEFAULT_WINDOWSIZE, BLKSIZE_STR, WINDOW_STR, ROLLOVER_STR, UTIMEOUT_STR, TSIZE_STR, OFFSET_STR, XCOMPRESS_STR, XCOMPRESS_DEFLATE, filter_extended_options, SendStateMachine, SendAction, SEND_RECV_BLOCK_TIMEOUT, RecvController, MANIFEST_NAME, describe_packet, hexdump, NegotiatedOptions, ErrorResponse, ErrorNumber, TransferError, BackoffStrategy, CancelToken}, tlog};
 
-struct ClientArguments {
-    remote:     String,
-    blksize:    usize,
-    windowsize: usize,
+#[derive(Clone)]
+pub struct ClientArguments {
+    remote:       String,
+    port:         u16,      //used when --remote has no port
+    blksize:      usize,
+    windowsize:   usize,
+    rollover:     Option<u16>,
+    verify:       Option<String>,
+    print_digest: bool,
+    deadline:     Option<Instant>,
+    trace:        bool,
+    keep_partial: bool,
+    max_redirects: usize,
+    local_ports:  Option<(u16, u16)>,
+    backoff:      BackoffStrategy,
+    no_options:   bool,
+    quiet:        bool,
+    cancel:       Option<CancelToken>,  //for embedders; stops the transfer in progress
+    inject_delay: Option<Duration>,
+    clobber:      bool,
+    interleave_acks: bool,
+    batch_sends:  bool,
+    utimeout:     Option<Duration>,     //requested; replaced by the acknowledged value after the handshake
+    request_tsize: bool,
+    tsize:        Option<u64>,          //requested; replaced by the acknowledged value; None is an unknown size
+    resume:       bool,
+    offset:       Option<u64>,          //requested; replaced by the acknowledged value
+    xcompress:    bool,                 //requested; replaced by the acknowledged value
 }
 
 impl ClientArguments {
     fn new(args: &ArgMatches) -> ClientArguments {
         ClientArguments {
             remote:  (args.get_one::<String>("remote").expect("invalid remote")).clone(),
+            port: {
+                if let Some(port) = args.get_one::<String>("port") {
+                    u16::from_str_radix(&*port, 10).expect("port value invalid")
+                } else {
+                    DEFAULT_PORT
+                }
+            },
             blksize: {
                 if let Some(blksize ) = args.get_one::<String>("blksize") {
                     usize::from_str_radix(&*blksize, 10).expect("blksize value invalid")
@@ -29,27 +63,379 @@ impl ClientArguments {
                 } else {
                     DEFAULT_WINDOWSIZE
                 }
+            },
+            rollover: {
+                if let Some(rollover) = args.get_one::<String>("rollover") {
+                    Some(u16::from_str_radix(&*rollover, 10).expect("rollover value invalid"))
+                } else {
+                    None
+                }
+            },
+            verify: {
+                if let Some(verify) = args.get_one::<String>("verify") {
+                    Checksum::for_expected(verify).expect("verify value is neither a md5 nor a sha256 hex digest");
+                    Some(verify.to_lowercase())
+                } else {
+                    None
+                }
+            },
+            print_digest: args.get_flag("print-digest"),
+            deadline: {
+                if let Some(timeout_total) = args.get_one::<String>("timeout-total") {
+                    let secs = u64::from_str_radix(&*timeout_total, 10).expect("timeout-total value invalid");
+                    Some(Instant::now() + Duration::from_secs(secs))
+                } else {
+                    None
+                }
+            },
+            trace: args.get_flag("trace"),
+            //there is nothing to continue without the part file
+            keep_partial: args.get_flag("keep-partial") || args.get_flag("continue"),
+            local_ports: {
+                if let Some(range) = args.get_one::<String>("local-port-range") {
+                    let (low, high) = range.split_once('-').expect("local-port-range must be LOW-HIGH");
+                    let low  = u16::from_str_radix(low.trim(), 10).expect("local-port-range value invalid");
+                    let high = u16::from_str_radix(high.trim(), 10).expect("local-port-range value invalid");
+                    assert!(low <= high, "local-port-range LOW is greater than HIGH");
+                    Some((low, high))
+                } else {
+                    None
+                }
+            },
+            no_options: args.get_flag("no-options"),
+            quiet: args.get_flag("quiet"),
+            cancel: None,
+            clobber: args.get_flag("clobber"),
+            interleave_acks: args.get_flag("interleave-acks"),
+            batch_sends: args.get_flag("batch-sends"),
+            request_tsize: args.get_flag("tsize"),
+            tsize: None,
+            resume: args.get_flag("continue"),
+            offset: None,
+            xcompress: args.get_flag("xcompress"),
+            utimeout: args.get_one::<String>("utimeout")
+                .map(|x| Duration::from_micros(u64::from_str_radix(x, 10).expect("utimeout value invalid"))),
+            inject_delay: args.get_one::<String>("inject-delay-ms")
+                .map(|x| Duration::from_millis(u64::from_str_radix(x, 10).expect("inject-delay-ms value invalid"))),
+            backoff: BackoffStrategy::from_name(args.get_one::<String>("backoff").unwrap()).expect("backoff value invalid"),
+            max_redirects: {
+                if let Some(max_redirects) = args.get_one::<String>("follow-redirect") {
+                    usize::from_str_radix(&*max_redirects, 10).expect("follow-redirect value invalid")
+                } else {
+                    0
+                }
+            },
+        }
+    }
+
+    fn is_expired(&self) -> bool {
+        return self.deadline.map_or(false, |x| Instant::now() >= x);
+    }
+
+    fn checksum(&self) -> Option<Checksum> {
+        if let Some(verify) = &self.verify {
+            return Checksum::for_expected(verify);
+        }
+        if self.print_digest {
+            return Some(Checksum::new_sha256());
+        }
+        return None;
+    }
+}
+
+//outcome of a single transfer; see exit_code for the exit status of the client
+#[derive(Debug)]
+pub enum ClientError {
+    Timeout(String),
+    Remote(ErrorResponse),   //ERROR packet received from the server
+    Verify(String),
+    NoServer(String),
+    Other(String),
+}
+
+impl ClientError {
+    fn exit_code(&self) -> i32 {
+        return match self {
+            ClientError::Timeout(_)   => 2,
+            ClientError::Remote(err)  => match err.number {
+                ErrorNumber::FileNotFound      => 3,
+                ErrorNumber::AccessViolation   => 4,
+                ErrorNumber::DiskFull          => 5,
+                ErrorNumber::FileAlreadyExists => 6,
+                _                              => 1,
+            },
+            ClientError::Verify(_)    => 7,
+            ClientError::NoServer(_)  => 8,
+            ClientError::Other(_)     => 1,
+        };
+    }
+}
+
+impl std::fmt::Display for ClientError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ClientError::Timeout(msg) => write!(f, "{}", msg),
+            ClientError::Remote(err)  => write!(f, "{}", err),
+            ClientError::Verify(msg)  => write!(f, "{}", msg),
+            ClientError::NoServer(msg) => write!(f, "{}", msg),
+            ClientError::Other(msg)   => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl From<TransferError> for ClientError {
+    fn from(err: TransferError) -> Self {
+        match err {
+            TransferError::Timeout      => ClientError::Timeout("timeout".into()),
+            TransferError::Remote(err)  => ClientError::Remote(err),
+            TransferError::Aborted(msg) => ClientError::Other(msg),
+            TransferError::Cancelled    => ClientError::Other(err.to_string()),
+            TransferError::Sink(_)      => ClientError::Other(err.to_string()),
+        }
+    }
+}
+
+//returns the exit code of the first failed transfer or 0
+pub fn client_main(args: &ArgMatches) -> i32 {
+    let client_arguments = ClientArguments::new(args);
+
+    if args.get_flag("probe") {
+        return print_summary(&vec![(PathBuf::from(PROBE_NAME), probe(&client_arguments))]);
+    }
+
+    let results = if let Some(values) = args.get_many::<String>("download-dir") {
+        download_dir(values.collect(), args.get_one::<String>("manifest"), &client_arguments)
+    } else {
+        let opcode = match (args.get_many::<String>("download"), args.get_many::<String>("upload")) {
+            (Some(_), None) => Opcode::Read,
+            (None, Some(_)) => Opcode::Write,
+            _               => panic!("invalid client action; only --read or --write possible")
+        };
+
+        let mut results = Vec::new();
+        for i_paths in get_connection_paths(opcode, args) {
+            let result = if args.get_flag("print-oack") {
+                print_oack(opcode, &i_paths, &client_arguments)
+            } else if opcode == Opcode::Write && i_paths.local == Path::new(STDIN_PATH) {
+                upload_stdin(&i_paths, &client_arguments)
+            } else {
+                transfer(opcode, &i_paths, &client_arguments)
+            };
+            results.push((i_paths.remote, result));
+        }
+        results
+    };
+
+    return print_summary(&results);
+}
+
+//returns the exit code of the first failed transfer or 0
+fn print_summary(results: &Vec<(PathBuf, Result<(), ClientError>)>) -> i32 {
+    let mut exit_code = 0;
+
+    for (path, result) in results {
+        if let Err(err) = result {
+            tlog::error!("{:?} failed; {}", path, err);
+            if exit_code == 0 {
+                exit_code = err.exit_code();
             }
+        } else if results.len() > 1 {
+            tlog::info!("{:?} ok", path);
+        }
+    }
+
+    return exit_code;
+}
+
+const DEFAULT_PORT: u16 = 69;
+
+//parses --remote; accepted are host, host:port, ipv4, ipv4:port, ipv6, [ipv6] and [ipv6]:port.
+//link-local ipv6 addresses need the zone e.g "fe80::1%eth0" or "[fe80::1%eth0]:69"
+//which is not understood by every platform's resolver, so those are parsed here
+fn parse_remote(remote: &str, default_port: u16) -> std::io::Result<SocketAddr> {
+    let invalid = || std::io::Error::new(ErrorKind::InvalidInput, format!("invalid remote {}", remote));
+    let parse_port = |x: &str| u16::from_str(x).map_err(|_| invalid());
+
+    let (host, port) = if let Some(bracketed) = remote.strip_prefix('[') {
+        let (host, rest) = if let Some(x) = bracketed.split_once(']') {x} else {return Err(invalid());};
+        let port = match rest.strip_prefix(':') {
+            Some(x) => parse_port(x)?,
+            None if rest.is_empty() => default_port,
+            None => return Err(invalid()),
+        };
+        (host, port)
+    } else if remote.matches(':').count() > 1 {
+        //more than one colon is only valid for a bare ipv6 address
+        (remote, default_port)
+    } else if let Some((host, port)) = remote.rsplit_once(':') {
+        (host, parse_port(port)?)
+    } else {
+        (remote, default_port)
+    };
+
+    if host.is_empty() {
+        return Err(invalid());
+    }
+
+    if let Some((ip, scope)) = host.split_once('%') {
+        let ip = Ipv6Addr::from_str(ip).map_err(|_| invalid())?;
+        let scope_id = if let Ok(x) = u32::from_str(scope) {x} else {interface_index(scope)?};
+        return Ok(SocketAddr::V6(SocketAddrV6::new(ip, port, scope_id, 0)));
+    }
+
+    if let Ok(ip) = IpAddr::from_str(host) {
+        return Ok(SocketAddr::new(ip, port));
+    }
+
+    return (host, port).to_socket_addrs()?.next().ok_or_else(invalid);
+}
+
+#[cfg(unix)]
+fn interface_index(name: &str) -> std::io::Result<u32> {
+    let c_name = std::ffi::CString::new(name).map_err(|_| std::io::Error::from(ErrorKind::InvalidInput))?;
+    let index = unsafe { libc::if_nametoindex(c_name.as_ptr()) };
+    if index == 0 {
+        return Err(std::io::Error::new(ErrorKind::NotFound, format!("unknown interface {}", name)));
+    }
+    return Ok(index);
+}
+
+//other platforms only accept the numeric zone
+#[cfg(not(unix))]
+fn interface_index(name: &str) -> std::io::Result<u32> {
+    return Err(std::io::Error::new(ErrorKind::InvalidInput, format!("zone must be numeric; got {}", name)));
+}
+
+//binds the first free port of --local-port-range or any port;
+//the address family has to match the remote
+fn bind_local(remote: &SocketAddr, local_ports: Option<(u16, u16)>) -> std::io::Result<UdpSocket> {
+    let local_ip = match remote {
+        SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+    let (low, high) = if let Some(x) = local_ports {x} else {return UdpSocket::bind((local_ip, 0))};
+
+    let mut last_err = None;
+    for i_port in low..=high {
+        match UdpSocket::bind((local_ip, i_port)) {
+            Ok(x)    => return Ok(x),
+            Err(err) => last_err = Some(err),
         }
     }
+
+    return Err(last_err.unwrap());
 }
 
-pub fn client_main(args: &ArgMatches) {
-    let opcode = match (args.get_many::<String>("download"), args.get_many::<String>("upload")) {
-        (Some(_), None) => Opcode::Read,
-        (None, Some(_)) => Opcode::Write,
-        _               => panic!("invalid client action; only --read or --write possible")
+//linux reports icmp errors only to connected sockets; IP_RECVERR also reports them
+//for the request which is sent before the transfer id of the server is known
+#[cfg(target_os = "linux")]
+fn enable_icmp_errors(socket: &UdpSocket) {
+    use std::os::unix::io::AsRawFd;
+
+    let (level, name) = match socket.local_addr() {
+        Ok(SocketAddr::V6(_)) => (libc::IPPROTO_IPV6, libc::IPV6_RECVERR),
+        _                     => (libc::IPPROTO_IP,   libc::IP_RECVERR),
+    };
+    let enable: libc::c_int = 1;
+    let result = unsafe {
+        libc::setsockopt(socket.as_raw_fd(), level, name, &enable as *const libc::c_int as *const libc::c_void, std::mem::size_of::<libc::c_int>() as libc::socklen_t)
     };
+    if result != 0 {
+        tlog::warning!("enable icmp errors failed; {}", std::io::Error::last_os_error());
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn enable_icmp_errors(_socket: &UdpSocket) {}
+
+fn connect(opcode: Opcode, remote: &Path, client_arguments: &ClientArguments) -> Result<(SocketSendRecv, ClientArguments), ClientError> {
+    let mut client_arguments = client_arguments.clone();
+
+    let remote_addr = parse_remote(&client_arguments.remote, client_arguments.port)
+        .map_err(|err| ClientError::Other(err.to_string()))?;
+    let socket = bind_local(&remote_addr, client_arguments.local_ports)
+        .map_err(|err| ClientError::Other(format!("bind to interface failed; {}", err)))?;
+    enable_icmp_errors(&socket);
+
+    let mut socket = SocketSendRecv::new(socket, remote_addr, client_arguments.deadline, client_arguments.trace);
+    socket.set_blksize(client_arguments.blksize);
+
+    //a download asks with 0; an upload announces the size of a regular file only
+    if client_arguments.request_tsize && opcode == Opcode::Read {
+        client_arguments.tsize = Some(0);
+    }
 
-    let      paths             = get_connection_paths(opcode, args);
-    let mut  client_arguments = ClientArguments::new(args);
+    let negotiated = send_initial_packet(opcode, remote, &client_arguments, &mut socket);
+    client_arguments.blksize    = negotiated.blksize;
+    client_arguments.windowsize = negotiated.windowsize;
+    client_arguments.rollover   = Some(negotiated.rollover);
+    client_arguments.utimeout   = negotiated.timeout;
+    client_arguments.tsize      = negotiated.tsize;
+    client_arguments.offset     = negotiated.offset;
+    client_arguments.xcompress  = negotiated.xcompress;
+    socket.set_blksize(negotiated.blksize);
 
-    let socket = UdpSocket::bind("127.0.0.1:0").expect("Bind to interface failed");
-    socket.connect(&client_arguments.remote).expect("Connection failed");
+    return Ok((socket, client_arguments));
+}
+
+//a server may answer with the custom error "REDIRECT host:port" to send the client elsewhere
+const REDIRECT_PREFIX: &str = "REDIRECT ";
+
+fn redirect_target(err: &ErrorResponse) -> Option<String> {
+    let msg = err.msg.as_ref()?;
+    return msg.strip_prefix(REDIRECT_PREFIX).map(|x| x.trim().to_string());
+}
+
+//repeats the request at the host of a redirect; at most --follow-redirect times
+fn transfer(opcode: Opcode, paths: &ClientFilePath, client_arguments: &ClientArguments) -> Result<(), ClientError> {
+    let mut arguments = client_arguments.clone();
+
+    //checked before the request so the server isn't bothered
+    if opcode == Opcode::Read && !arguments.clobber && paths.local.exists() {
+        return Err(ClientError::Other(format!("{:?} already exists; use --clobber to overwrite it", paths.local)));
+    }
+
+    for _ in 0..arguments.max_redirects {
+        let result = transfer_once(opcode, paths, &arguments);
+
+        let target = match &result {
+            Err(ClientError::Remote(err)) => redirect_target(err),
+            _                             => None,
+        };
+        let target = if let Some(x) = target {x} else {return result;};
+
+        tlog::info!("{:?} redirected from {} to {}", paths.remote, arguments.remote, target);
+        arguments.remote = target;
+    }
 
-    let mut socket = SocketSendRecv::new(socket);
+    let result = transfer_once(opcode, paths, &arguments);
+    if let Err(ClientError::Remote(err)) = &result {
+        if arguments.max_redirects > 0 && redirect_target(err).is_some() {
+            return Err(ClientError::Other(format!("more than {} redirects", arguments.max_redirects)));
+        }
+    }
+    return result;
+}
+
+//size of a regular file for tsize; a fifo or device has none
+fn upload_size(local: &Path, client_arguments: &ClientArguments) -> Option<u64> {
+    if !client_arguments.request_tsize {
+        return None;
+    }
+    return fs::metadata(local).ok().filter(|x| x.is_file()).map(|x| x.len());
+}
 
-    send_initial_packet(opcode, &paths, &mut client_arguments, &mut socket);
+fn transfer_once(opcode: Opcode, paths: &ClientFilePath, client_arguments: &ClientArguments) -> Result<(), ClientError> {
+    let mut client_arguments = client_arguments.clone();
+    if opcode == Opcode::Write {
+        client_arguments.tsize = upload_size(&paths.local, &client_arguments);
+    }
+    //--continue: the download starts after the bytes of an existing part file
+    if opcode == Opcode::Read && client_arguments.resume {
+        client_arguments.offset = fs::metadata(partial_path(&paths.local)).ok().map(|x| x.len()).filter(|x| *x > 0);
+    }
+    let requested_offset = client_arguments.offset;
+    let (mut socket, client_arguments) = connect(opcode, &paths.remote, &client_arguments)?;
 
     let mut timeout = Timeout::new(RECV_TIMEOUT);
 
@@ -60,27 +446,367 @@ pub fn client_main(args: &ArgMatches) {
 
         match opcode {
             Opcode::Read => {
-                let mut file = File::create(paths.local).expect("Cannot write file");
-                download_action(&mut socket, &mut file, &client_arguments);
-                break;
+                //a rejected request leaves no local file; not even with --keep-partial
+                if let Some(err) = request_rejected(&socket) {
+                    return Err(err);
+                }
+                if client_arguments.request_tsize {
+                    match client_arguments.tsize {
+                        Some(tsize) => tlog::info!("{:?} size = {} bytes", paths.remote, tsize),
+                        None        => tlog::info!("{:?} size unknown", paths.remote),
+                    }
+                }
+
+                //a server without the offset option sends the whole file; the part file is written again
+                let is_resumed = requested_offset.is_some() && client_arguments.offset == requested_offset;
+                if let (Some(offset), true) = (requested_offset, is_resumed) {
+                    tlog::info!("{:?} continue at offset {}", paths.remote, offset);
+                } else if requested_offset.is_some() {
+                    tlog::info!("{:?} server ignored the offset; download from the start", paths.remote);
+                }
+
+                let part = partial_path(&paths.local);
+                let mut file = LazyFile::new(&part, is_resumed);
+                let start  = Instant::now();
+                let result = download_action(&mut socket, &mut file, &client_arguments).map(|(checksum, bytes)| {
+                    log_throughput(&paths.remote, bytes, start, &client_arguments);
+                    checksum
+                });
+                let result = result.and_then(|x| file.finish().map(|_| x));
+                let result = if is_resumed {result.and_then(|_| part_checksum(&part, &client_arguments))} else {result};
+
+                return finish_download(result, &part, &paths.local, &client_arguments);
             }
             Opcode::Write => {
-                let mut file = File::open(paths.local).expect("Cannot write file");
-                upload_action(&mut socket, &mut file, &client_arguments);
-                break;
+                let mut file = File::open(&paths.local)
+                    .map_err(|err| ClientError::Other(format!("open {:?} failed; {}", paths.local, err)))?;
+                let start = Instant::now();
+                let bytes = upload_checksummed(&mut socket, &mut file, &client_arguments)?;
+                log_throughput(&paths.remote, bytes, start, &client_arguments);
+                return Ok(());
             }
             _ => panic!("not yet implemented"),
         }
     }
 
+    return Err(ClientError::Timeout("timeout".into()));
+}
+
+//the checksum of a resumed download has to cover the bytes received before
+fn part_checksum(part: &Path, arguments: &ClientArguments) -> Result<Option<Checksum>, ClientError> {
+    let checksum = if let Some(x) = arguments.checksum() {x} else {return Ok(None);};
+    let read_error = |err: std::io::Error| ClientError::Other(format!("read {:?} failed; {}", part, err));
+
+    let mut file   = File::open(part).map_err(read_error)?;
+    let mut reader = ChecksumReader::new(&mut file, checksum);
+    std::io::copy(&mut reader, &mut std::io::sink()).map_err(read_error)?;
+    return Ok(Some(reader.into_checksum()));
+}
+
+//created with the first DATA block or the end of an empty download; a download which never starts leaves no local file
+struct LazyFile {
+    path:  PathBuf,
+    append: bool,                       //a resumed download continues the existing file
+    file:  Option<File>,
+    error: Option<std::io::Error>,      //first failed create or write; reported by finish
+}
+
+impl LazyFile {
+    fn new(path: &Path, append: bool) -> LazyFile {
+        LazyFile {
+            path:  path.to_path_buf(),
+            append: append,
+            file:  None,
+            error: None,
+        }
+    }
+
+    //creates or opens the file once; called by the first block or the end of an empty download
+    fn create(&mut self) -> std::io::Result<()> {
+        if self.file.is_none() && self.error.is_none() {
+            let result = if self.append {fs::OpenOptions::new().append(true).open(&self.path)} else {File::create(&self.path)};
+            match result {
+                Ok(x)    => self.file = Some(x),
+                Err(err) => self.error = Some(err),
+            }
+        }
+        return if self.file.is_some() {Ok(())} else {Err(self.copy_error())};
+    }
+
+    //the stored error stays for finish; io::Error isn't Clone
+    fn copy_error(&self) -> std::io::Error {
+        return self.error.as_ref().map_or(ErrorKind::Other.into(), |x| std::io::Error::new(x.kind(), x.to_string()));
+    }
+
+    //closes the file
+    fn finish(&mut self) -> Result<(), ClientError> {
+        self.file = None;
+        return match self.error.take() {
+            Some(err) => Err(ClientError::Other(format!("write {:?} failed; {}", self.path, err))),
+            None      => Ok(()),
+        };
+    }
+}
+
+impl Write for LazyFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.create()?;
+        let file = self.file.as_mut().unwrap();
+
+        let result = file.write_all(buf);
+        if let Err(err) = result {
+            self.file  = None;
+            self.error = Some(err);
+            return Err(self.copy_error());
+        }
+        return Ok(buf.len());
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        return self.file.as_mut().map_or(Ok(()), |x| x.flush());
+    }
+}
+
+//destination of a download; end is called once after the last block
+trait DownloadSink: Write {
+    fn end(&mut self) -> std::io::Result<()> {
+        return Ok(());
+    }
+}
+
+impl DownloadSink for Vec<u8> {}
+
+impl DownloadSink for LazyFile {
+    fn end(&mut self) -> std::io::Result<()> {
+        return self.create();
+    }
+}
+
+//the server answered the request with an ERROR or there is no server at all
+fn request_rejected(socket: &SocketSendRecv) -> Option<ClientError> {
+    if socket.is_refused() {
+        return Some(socket.no_server_error());
+    }
+
+    let mut pp = PacketParser::new(socket.recv_buf());
+    if pp.peek_opcode() != Some(Opcode::Error) {
+        return None;
+    }
+    return pp.parse_error().map(ClientError::Remote);
+}
+
+fn upload_checksummed(socket: &mut SocketSendRecv, reader: &mut dyn Read, client_arguments: &ClientArguments) -> Result<u64, ClientError> {
+    let checksum = if let Some(x) = client_arguments.checksum() {x} else {return upload_action(socket, reader, client_arguments);};
+
+    let mut reader = ChecksumReader::new(reader, checksum);
+    let bytes = upload_action(socket, &mut reader, client_arguments)?;
+
+    let checksum = reader.into_checksum();
+    tlog::info!("sent {} = {}", checksum.name(), checksum.finalize_hex());
+    return Ok(bytes);
+}
+
+//upload of a source with unknown length e.g a pipe; nothing is seeked or opened.
+//the transfer ends with the first short block after the reader returned eof
+pub fn upload_reader(remote: &str, remote_name: &Path, mut reader: impl Read, client_arguments: &ClientArguments) -> Result<u64, ClientError> {
+    let mut client_arguments = client_arguments.clone();
+    client_arguments.remote = remote.to_string();
+
+    let (mut socket, client_arguments) = connect(Opcode::Write, remote_name, &client_arguments)?;
+    return upload_checksummed(&mut socket, &mut reader, &client_arguments);
+}
+
+//local name of --upload which reads from stdin
+const STDIN_PATH: &str = "-";
+
+//a stream can't be repeated so redirects are not followed
+fn upload_stdin(paths: &ClientFilePath, client_arguments: &ClientArguments) -> Result<(), ClientError> {
+    if paths.remote == Path::new(STDIN_PATH) {
+        return Err(ClientError::Other("upload from stdin needs a remote name e.g --upload - <name>".into()));
+    }
+
+    let start = Instant::now();
+    let bytes = upload_reader(&client_arguments.remote, &paths.remote, std::io::stdin().lock(), client_arguments)?;
+    log_throughput(&paths.remote, bytes, start, client_arguments);
+    return Ok(());
+}
+
+//--print-oack: only the handshake; the server is stopped with an ERROR before any data
+fn print_oack(opcode: Opcode, paths: &ClientFilePath, client_arguments: &ClientArguments) -> Result<(), ClientError> {
+    let mut client_arguments = client_arguments.clone();
+    if opcode == Opcode::Write {
+        client_arguments.tsize = upload_size(&paths.local, &client_arguments);
+    }
+    let (mut socket, negotiated) = connect(opcode, &paths.remote, &client_arguments)?;
+    if socket.is_refused() {
+        return Err(socket.no_server_error());
+    }
+
+    let reply = socket.recv_buf().to_vec();
+    let mut pp = PacketParser::new(&reply);
+    match pp.opcode() {
+        Some(Opcode::Oack) => {
+            match pp.extended_options_ordered() {
+                Ok(options) => {
+                    for (key, value) in options {
+                        tlog::info!("oack {} = {}", key, value);
+                    }
+                },
+                Err(err) => tlog::warning!("oack invalid; {}", err),
+            }
+        },
+        Some(Opcode::Error) => return Err(ClientError::Remote(PacketParser::new(&reply).parse_error().unwrap())),
+        None if reply.is_empty() => return Err(ClientError::Timeout("no answer to the request".into())),
+        _ => tlog::info!("no oack; the server ignored all options"),
+    }
+    tlog::info!("negotiated {}", NegotiatedOptions {
+        blksize:    negotiated.blksize,
+        windowsize: negotiated.windowsize,
+        rollover:   negotiated.rollover.unwrap_or(0),
+        tsize:      negotiated.tsize,
+        timeout:    negotiated.utimeout,
+        offset:     negotiated.offset,
+        xcompress:  negotiated.xcompress,
+    });
+
+    //rfc2347: a client which doesn't accept the oack answers with error 8
+    let mut buf = Vec::new();
+    ErrorResponse::with_msg(ErrorNumber::OptionNegotiationFailed, "print-oack; no transfer".to_string()).write_packet(&mut buf);
+    socket.send(&buf);
+    return Ok(());
+}
+
+//--probe asks for this name; a server without it answers with an ERROR, which is enough
+const PROBE_NAME: &str = ".tftp-probe";
+
+//--probe: sends a plain RRQ and measures the time until the first reply; nothing is transferred
+fn probe(client_arguments: &ClientArguments) -> Result<(), ClientError> {
+    let mut client_arguments = client_arguments.clone();
+    client_arguments.no_options    = true;
+    client_arguments.request_tsize = false;
+
+    let start = Instant::now();
+    let (mut socket, _) = connect(Opcode::Read, Path::new(PROBE_NAME), &client_arguments)?;
+    let rtt = start.elapsed();
+
+    if socket.is_refused() {
+        return Err(socket.no_server_error());
+    }
+    let reply = socket.recv_buf().to_vec();
+    if reply.is_empty() {
+        return Err(ClientError::Timeout(format!("no answer from {}", client_arguments.remote)));
+    }
+
+    //rtt includes the waits of resent requests
+    tlog::info!("probe {}; reply = {}; rtt = {:.3}ms", client_arguments.remote, describe_packet(&reply), rtt.as_secs_f64() * 1000.0);
+
+    //the server really has the file; stop it before it waits for the ack of the first block
+    if PacketParser::new(&reply).peek_opcode() != Some(Opcode::Error) {
+        let mut buf = Vec::new();
+        ErrorResponse::with_msg(ErrorNumber::NotDefined, "probe; no transfer".to_string()).write_packet(&mut buf);
+        socket.send(&buf);
+    }
+    return Ok(());
+}
+
+//manifest format: one relative path per line; '/' as separator;
+//an optional size may follow the path separated by a tab;
+//empty lines and lines starting with '#' are ignored
+fn parse_manifest(manifest: &str) -> Vec<String> {
+    let mut entries = Vec::new();
+
+    for i_line in manifest.lines() {
+        let line = i_line.trim_end_matches('\r');
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let path = line.split('\t').next().unwrap();
+        let is_escape = path.starts_with('/') || path.split('/').any(|x| x == "..");
+        if is_escape {
+            tlog::warning!("skip manifest entry outside of directory: {}", path);
+            continue;
+        }
+
+        entries.push(path.to_string());
+    }
+
+    return entries;
+}
+
+fn download_dir(values: Vec<&String>, manifest: Option<&String>, client_arguments: &ClientArguments) -> Vec<(PathBuf, Result<(), ClientError>)> {
+    let remote_dir = values[0].trim_end_matches('/');
+    let local_dir  = if let Some(l) = values.get(1) {
+        PathBuf::from_str(l).unwrap()
+    } else {
+        env::current_dir().expect("cannot get current working directory")
+    };
+
+    let manifest = if let Some(manifest) = manifest {
+        match fs::read_to_string(manifest) {
+            Ok(x)    => x,
+            Err(err) => return vec![(PathBuf::from(manifest), Err(ClientError::Other(format!("cannot read manifest file; {}", err))))],
+        }
+    } else {
+        let remote = PathBuf::from(format!("{}/{}", remote_dir, MANIFEST_NAME));
+        let (mut socket, client_arguments) = match connect(Opcode::Read, &remote, client_arguments) {
+            Ok(x)    => x,
+            Err(err) => return vec![(remote, Err(err))],
+        };
 
+        let mut data: Vec<u8> = Vec::new();
+        if let Err(err) = download_action(&mut socket, &mut data, &client_arguments) {
+            return vec![(remote, Err(err))];
+        }
+        match String::from_utf8(data) {
+            Ok(x)  => x,
+            Err(_) => return vec![(remote, Err(ClientError::Other("manifest is not valid utf8".into())))],
+        }
+    };
+
+    let mut results = Vec::new();
+    for i_entry in parse_manifest(&manifest) {
+        if client_arguments.is_expired() {
+            tlog::error!("timeout-total exceeded; remaining files are skipped");
+            break;
+        }
+
+        let paths = ClientFilePath {
+            local:  local_dir.join(&i_entry),
+            remote: PathBuf::from(format!("{}/{}", remote_dir, i_entry)),
+        };
+
+        if let Some(parent) = paths.local.parent() {
+            if let Err(err) = fs::create_dir_all(parent) {
+                results.push((paths.remote, Err(ClientError::Other(format!("cannot create local directory {:?}; {}", parent, err)))));
+                continue;
+            }
+        }
+
+        tlog::info!("download {:?}", paths.remote);
+        let result = transfer(Opcode::Read, &paths, client_arguments);
+        results.push((paths.remote, result));
+    }
+
+    return results;
 }
 
 
+//options and errors may be longer than the data of a small blksize
+fn packet_size(blksize: usize) -> usize {
+    return blksize.max(DEFAULT_BLOCKSIZE) + DATA_OFFSET;
+}
+
 struct SocketSendRecv {
     socket:   UdpSocket,
+    remote:   SocketAddr,
+    is_tid:   bool,         //remote port is replaced by the transfer id of the first reply
+    is_refused: bool,       //icmp port unreachable; nobody listens at remote
     read_buf: Vec<u8>,
+    packet_size: usize,
     defer:    bool,
+    deadline: Option<Instant>,
+    trace:    bool,
 }
 
 impl std::io::Write for SocketSendRecv {
@@ -95,11 +821,17 @@ impl std::io::Write for SocketSendRecv {
 }
 
 impl SocketSendRecv {
-    fn new(socket: UdpSocket) -> SocketSendRecv {
+    fn new(socket: UdpSocket, remote: SocketAddr, deadline: Option<Instant>, trace: bool) -> SocketSendRecv {
         SocketSendRecv {
             socket:    socket,
+            remote:    remote,
+            is_tid:    false,
+            is_refused: false,
             read_buf:  Vec::new(),
+            packet_size: packet_size(DEFAULT_BLOCKSIZE),
             defer:     false,
+            deadline:  deadline,
+            trace:     trace,
         }
     }
 
@@ -108,19 +840,103 @@ impl SocketSendRecv {
             self.defer = false;
             return true;
         }
+        if self.is_refused {
+            self.read_buf.resize(0, 0);
+            return false;
+        }
 
-        self.read_buf.resize(PACKET_SIZE_MAX, 0);
-        let _           = self.socket.set_read_timeout(Some(timeout)); 
-        match self.socket.recv_from(&mut self.read_buf) {
-            Ok((size, _)) =>  {
-                self.read_buf.resize(size, 0);
-                return true;
-            }
-            Err(_) => {
+        //never wait beyond --timeout-total
+        let timeout = if let Some(deadline) = self.deadline {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
                 self.read_buf.resize(0, 0);
                 return false;
             }
+            timeout.min(remaining)
+        } else {
+            timeout
         };
+
+        //a zero timeout only takes a packet which is already queued
+        let is_poll  = timeout.is_zero();
+        let _        = self.socket.set_nonblocking(is_poll);
+        let deadline = Instant::now() + timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() && !is_poll {
+                self.read_buf.resize(0, 0);
+                return false;
+            }
+
+            self.read_buf.resize(self.packet_size, 0);
+            if !is_poll {
+                let _ = self.socket.set_read_timeout(Some(remaining));
+            }
+            match self.socket.recv_from(&mut self.read_buf) {
+                Ok((size, src)) =>  {
+                    if !self.accept_source(src) {
+                        continue;
+                    }
+
+                    self.read_buf.resize(size, 0);
+                    if self.trace {
+                        tlog::debug!("recv {}", describe_packet(&self.read_buf));
+                    }
+                    return true;
+                }
+                //a signal interrupted the wait; the remaining time is recalculated
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) if err.kind() == ErrorKind::ConnectionRefused => {
+                    self.is_refused = true;
+                    self.read_buf.resize(0, 0);
+                    return false;
+                }
+                Err(err) => {
+                    if err.kind() != ErrorKind::WouldBlock && err.kind() != ErrorKind::TimedOut {
+                        tlog::error!("recv failed; {}", err);
+                    }
+                    self.read_buf.resize(0, 0);
+                    return false;
+                }
+            };
+        }
+    }
+
+    //the server answers from a new port; the first reply fixes it for the rest of the transfer
+    fn accept_source(&mut self, src: SocketAddr) -> bool {
+        if self.is_tid {
+            if src != self.remote {
+                tlog::warning!("drop packet from unknown transfer id {:?}", src);
+                return false;
+            }
+            return true;
+        }
+
+        if src.ip() != self.remote.ip() {
+            tlog::warning!("drop packet from unknown host {:?}", src);
+            return false;
+        }
+
+        self.remote = src;
+        self.is_tid = true;
+
+        //a connected socket reports icmp errors of the transfer id as ConnectionRefused;
+        //the server port itself cannot be connected because the replies come from the transfer id
+        let _ = self.socket.connect(src);
+        return true;
+    }
+
+    fn is_refused(&self) -> bool {
+        return self.is_refused;
+    }
+
+    fn no_server_error(&self) -> ClientError {
+        return ClientError::NoServer(format!("no TFTP server listening at {}", self.remote));
+    }
+
+    //largest packet expected for the blksize; bigger ones are truncated
+    fn set_blksize(&mut self, blksize: usize) {
+        self.packet_size = packet_size(blksize);
     }
 
     fn recv_buf(&self) -> &[u8] {
@@ -128,78 +944,168 @@ impl SocketSendRecv {
     }
 
     fn send(&mut self, data: &[u8]) {
-        self.socket.send(data).expect("ERR  : send tftp request failed");
+        if self.trace {
+            tlog::debug!("send {}", describe_packet(data));
+        }
+        let result = if self.is_tid {self.socket.send(data)} else {self.socket.send_to(data, self.remote)};
+        match result {
+            Err(err) if err.kind() == ErrorKind::ConnectionRefused => self.is_refused = true,
+            Err(err) => tlog::error!("send failed; {}", err),
+            Ok(_)    => {},
+        }
+    }
+
+    //the frames of a window with one system call; see --batch-sends
+    fn send_batch(&mut self, frames: &[Vec<u8>]) {
+        if self.trace {
+            for i_frame in frames {
+                tlog::debug!("send {}", describe_packet(i_frame));
+            }
+        }
+        let remote = if self.is_tid {None} else {Some(self.remote)};
+        match batch::send_batch(&self.socket, remote, frames) {
+            Err(err) if err.kind() == ErrorKind::ConnectionRefused => self.is_refused = true,
+            Err(err) => tlog::error!("send failed; {}", err),
+            Ok(_)    => {},
+        }
     }
 
     fn defer_recv(&mut self) {
         self.defer = true;
     }
 
+    //raw bytes of a reply that could not be parsed; only with --trace
+    fn dump_recv(&self) {
+        if self.trace {
+            tlog::debug!("unparseable reply len={}\n{}", self.read_buf.len(), hexdump(&self.read_buf));
+        }
+    }
+
 
 
 }
 
 
-fn send_initial_packet(opcode: Opcode, paths: &ClientFilePath, args: &mut ClientArguments, socket: &mut SocketSendRecv) {
+//sends the request and returns the options the server acknowledged
+fn send_initial_packet(opcode: Opcode, remote: &Path, args: &ClientArguments, socket: &mut SocketSendRecv) -> NegotiatedOptions {
+    //--no-options: classic 512 byte transfer for servers which drop requests with options
+    let mut negotiated = if args.no_options {
+        NegotiatedOptions::new()
+    } else {
+        NegotiatedOptions {
+            blksize:    args.blksize,
+            windowsize: args.windowsize,
+            ..NegotiatedOptions::new()
+        }
+    };
+
     //send initial packet
     {
         let mut buf = Vec::new();
 
         let mut pkg = PacketBuilder::new(&mut buf)
             .opcode(opcode)
-            .str(paths.remote.clone().to_str().expect("invalid remote filepath"))
+            .str(remote.to_str().expect("invalid remote filepath"))
             .separator()
             .transfer_mode(TransferMode::Octet);
     
-        if args.blksize != DEFAULT_BLOCKSIZE {
+        if args.blksize != DEFAULT_BLOCKSIZE && !args.no_options {
             pkg = pkg.separator().str(&BLKSIZE_STR).separator().str(&args.blksize.to_string());
         }
-        if args.windowsize != DEFAULT_WINDOWSIZE {
+        if args.windowsize != DEFAULT_WINDOWSIZE && !args.no_options {
             pkg = pkg.separator().str(&WINDOW_STR).separator().str(&args.windowsize.to_string());
         }
+        if let (Some(rollover), false) = (args.rollover, args.no_options) {
+            pkg = pkg.separator().str(&ROLLOVER_STR).separator().str(&rollover.to_string());
+        }
+        if let (Some(utimeout), false) = (args.utimeout, args.no_options) {
+            pkg = pkg.separator().str(&UTIMEOUT_STR).separator().str(&utimeout.as_micros().to_string());
+        }
+        if let (Some(tsize), false) = (args.tsize, args.no_options) {
+            pkg = pkg.separator().str(&TSIZE_STR).separator().str(&tsize.to_string());
+        }
+        if let (Some(offset), false) = (args.offset, args.no_options) {
+            pkg = pkg.separator().str(&OFFSET_STR).separator().str(&offset.to_string());
+        }
+        if args.xcompress && !args.no_options {
+            pkg = pkg.separator().str(&XCOMPRESS_STR).separator().str(&XCOMPRESS_DEFLATE);
+        }
     
         pkg = pkg.separator();
-    
-        socket.send(pkg.as_bytes());
+        let request = pkg.as_bytes().to_vec();
+
+        //the request is sent again until the server answers; only the data phase uses the block timeouts
+        let mut is_answered = false;
+        for i_try in 0..=RETRY_COUNT {
+            if i_try > 0 {
+                tlog::info!("no answer to the request; send again {}/{}", i_try, RETRY_COUNT);
+            }
+            socket.send(&request);
+            if socket.recv_next(HANDSHAKE_TIMEOUT) {
+                is_answered = true;
+                break;
+            }
+            if socket.is_refused() || args.is_expired() {
+                break;
+            }
+        }
+
+        //options count only when acknowledged; a late DATA 1 with 512 bytes
+        //would otherwise be taken as the last block of a 1024 byte transfer
+        if !is_answered {
+            return NegotiatedOptions::new();
+        }
     }
 
     //try parse extended options
     {
-        if !socket.recv_next(SEND_RECV_BLOCK_TIMEOUT) {
-            return;
-        }
 
         let mut pp = PacketParser::new(socket.recv_buf());
 
+        //without OACK the server ignored all options
         if !pp.opcode_expect(Opcode::Oack) {
+            let is_error = PacketParser::new(socket.recv_buf()).peek_opcode() == Some(Opcode::Error);
+            if negotiated != NegotiatedOptions::new() && !is_error {
+                tlog::info!("server ignored the options; fall back to {}", NegotiatedOptions::new());
+            }
             socket.defer_recv();
-            return;
+            return NegotiatedOptions::new();
         }
 
-       
+        match pp.extended_options() {
+            Ok(recv_map) => {
+                for (key,value) in &recv_map {
+                    tlog::info!("acknowledge {} = {}", key, value);
+                }
 
-        if let Ok(recv_map) = pp.extended_options() {
-            for (key,value) in &recv_map {
-                tlog::info!("acknowledge {} = {}", key, value);
-            }
- 
-            if let Ok((options,other)) = filter_extended_options(&recv_map) {
-                args.blksize    = options.blksize    as usize;
-                args.windowsize = options.windowsize as usize;
+                match filter_extended_options(&recv_map) {
+                    Ok((options,other)) => {
+                        negotiated.blksize    = options.blksize    as usize;
+                        negotiated.windowsize = options.windowsize as usize;
+                        negotiated.rollover   = options.rollover.unwrap_or(0);
+                        negotiated.timeout    = options.utimeout;
+                        negotiated.tsize      = options.tsize;
+                        negotiated.offset     = options.offset;
+                        negotiated.xcompress  = options.xcompress;
 
-                if !other.is_empty() {
-                    tlog::warning!("Ignored extended options {:?}", other);
+                        if !other.is_empty() {
+                            tlog::warning!("Ignored extended options {:?}", other);
+                        }
+                    },
+                    Err(err) => {
+                        tlog::warning!("recv extended options but format invalid; {}", err);
+                        socket.dump_recv();
+                    },
                 }
-            }
-            else {
-                tlog::warning!("recv extended options but format invalid");
-            }
-        }
-        else {
-            tlog::warning!("recv extended options but format invalid");
+            },
+            Err(err) => {
+                tlog::warning!("recv extended options but format invalid; {}", err);
+                socket.dump_recv();
+            },
         }
     }
 
+    return negotiated;
 }
 
 struct ClientFilePath {
@@ -207,91 +1113,318 @@ struct ClientFilePath {
    remote: PathBuf,
 }
 
-fn get_connection_paths(opcode: Opcode, args: &ArgMatches) -> ClientFilePath {
-    let (values, remote_idx, local_idx) = match opcode {
-        Opcode::Read  => (args.get_many::<String>("download"),  0, 1),
-        Opcode::Write => (args.get_many::<String>("upload"), 1, 0),
+//values of each --download/--upload occurrence; repeated flags are told apart
+//by their position on the command line
+fn grouped_values(args: &ArgMatches, id: &str) -> Vec<Vec<String>> {
+    let values:  Vec<&String> = args.get_many::<String>(id).unwrap().collect();
+    let indices: Vec<usize>   = args.indices_of(id).unwrap().collect();
+
+    let mut groups: Vec<Vec<String>> = Vec::new();
+    let mut last_idx: Option<usize>  = None;
+
+    for (value, idx) in values.iter().zip(indices) {
+        if last_idx.map_or(true, |x| x + 1 != idx) {
+            groups.push(Vec::new());
+        }
+        groups.last_mut().unwrap().push(value.to_string());
+        last_idx = Some(idx);
+    }
+
+    return groups;
+}
+
+//values of one --download/--upload; the source comes first, the destination is optional
+//  --download <remote> [local]: local defaults to the remote file name in the current directory
+//  --upload   <local> [remote]: remote defaults to the local file name; the local directory is not sent
+fn connection_path(opcode: Opcode, values: &[String], current_dir: &Path) -> ClientFilePath {
+    let source      = PathBuf::from(&values[0]);
+    let destination = values.get(1).map(PathBuf::from);
+
+    return match opcode {
+        Opcode::Read  => ClientFilePath {
+            local:  destination.unwrap_or_else(|| current_dir.join(source.file_name().expect("invalid remote filename"))),
+            remote: source,
+        },
+        Opcode::Write => ClientFilePath {
+            remote: destination.unwrap_or_else(|| source.file_name().expect("invalid local filename").into()),
+            local:  source,
+        },
         _             => panic!("Invalid Operation: only --download or --upload allowed"),
     };
-    let values: Vec<&String> = values.unwrap().collect();
+}
 
-    //get from args
-    let mut localfile = if let Some(l) = values.get(local_idx) {
-        Some(PathBuf::from_str(l).unwrap())
-    } else {
-        Option::None
-    };
+fn get_connection_paths(opcode: Opcode, args: &ArgMatches) -> Vec<ClientFilePath> {
+    let id = if opcode == Opcode::Read {"download"} else {"upload"};
+    let current_dir = env::current_dir().expect("cannot get current working directory");
 
-    let mut remote = if let Some(r) = values.get(remote_idx) {
-        Some(PathBuf::from_str(r).unwrap())
-    } else {
-        Option::None
-    };
+    return grouped_values(args, id).iter()
+        .map(|x| connection_path(opcode, x, &current_dir))
+        .collect();
+}
 
-    //default missing
-    if localfile.is_none() {
-        localfile = Some(env::current_dir()
-            .expect("cannot get current working directory")
-            .join(remote.as_ref().unwrap().file_name().unwrap()))
-    };
-    if remote.is_none() {
-        remote = Some(localfile.as_ref().unwrap().file_name().unwrap().into());
+//downloads are written to "<local>.part" and renamed when complete
+fn partial_path(local: &Path) -> PathBuf {
+    let mut name = local.as_os_str().to_owned();
+    name.push(".part");
+    return PathBuf::from(name);
+}
+
+//bytes is the payload of the data packets
+fn log_throughput(path: &Path, bytes: u64, start: Instant, arguments: &ClientArguments) {
+    if arguments.quiet {
+        return;
+    }
+
+    let runtime = start.elapsed().as_secs_f32();
+    let mib_s   = ((bytes as f32) / runtime) / 1000000.0;
+    tlog::info!("{:?} {} bytes; runtime = {}s; speed = {}MiB/s", path, bytes, runtime, mib_s);
+}
+
+fn finish_download(result: Result<Option<Checksum>, ClientError>, part: &Path, local: &Path, arguments: &ClientArguments) -> Result<(), ClientError> {
+    let result = match result {
+        Ok(Some(checksum)) => verify_download(checksum, arguments),
+        Ok(None)           => Ok(()),
+        Err(err)           => Err(err),
     };
 
-    ClientFilePath {
-        local:  localfile.unwrap(),
-        remote: remote.unwrap(),
+    if let Err(err) = result {
+        if !arguments.keep_partial {
+            let _ = fs::remove_file(part);
+        }
+        return Err(err);
     }
+
+    return fs::rename(part, local).map_err(|x| ClientError::Other(format!("rename {:?} failed; {}", part, x)));
 }
 
-fn download_action(socket: &mut SocketSendRecv, file: &mut File, arguments: &ClientArguments) {
-    let mut ctrl_result = RecvController::new(arguments.windowsize, arguments.blksize, Box::new(|action| {
+fn verify_download(checksum: Checksum, arguments: &ClientArguments) -> Result<(), ClientError> {
+    let name   = checksum.name();
+    let digest = checksum.finalize_hex();
+    tlog::info!("received {} = {}", name, digest);
+
+    if let Some(expected) = &arguments.verify {
+        if *expected != digest {
+            return Err(ClientError::Verify(format!("{} mismatch; expected = {}; received = {}", name, expected, digest)));
+        }
+    }
+
+    return Ok(());
+}
+
+//returns the checksum of the received data if requested and the received payload in bytes
+fn download_action(socket: &mut SocketSendRecv, file: &mut dyn DownloadSink, arguments: &ClientArguments) -> Result<(Option<Checksum>, u64), ClientError> {
+    let mut checksum = arguments.checksum();
+
+    let mut sink = |data: &[u8], is_end: bool| -> std::io::Result<()> {
+        file.write_all(data)?;
+        if let Some(checksum) = checksum.as_mut() {
+            checksum.update(data);
+        }
+        if is_end {
+            file.end()?;
+        }
+        return Ok(());
+    };
+    //xcompress: the blocks are inflated into a buffer which is passed on to the sink
+    let mut inflate = if arguments.xcompress {Some(DeflateDecoder::new(Vec::new()))} else {None};
+
+    let mut ctrl = RecvController::new(arguments.windowsize, arguments.blksize, Box::new(|action| {
         match action {
             tftp_protocol::RecvCallbackArg::WriteSink(data) => {
-                file.write(data);
+                if let Some(inflate) = inflate.as_mut() {
+                    inflate.write_all(data)?;
+                    sink(&std::mem::take(inflate.get_mut()), false)?;
+                } else {
+                    sink(data, false)?;
+                }
+            },
+            tftp_protocol::RecvCallbackArg::SinkEnd => {
+                if let Some(inflate) = inflate.as_mut() {
+                    inflate.try_finish()?;
+                    sink(&std::mem::take(inflate.get_mut()), true)?;
+                } else {
+                    sink(&[], true)?;
+                }
             },
-            tftp_protocol::RecvCallbackArg::Ack(ack_packet) => {
-                let _ = socket.send(ack_packet);
+            tftp_protocol::RecvCallbackArg::Ack(packet) | tftp_protocol::RecvCallbackArg::Error(packet) => {
+                let _ = socket.send(packet);
             }
             tftp_protocol::RecvCallbackArg::Recv(out_buff, timeout) => {
-                if !socket.recv_next(timeout) {return;}
+                if !socket.recv_next(timeout) {return Ok(());}
                 out_buff.write_all(socket.recv_buf());
             }
         }
-    })).run();
+        Ok(())
+    }));
+    ctrl.set_rollover(arguments.rollover.unwrap_or(0));
+    ctrl.set_cancel(arguments.cancel.clone());
+    ctrl.set_inject_delay(arguments.inject_delay);
+    if let Some(utimeout) = arguments.utimeout {
+        ctrl.set_timeout(utimeout);
+    }
+    let ctrl_result = ctrl.run();
+    let bytes       = ctrl.written_len();
+    drop(ctrl);
 
-    match ctrl_result {
-        Err(err) =>  tlog::error!("{}", &err),
-        _ => {}
+    return match ctrl_result {
+        Err(_) if socket.is_refused()    => Err(socket.no_server_error()),
+        Err(_) if arguments.is_expired() => Err(ClientError::Timeout("timeout-total exceeded".into())),
+        Err(err) => Err(err.into()),
+        _ => Ok((checksum, bytes)),
+    };
+}
+
+//returns the sent payload in bytes
+//an ACK advances the window; an ERROR ends the upload
+fn upload_reply(recv_packet: &[u8], window_buffer: &mut SendStateMachine) -> Result<(), ClientError> {
+    let mut pp = PacketParser::new(recv_packet);
+
+    match pp.peek_opcode() {
+        Some(Opcode::Ack)   => window_buffer.ack_packet(recv_packet),
+        Some(Opcode::Error) => return Err(ClientError::Remote(pp.parse_error().unwrap())),
+        _                   => {},
     }
+    return Ok(());
 }
 
-fn upload_action(socket: &mut SocketSendRecv, file: &mut File, arguments: &ClientArguments) {
+fn upload_action(socket: &mut SocketSendRecv, file: &mut dyn Read, arguments: &ClientArguments) -> Result<u64, ClientError> {
+    let mut deflate;
+    let file: &mut dyn Read = if arguments.xcompress {
+        deflate = DeflateEncoder::new(file, Compression::default());
+        &mut deflate
+    } else {
+        file
+    };
+
     let mut window_buffer = SendStateMachine::new(file, arguments.blksize, arguments.windowsize);
-    
+    window_buffer.set_rollover(arguments.rollover.unwrap_or(0));
+    window_buffer.set_backoff(arguments.backoff);
+    window_buffer.set_cancel(arguments.cancel.clone());
+    window_buffer.set_inject_delay(arguments.inject_delay);
+    if let Some(utimeout) = arguments.utimeout {
+        window_buffer.set_resend_timeout(utimeout);
+    }
+    //a short utimeout has to be checked more often than once per second
+    let recv_timeout = arguments.utimeout.map_or(SEND_RECV_BLOCK_TIMEOUT, |x| x.min(SEND_RECV_BLOCK_TIMEOUT));
+
     while let action = window_buffer.next() {
+        if socket.is_refused() {
+            return Err(socket.no_server_error());
+        }
+        if arguments.is_expired() {
+            return Err(ClientError::Timeout("timeout-total exceeded".into()));
+        }
+
         match action {
-            SendAction::SendBuffer(bufs) => {
-                for i_frame in window_buffer.send_data() {
-                    socket.send(&i_frame)
+            SendAction::SendBuffer if arguments.batch_sends => {
+                socket.send_batch(window_buffer.next_frames());
+            },
+            SendAction::SendBuffer => {
+                while let Some(i_frame) = window_buffer.next_frame() {
+                    socket.send(i_frame);
+
+                    //an early ACK trims the frames which are still to be sent
+                    if arguments.interleave_acks && socket.recv_next(Duration::ZERO) {
+                        upload_reply(socket.recv_buf(), &mut window_buffer)?;
+                    }
                 }
             },
-            SendAction::Timeout => { 
-                tlog::error!("timeout");
-                return;}
+            SendAction::Cancel(packet) => {
+                socket.send(&packet);
+                return Err(TransferError::Cancelled.into());
+            },
+            SendAction::Timeout => { return Err(ClientError::Timeout("timeout".into())); }
             SendAction::End => break,
             _ => {}
         }
 
-        if !socket.recv_next(SEND_RECV_BLOCK_TIMEOUT) { continue; }
+        if !socket.recv_next(recv_timeout) { continue; }
+
+        upload_reply(socket.recv_buf(), &mut window_buffer)?;
+    }
 
-        let recv_packet = socket.recv_buf();
+    tlog::info!("upload complete; bytes={} retransmits={}", window_buffer.read_len(), window_buffer.retransmits());
+    return Ok(window_buffer.read_len() as u64);
+}
 
-        if let Some(packet_error) = PacketParser::new(recv_packet).parse_error() {
-            tlog::error!("{}", packet_error.to_string());
-            return;
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remote_with_zone() {
+        let ip = Ipv6Addr::from_str("fe80::1").unwrap();
+
+        assert_eq!(parse_remote("[fe80::1%3]:6969", DEFAULT_PORT).unwrap(), SocketAddr::V6(SocketAddrV6::new(ip, 6969, 3, 0)));
+        assert_eq!(parse_remote("fe80::1%3", DEFAULT_PORT).unwrap(), SocketAddr::V6(SocketAddrV6::new(ip, DEFAULT_PORT, 3, 0)));
+
+        assert!(parse_remote("[fe80::1%3]6969", DEFAULT_PORT).is_err());
+        assert!(parse_remote("[fe80::1%3", DEFAULT_PORT).is_err());
+        assert!(parse_remote("fe80::1%no-such-interface", DEFAULT_PORT).is_err());
+    }
+
+    #[test]
+    fn remote_forms() {
+        let v4 = SocketAddr::from(([127, 0, 0, 1], 6969));
+        let v6 = SocketAddr::from((Ipv6Addr::LOCALHOST, 6969));
+
+        assert_eq!(parse_remote("127.0.0.1:6969", 69).unwrap(), v4);
+        assert_eq!(parse_remote("127.0.0.1", 6969).unwrap(), v4);
+        assert_eq!(parse_remote("[::1]:6969", 69).unwrap(), v6);
+        assert_eq!(parse_remote("[::1]", 6969).unwrap(), v6);
+        assert_eq!(parse_remote("::1", 6969).unwrap(), v6);
+        assert_eq!(parse_remote("localhost:6969", 69).unwrap().port(), 6969);
+        assert_eq!(parse_remote("localhost", 6969).unwrap().port(), 6969);
 
-        window_buffer.ack_packet(recv_packet);
+        assert!(parse_remote("127.0.0.1:port", 69).is_err());
+        assert!(parse_remote("127.0.0.1:70000", 69).is_err());
+        assert!(parse_remote(":6969", 69).is_err());
+        assert!(parse_remote("[::1]:", 69).is_err());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn connection_path_source_first() {
+        let values = |x: &[&str]| x.iter().map(|x| x.to_string()).collect::<Vec<String>>();
+        let current_dir = Path::new("/work");
+
+        let paths = connection_path(Opcode::Write, &values(&["/tmp/random123", "firmware.bin"]), current_dir);
+        assert_eq!((paths.local.as_path(), paths.remote.as_path()), (Path::new("/tmp/random123"), Path::new("firmware.bin")));
+
+        let paths = connection_path(Opcode::Write, &values(&["/tmp/random123"]), current_dir);
+        assert_eq!((paths.local.as_path(), paths.remote.as_path()), (Path::new("/tmp/random123"), Path::new("random123")));
+
+        let paths = connection_path(Opcode::Read, &values(&["images/forest.jpg", "/tmp/a.jpg"]), current_dir);
+        assert_eq!((paths.local.as_path(), paths.remote.as_path()), (Path::new("/tmp/a.jpg"), Path::new("images/forest.jpg")));
+
+        let paths = connection_path(Opcode::Read, &values(&["images/forest.jpg"]), current_dir);
+        assert_eq!((paths.local.as_path(), paths.remote.as_path()), (Path::new("/work/forest.jpg"), Path::new("images/forest.jpg")));
+    }
+
+    #[test]
+    fn lazy_file_created_on_first_block() {
+        let path = env::temp_dir().join(format!("tftp-lazy-{}.part", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let mut file = LazyFile::new(&path, false);
+        assert!(file.finish().is_ok());
+        assert!(!path.exists());
+
+        //the end of an empty download creates it too
+        let mut file = LazyFile::new(&path, false);
+        file.end().unwrap();
+        assert!(file.finish().is_ok());
+        assert_eq!(fs::read(&path).unwrap(), b"");
+        let _ = fs::remove_file(&path);
+
+        let mut file = LazyFile::new(&env::temp_dir().join("no-such-dir").join("a.part"), false);
+        assert!(file.write_all(b"data").is_err());
+        assert!(file.finish().is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn remote_with_interface_name() {
+        let addr = parse_remote("[fe80::1%lo]:69", DEFAULT_PORT).unwrap();
+        assert_eq!(addr, SocketAddr::V6(SocketAddrV6::new(Ipv6Addr::from_str("fe80::1").unwrap(), 69, interface_index("lo").unwrap(), 0)));
+    }
+}
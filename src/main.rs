@@ -1,10 +1,14 @@
 #[macro_use]
 extern crate num_derive;
 
+use std::process;
+
 use clap::{Command, Arg, builder::PossibleValue, ArgAction};
 
 mod server;
 mod client;
+mod checksum;
+mod batch;
 mod tftp_protocol;
 mod tlog;
 
@@ -17,15 +21,16 @@ fn main()  {
             Command::new("server")
                 .arg(Arg::new("rootdir")
                     .long("rootdir")
+                    .env("TFTP_ROOT")
                     .required(true)
                     .help("base dir of the server")
                 )
                 .arg(Arg::new("writemode")
                     .long("writemode")
                     .required(false)
-                    .value_parser([PossibleValue::new("disabled"), PossibleValue::new("new"), PossibleValue::new("overwrite")])
+                    .value_parser([PossibleValue::new("disabled"), PossibleValue::new("new"), PossibleValue::new("overwrite"), PossibleValue::new("overwrite-backup")])
                     .default_value("new")
-                    .help("Disabled: write not possible; New: New files can be uploaded; Overwrite: overwrite existing files allowed")
+                    .help("Disabled: write not possible; New: New files can be uploaded; Overwrite: overwrite existing files allowed; Overwrite-backup: like Overwrite but the existing file is kept as <name>.bak")
                 )
                 .arg(Arg::new("verbose")
                     .long("verbose")
@@ -34,6 +39,11 @@ fn main()  {
                     .help("print verbose messages")
                     .default_value("false")
                 )
+                .arg(Arg::new("trace")
+                    .long("trace")
+                    .action(ArgAction::SetTrue)
+                    .help("print a summary of every sent and received packet")
+                )
                 .arg(Arg::new("exit-with-client")
                     .long("exit-with-client")
                     .action(ArgAction::SetTrue)
@@ -41,30 +51,216 @@ fn main()  {
                 )
                 .arg(Arg::new("port")
                     .long("port")
+                    .env("TFTP_PORT")
                     .help("port number server connect to; default is 69")
                 )
+                .arg(Arg::new("bind")
+                    .long("bind")
+                    .default_value("0.0.0.0")
+                    .help("ipv4 or ipv6 address of the interface the server listens on")
+                )
+                .arg(Arg::new("max-windowsize")
+                    .long("max-windowsize")
+                    .default_value("16")
+                    .help("largest windowsize the server accepts; bigger requests are answered with this value")
+                )
+                .arg(Arg::new("allow-upload-ext")
+                    .long("allow-upload-ext")
+                    .action(ArgAction::Append)
+                    .help("only accept uploads with this file extension e.g. bin; case-insensitive; can be repeated. Without it every name is accepted")
+                )
+                .arg(Arg::new("upload-dir")
+                    .long("upload-dir")
+                    .help("only accept uploads below this directory; relative to the rootdir. Downloads are not restricted")
+                )
+                .arg(Arg::new("follow-symlinks")
+                    .long("follow-symlinks")
+                    .action(ArgAction::SetTrue)
+                    .overrides_with("no-follow-symlinks")
+                    .help("open paths through symlinks below the rootdir; their target may be outside of it")
+                )
+                .arg(Arg::new("no-follow-symlinks")
+                    .long("no-follow-symlinks")
+                    .action(ArgAction::SetTrue)
+                    .overrides_with("follow-symlinks")
+                    .help("refuse paths through a symlink below the rootdir with an access violation; the default")
+                )
+                .arg(Arg::new("connection-queue-depth")
+                    .long("connection-queue-depth")
+                    .default_value("16")
+                    .help("datagrams queued by the dispatcher for one connection; when full the oldest is dropped")
+                )
+                .arg(Arg::new("max-request-size")
+                    .long("max-request-size")
+                    .default_value("2048")
+                    .help("largest RRQ/WRQ in bytes; bigger requests are answered with an ERROR before their options are parsed")
+                )
+                .arg(Arg::new("allow-special-files")
+                    .long("allow-special-files")
+                    .action(ArgAction::SetTrue)
+                    .help("uploads to an existing fifo or device write into it without truncating, also with --writemode new; without it they are rejected")
+                )
+                .arg(Arg::new("health-port")
+                    .long("health-port")
+                    .help("answer http requests on this tcp port with 200 OK while the server accepts requests; for liveness probes. Without it no tcp port is opened")
+                )
+                .arg(Arg::new("daemonize")
+                    .long("daemonize")
+                    .action(ArgAction::SetTrue)
+                    .help("unix only: run in the background detached from the terminal; the output goes to --log-file")
+                )
+                .arg(Arg::new("pid-file")
+                    .long("pid-file")
+                    .requires("daemonize")
+                    .help("write the process id of the daemon to this file")
+                )
+                .arg(Arg::new("log-file")
+                    .long("log-file")
+                    .requires("daemonize")
+                    .help("append the log of the daemon to this file; default is to discard it")
+                )
+                .arg(Arg::new("rate-limit-per-ip")
+                    .long("rate-limit-per-ip")
+                    .help("accept at most the given number of requests per second from one ip; further requests are dropped without answer")
+                )
+                .arg(Arg::new("stats-interval")
+                    .long("stats-interval")
+                    .help("log the active transfers every n seconds; client address, file, direction, bytes so far and elapsed time")
+                )
+                .arg(Arg::new("interleave-acks")
+                    .long("interleave-acks")
+                    .action(ArgAction::SetTrue)
+                    .help("check for an ACK between the DATA packets of a window; already acknowledged packets are not sent. Avoids bursts of big windows to slow receivers")
+                )
+                .arg(Arg::new("batch-sends")
+                    .long("batch-sends")
+                    .action(ArgAction::SetTrue)
+                    .conflicts_with("interleave-acks")
+                    .help("send the DATA packets of a window with one system call (sendmmsg on linux); other platforms send them one by one")
+                )
+                .arg(Arg::new("manifest")
+                    .long("manifest")
+                    .action(ArgAction::SetTrue)
+                    .help("generate a list of all files when a directory manifest is requested; e.g images/.tftp-manifest")
+                )
+                .arg(Arg::new("manifest-name")
+                    .long("manifest-name")
+                    .default_value(tftp_protocol::MANIFEST_NAME)
+                    .help("filename which requests a directory manifest")
+                )
+                .arg(Arg::new("fsync")
+                    .long("fsync")
+                    .action(ArgAction::SetTrue)
+                    .help("sync an uploaded file to disk before the last block is acknowledged; costs throughput")
+                )
+                .arg(Arg::new("expose-caps")
+                    .long("expose-caps")
+                    .action(ArgAction::SetTrue)
+                    .help("answer a read of .tftp-caps with the supported options, limits and write mode of the server")
+                )
+                .arg(Arg::new("checksum-manifest")
+                    .long("checksum-manifest")
+                    .value_parser([PossibleValue::new("md5"), PossibleValue::new("sha256")])
+                    .help("answer a read of <file>.<algorithm> with the digest of <file>; digests are cached until the file changes")
+                )
+                .arg(Arg::new("auto-decompress")
+                    .long("auto-decompress")
+                    .action(ArgAction::SetTrue)
+                    .help("answer a read of a missing <file> with the decompressed content of <file>.gz; tsize is left out")
+                )
+                .arg(Arg::new("recv-buffer-bytes")
+                    .long("recv-buffer-bytes")
+                    .help("size of the socket receive buffer (SO_RCVBUF); larger buffers avoid drops with big windowsizes")
+                )
+                .arg(Arg::new("send-buffer-bytes")
+                    .long("send-buffer-bytes")
+                    .help("size of the socket send buffer (SO_SNDBUF)")
+                )
+                .arg(Arg::new("lock-wait")
+                    .long("lock-wait")
+                    .help("milliseconds to wait for a locked file before the request fails with \"file is locked\"; default is 0")
+                )
+                .arg(Arg::new("file-mode")
+                    .long("file-mode")
+                    .help("octal permissions of uploaded files e.g 0644; unix only")
+                )
+                .arg(Arg::new("max-upload-size")
+                    .long("max-upload-size")
+                    .help("maximum size of an uploaded file in bytes; larger uploads are aborted and the partial file is deleted")
+                )
+                .arg(Arg::new("max-total-window-memory")
+                    .long("max-total-window-memory")
+                    .help("maximum bytes of windowsize * blksize summed over all connections; a request which doesn't fit gets a smaller windowsize in the OACK")
+                )
+                .arg(Arg::new("backoff")
+                    .long("backoff")
+                    .default_value("fixed")
+                    .value_parser([PossibleValue::new("fixed"), PossibleValue::new("exponential")])
+                    .help("wait between retransmits of a download; exponential doubles the wait on every retry up to 16s")
+                )
+                .arg(Arg::new("isolate-by-ip")
+                    .long("isolate-by-ip")
+                    .action(ArgAction::SetTrue)
+                    .help("every client only sees the subdirectory of the rootdir named after its ip e.g rootdir/10.0.0.5; ':' of ipv6 is replaced by '_'. The directory is created on the first upload")
+                )
+                .arg(Arg::new("dump-rejected")
+                    .long("dump-rejected")
+                    .action(ArgAction::SetTrue)
+                    .help("print a hexdump of every request packet that was rejected")
+                )
+                .arg(Arg::new("strict-options")
+                    .long("strict-options")
+                    .action(ArgAction::SetTrue)
+                    .help("refuse requests with options the server doesn't implement with error 8 \"Option negotiation failed\"; by default they are ignored")
+                )
+                .arg(Arg::new("inject-delay-ms")
+                    .long("inject-delay-ms")
+                    .hide(true)
+                    .help("diagnostics; sleep the given milliseconds before each DATA block and ACK")
+                )
+                .arg(Arg::new("on-upload")
+                    .long("on-upload")
+                    .value_name("CMD")
+                    .help("program started after each successful upload; the path of the file is passed as argument and in TFTP_UPLOAD_PATH. \
+                           The program runs with the privileges of the server, use with care")
+                )
         )
         .subcommand(Command::new("client")
             .arg(Arg::new("remote")
                 .long("remote")
                 .required(true)
-                .help("address of the remote host; ipv4 or ipv6 address; port can also be appended e.g localhost:69")
+                .help("address of the remote host; hostname, ipv4 or ipv6 address; port can also be appended e.g localhost:69 or [::1]:69; link-local ipv6 takes the zone e.g [fe80::1%eth0]:69")
             )
             .arg(Arg::new("download")
                 .long("download")
                 .required(false)
                 .num_args(1..=2)
-                .help("download a file with the given name from the remote server")
+                .value_names(["REMOTE", "LOCAL"])
+                .action(ArgAction::Append)
+                .help("download the remote file; stored as LOCAL or under its file name in the current directory; can be repeated to download multiple files")
             )
             .arg(Arg::new("upload")
                 .long("upload")
                 .required(false)
                 .num_args(1..=2)
-                .help("upload a file with the given name to the remote server")
+                .value_names(["LOCAL", "REMOTE"])
+                .action(ArgAction::Append)
+                .help("upload the local file; stored as REMOTE or under its file name on the server; can be repeated to upload multiple files; '-' uploads stdin and needs the remote name")
+            )
+            .arg(Arg::new("download-dir")
+                .long("download-dir")
+                .required(false)
+                .num_args(1..=2)
+                .help("download all files listed in the manifest of a remote directory into a local directory")
+            )
+            .arg(Arg::new("manifest")
+                .long("manifest")
+                .required(false)
+                .help("local manifest file used by --download-dir instead of fetching .tftp-manifest from the server")
             )
             .arg(Arg::new("port")
                 .long("port")
-                .help("port number client connect to; default is 69")
+                .help("port number client connect to if --remote has none; default is 69")
             )
             .arg(Arg::new("blksize")
                 .long("blksize")
@@ -76,13 +272,129 @@ fn main()  {
                 .short('w')
                 .help("set the windows size of the transfer; means number of blocks for one ack; default is 1")
             )
+            .arg(Arg::new("utimeout")
+                .long("utimeout")
+                .help("request the timeout before a retransmit in microseconds; e.g 50000 for low latency links. Allowed are 1000 to 255000000")
+            )
+            .arg(Arg::new("interleave-acks")
+                .long("interleave-acks")
+                .action(ArgAction::SetTrue)
+                .help("upload: check for an ACK between the DATA packets of a window; already acknowledged packets are not sent. Avoids bursts of big windows to slow receivers")
+            )
+            .arg(Arg::new("batch-sends")
+                .long("batch-sends")
+                .action(ArgAction::SetTrue)
+                .conflicts_with("interleave-acks")
+                .help("upload: send the DATA packets of a window with one system call (sendmmsg on linux); other platforms send them one by one")
+            )
+            .arg(Arg::new("rollover")
+                .long("rollover")
+                .value_parser([PossibleValue::new("0"), PossibleValue::new("1")])
+                .help("request the block number following 65535; servers without support use 0")
+            )
+            .arg(Arg::new("backoff")
+                .long("backoff")
+                .default_value("fixed")
+                .value_parser([PossibleValue::new("fixed"), PossibleValue::new("exponential")])
+                .help("wait between retransmits of an upload; exponential doubles the wait on every retry up to 16s")
+            )
+            .arg(Arg::new("no-options")
+                .long("no-options")
+                .action(ArgAction::SetTrue)
+                .conflicts_with_all(["blksize", "windowsize", "rollover", "utimeout", "tsize"])
+                .help("send the request without options; a classic transfer with 512 byte blocks for legacy servers which don't answer requests with options")
+            )
+            .arg(Arg::new("inject-delay-ms")
+                .long("inject-delay-ms")
+                .hide(true)
+                .help("diagnostics; sleep the given milliseconds before each DATA block and ACK")
+            )
+            .arg(Arg::new("clobber")
+                .long("clobber")
+                .action(ArgAction::SetTrue)
+                .help("overwrite existing local files with downloads")
+            )
+            .arg(Arg::new("no-clobber")
+                .long("no-clobber")
+                .action(ArgAction::SetTrue)
+                .conflicts_with("clobber")
+                .help("fail a download before it is requested if the local file exists; this is the default")
+            )
+            .arg(Arg::new("keep-partial")
+                .long("keep-partial")
+                .action(ArgAction::SetTrue)
+                .help("keep the incomplete <file>.part of a failed download; by default it is removed")
+            )
+            .arg(Arg::new("continue")
+                .long("continue")
+                .action(ArgAction::SetTrue)
+                .conflicts_with("no-options")
+                .help("resume a download after the bytes of an existing <file>.part; needs a server with the offset option, others send the whole file again. Implies --keep-partial")
+            )
+            .arg(Arg::new("xcompress")
+                .long("xcompress")
+                .action(ArgAction::SetTrue)
+                .conflicts_with("no-options")
+                .help("ask for deflate compressed DATA blocks; not standardized, only servers of this project accept it. Others ignore it and the transfer is uncompressed")
+            )
+            .arg(Arg::new("timeout-total")
+                .long("timeout-total")
+                .help("abort the whole operation after the given number of seconds")
+            )
+            .arg(Arg::new("trace")
+                .long("trace")
+                .action(ArgAction::SetTrue)
+                .help("print a summary of every sent and received packet")
+            )
+            .arg(Arg::new("quiet")
+                .long("quiet")
+                .short('q')
+                .action(ArgAction::SetTrue)
+                .help("don't print the runtime and speed of each transfer")
+            )
+            .arg(Arg::new("local-port-range")
+                .long("local-port-range")
+                .help("bind the client to the first free local port of the range LOW-HIGH e.g 50000-50100")
+            )
+            .arg(Arg::new("follow-redirect")
+                .long("follow-redirect")
+                .num_args(0..=1)
+                .default_missing_value("3")
+                .help("repeat the request at host:port when the server answers with the error \"REDIRECT host:port\"; the value is the maximum number of redirects; default is 3")
+            )
+            .arg(Arg::new("verify")
+                .long("verify")
+                .help("expected md5 or sha256 hex digest of the downloaded file; on mismatch the file is deleted")
+            )
+            .arg(Arg::new("tsize")
+                .long("tsize")
+                .action(ArgAction::SetTrue)
+                .help("negotiate the transfer size (rfc2349); uploads from stdin or a fifo leave it out because their size is unknown")
+            )
+            .arg(Arg::new("print-oack")
+                .long("print-oack")
+                .action(ArgAction::SetTrue)
+                .conflicts_with("download-dir")
+                .help("only send the request and print the options acknowledged by the server; the transfer is aborted before any data")
+            )
+            .arg(Arg::new("probe")
+                .long("probe")
+                .action(ArgAction::SetTrue)
+                .conflicts_with_all(["download", "upload", "download-dir"])
+                .help("only check that the server answers; sends a request for a bogus name and prints the round-trip time of the first reply")
+            )
+            .arg(Arg::new("print-digest")
+                .long("print-digest")
+                .action(ArgAction::SetTrue)
+                .help("print the sha256 digest of the transferred data")
+            )
         );
 
     let args = app.clone().get_matches();
 
     match args.subcommand() {
         Some(("server", args)) => server::server_main(args),
-        Some(("client", args)) => client::client_main(args),
+        Some(("client", args)) => process::exit(client::client_main(args)),
         _ => {
             tlog::error!("no command specified");
             let _ = app.print_help();
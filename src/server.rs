@@ -1,13 +1,26 @@
-use std::{net::{UdpSocket, SocketAddr}, time::{Duration, Instant}, sync::{Mutex, Arc, mpsc::channel}, thread, collections::HashMap, path::PathBuf};
+use std::{net::{UdpSocket, SocketAddr, IpAddr}, time::{Duration, Instant}, sync::Arc, thread, collections::HashMap, path::{Component, Path, PathBuf}, str::FromStr, io::ErrorKind};
 
 use clap::*;
+use socket2::SockRef;
 
-use crate::{tftp_protocol, tlog};
+use crate::{tftp_protocol::{self, BackoffStrategy}, tlog};
 
-use self::defs::{WriteMode, ServerSettings, FileLockMode, ClientState};
+use self::storage::RealFs;
+use self::defs::{WriteMode, ServerSettings, ClientState, SharedState};
+use self::ratelimit::{RateLimiter, RATE_LIMIT_WINDOW};
+use self::transfers::StatsTimer;
 
+mod caps;
 mod connection;
+mod daemon;
 mod defs;
+mod digest;
+mod health;
+mod manifest;
+mod queue;
+mod ratelimit;
+mod storage;
+mod transfers;
 
 pub fn server_main(args: &ArgMatches) {
     //TODO: there is a more elegant way with clap; but for now simple redundant strings used
@@ -16,6 +29,7 @@ pub fn server_main(args: &ArgMatches) {
         "disabled" => WriteMode::Disabled,
         "new" => WriteMode::WriteNew,
         "overwrite" => WriteMode::WriteOverwrite,
+        "overwrite-backup" => WriteMode::WriteOverwriteBackup,
         other => panic!("writemode {} does not exist", other),
     };
 
@@ -29,34 +43,215 @@ pub fn server_main(args: &ArgMatches) {
         return;
     }
 
+    //absolute so the logs show the path which is actually opened
+    let rootdir = match std::env::current_dir() {
+        Ok(cwd) => cwd.join(rootdir).to_string_lossy().to_string(),
+        Err(_)  => rootdir.clone(),
+    };
+    let rootdir = &rootdir;
+
     let port = args.get_one::<String>("port").unwrap_or(&"69".to_string()).clone();
     let port     = u16::from_str_radix(&port, 10).expect("wrong port");
 
+    let max_windowsize = match usize::from_str(args.get_one::<String>("max-windowsize").unwrap()) {
+        Ok(x) if x > 0 => x,
+        _              => {
+            tlog::error!("max-windowsize is not a valid number of blocks");
+            return;
+        }
+    };
+
+    //'/incoming' and 'incoming' both mean the directory below the root
+    let upload_dir = match args.get_one::<String>("upload-dir").map(|x| relative_dir(x)) {
+        None            => None,
+        Some(Some(x))   => Some(x),
+        Some(None)      => {
+            tlog::error!("upload-dir has to be a directory below the rootdir");
+            return;
+        }
+    };
+
+    let queue_depth = match usize::from_str(args.get_one::<String>("connection-queue-depth").unwrap()) {
+        Ok(x) if x > 0 => x,
+        _              => {
+            tlog::error!("connection-queue-depth is not a valid number of datagrams");
+            return;
+        }
+    };
+
+    let max_request_size = match usize::from_str(args.get_one::<String>("max-request-size").unwrap()) {
+        Ok(x) if x >= 512 => x,
+        _                 => {
+            tlog::error!("max-request-size must be a number of at least 512 bytes");
+            return;
+        }
+    };
+
+    let bind = args.get_one::<String>("bind").unwrap();
+    let bind = match IpAddr::from_str(bind) {
+        Ok(x)  => x,
+        Err(_) => {
+            tlog::error!("bind = \"{}\" is not a valid ipv4 or ipv6 address", bind);
+            return;
+        }
+    };
+
+    let max_upload_size = if let Ok(x) = optional_number::<u64>(args, "max-upload-size") {x} else {return;};
+    let recv_buffer     = if let Ok(x) = optional_number::<usize>(args, "recv-buffer-bytes") {x} else {return;};
+    let send_buffer     = if let Ok(x) = optional_number::<usize>(args, "send-buffer-bytes") {x} else {return;};
+    let lock_wait       = if let Ok(x) = optional_number::<u64>(args, "lock-wait") {x} else {return;};
+    let inject_delay    = if let Ok(x) = optional_number::<u64>(args, "inject-delay-ms") {x} else {return;};
+    let rate_limit      = if let Ok(x) = optional_number::<usize>(args, "rate-limit-per-ip") {x} else {return;};
+    let stats_interval  = if let Ok(x) = optional_number::<u64>(args, "stats-interval") {x} else {return;};
+    let health_port     = if let Ok(x) = optional_number::<u16>(args, "health-port") {x} else {return;};
+    let window_memory   = if let Ok(x) = optional_number::<usize>(args, "max-total-window-memory") {x} else {return;};
+
+    let file_mode = match args.get_one::<String>("file-mode").map(|x| u32::from_str_radix(x, 8)) {
+        None         => None,
+        Some(Ok(x))  => Some(x),
+        Some(Err(_)) => {
+            tlog::error!("file-mode is not a valid octal number");
+            return;
+        }
+    };
+    if file_mode.is_some() && !cfg!(unix) {
+        tlog::warning!("file-mode is only supported on unix; ignored");
+    }
+
     let settings = ServerSettings {
         write_mode:        writemode,
-        root_dir:          rootdir.clone(),
-        blocksize:         tftp_protocol::DEFAULT_BLOCKSIZE,
-        windowsize:        tftp_protocol::DEFAULT_WINDOWSIZE,
-        verbose:           true, 
+        max_windowsize:    max_windowsize,
+        trace:             args.get_flag("trace"),
         exit_with_client:  *args.get_one::<bool>("exit-with-client").unwrap(),
-        port:              port
-
+        port:              port,
+        bind:              bind,
+        manifest:          args.get_flag("manifest"),
+        manifest_name:     args.get_one::<String>("manifest-name").unwrap().clone(),
+        max_upload_size:   max_upload_size,
+        recv_buffer:       recv_buffer,
+        send_buffer:       send_buffer,
+        lock_wait:         Duration::from_millis(lock_wait.unwrap_or(0)),
+        file_mode:         file_mode,
+        on_upload:         args.get_one::<String>("on-upload").cloned(),
+        inject_delay:      inject_delay.map(Duration::from_millis),
+        strict_options:    args.get_flag("strict-options"),
+        isolate_by_ip:     args.get_flag("isolate-by-ip"),
+        dump_rejected:     args.get_flag("dump-rejected"),
+        interleave_acks:   args.get_flag("interleave-acks"),
+        batch_sends:       args.get_flag("batch-sends"),
+        rate_limit_per_ip: rate_limit,
+        health_port:       health_port,
+        allow_special_files: args.get_flag("allow-special-files"),
+        fsync:             args.get_flag("fsync"),
+        max_request_size:  max_request_size,
+        follow_symlinks:   args.get_flag("follow-symlinks"),
+        connection_queue_depth: queue_depth,
+        allow_upload_ext:  args.get_many::<String>("allow-upload-ext").map_or(Vec::new(), |x| x.map(|ext| ext.trim_start_matches('.').to_lowercase()).collect()),
+        stats_interval:    stats_interval.filter(|x| *x > 0).map(Duration::from_secs),
+        max_total_window_memory: window_memory,
+        upload_dir:        upload_dir,
+        backoff:           BackoffStrategy::from_name(args.get_one::<String>("backoff").unwrap()).unwrap(),
+        expose_caps:       args.get_flag("expose-caps"),
+        checksum_manifest: args.get_one::<String>("checksum-manifest").cloned(),
+        auto_decompress:   args.get_flag("auto-decompress"),
+        ..ServerSettings::new(rootdir)
     };
 
+    if args.get_flag("daemonize") {
+        let pid_file = args.get_one::<String>("pid-file").map(PathBuf::from);
+        let log_file = args.get_one::<String>("log-file").map(PathBuf::from);
+        if let Err(err) = daemon::daemonize(pid_file.as_deref(), log_file.as_deref()) {
+            tlog::error!("{}", err);
+            return;
+        }
+    }
+
     run_server(settings);
 }
 
+fn optional_number<T: FromStr>(args: &ArgMatches, id: &str) -> Result<Option<T>, ()> {
+    return match args.get_one::<String>(id).map(|x| T::from_str(x)) {
+        None         => Ok(None),
+        Some(Ok(x))  => Ok(Some(x)),
+        Some(Err(_)) => {
+            tlog::error!("{} is not a valid number", id);
+            Err(())
+        }
+    };
+}
+
+//keeps the normal components; None for '..' or when nothing is left
+fn relative_dir(value: &str) -> Option<PathBuf> {
+    let mut dir = PathBuf::new();
+    for i_comp in Path::new(&value.replace('\\', "/")).components() {
+        match i_comp {
+            Component::Normal(x)                        => dir.push(x),
+            Component::RootDir | Component::CurDir      => {},
+            Component::ParentDir | Component::Prefix(_) => return None,
+        }
+    }
+    if dir.as_os_str().is_empty() {
+        return None;
+    }
+    return Some(dir);
+}
+
+//set SO_RCVBUF/SO_SNDBUF; the os may grant a different size than requested
+fn set_buffer_sizes(socket: &UdpSocket, settings: &ServerSettings, is_log: bool) {
+    let sock = SockRef::from(socket);
+
+    if let Some(size) = settings.recv_buffer {
+        match sock.set_recv_buffer_size(size).and_then(|_| sock.recv_buffer_size()) {
+            Ok(granted) => if is_log { tlog::info!("recv buffer requested = {}; granted = {}", size, granted); },
+            Err(err)    => tlog::error!("set recv buffer to {} failed; {}", size, err),
+        }
+    }
+    if let Some(size) = settings.send_buffer {
+        match sock.set_send_buffer_size(size).and_then(|_| sock.send_buffer_size()) {
+            Ok(granted) => if is_log { tlog::info!("send buffer requested = {}; granted = {}", size, granted); },
+            Err(err)    => tlog::error!("set send buffer to {} failed; {}", size, err),
+        }
+    }
+}
+
 pub fn run_server(settings: ServerSettings) {
-    let socket = UdpSocket::bind(format!("0.0.0.0:{}", settings.port)).unwrap();
+    let bind_addr = SocketAddr::new(settings.bind, settings.port);
+    let socket = match UdpSocket::bind(bind_addr) {
+        Ok(x)    => x,
+        Err(err) => {
+            tlog::error!("bind to {} failed; {}", bind_addr, err);
+            if err.kind() == ErrorKind::PermissionDenied && settings.port < 1024 {
+                tlog::error!("port {} is privileged; run as root, grant CAP_NET_BIND_SERVICE (e.g. setcap 'cap_net_bind_service=+ep' <tftp binary>) or use --port with a number >= 1024", settings.port);
+            }
+            return;
+        }
+    };
     let _ = socket.set_read_timeout(Some(Duration::from_secs(1)));  //TODO: check for error
+    set_buffer_sizes(&socket, &settings, true);
+
+    let heartbeat = health::Heartbeat::new();
+    if let Some(port) = settings.health_port {
+        if let Err(err) = health::spawn(SocketAddr::new(settings.bind, port), heartbeat.clone()) {
+            tlog::error!("bind of health port {} failed; {}", port, err);
+            return;
+        }
+    }
+
     let mut connections = HashMap::<SocketAddr,ClientState>::new();
     let mut cleanpup_stopwatch = Instant::now();
 
-    let files_locked = Arc::new(Mutex::new(HashMap::<PathBuf,FileLockMode>::new()));
+    let shared = SharedState::new(Arc::new(RealFs));
+    let mut rate_limiter = settings.rate_limit_per_ip.map(|x| RateLimiter::new(x, RATE_LIMIT_WINDOW));
+    let mut stats_timer  = settings.stats_interval.map(StatsTimer::new);
 
     let mut buf = Vec::<u8>::new();
 
     loop {
+        heartbeat.beat();
+        if let Some(timer) = stats_timer.as_mut() {
+            timer.poll(&shared.transfers);
+        }
+
         //cleanup
         if cleanup_connections(&mut connections, &mut cleanpup_stopwatch) && settings.exit_with_client {
             break;
@@ -73,11 +268,20 @@ pub fn run_server(settings: ServerSettings) {
 
         buf.resize(amt, 0);
     
-        if connections.contains_key(&src) {
-            let _ = connections.get(&src).unwrap().tx.send(buf.clone());
+        if let Some(client_state) = connections.get(&src) {
+            if client_state.tx.send(buf.clone()) > 0 {
+                tlog::debug!("{:?} connection queue full; oldest datagram dropped", src);
+            }
         }
         else {
-            let (sender, receiver) = channel();
+            //dropped without ERROR; an answer would amplify the flood
+            if let Some(limiter) = rate_limiter.as_mut() {
+                if !limiter.allow(src.ip(), Instant::now()) {
+                    continue;
+                }
+            }
+
+            let (sender, receiver) = queue::bounded(settings.connection_queue_depth);
 
             let mut client_state = ClientState {
                 tx: sender,
@@ -85,17 +289,25 @@ pub fn run_server(settings: ServerSettings) {
             };
 
             let remote = src;
-            
-            let socket = socket.try_clone().unwrap();
+
+            //each transfer is answered from a new port so the server port stays free for requests
+            let socket = match UdpSocket::bind(SocketAddr::new(settings.bind, 0)) {
+                Ok(x)    => x,
+                Err(err) => {
+                    tlog::error!("{:?} bind of transfer socket failed; {}", remote, err);
+                    continue;
+                }
+            };
+            set_buffer_sizes(&socket, &settings, false);
             let settings = settings.clone();
-            let files_locked = files_locked.clone();
+            let shared = shared.clone();
             client_state.join_handle = Some(thread::spawn(move|| {
                 connection::Connection::new(
                     receiver, 
                     remote,
                     socket,
                     settings,
-                    files_locked).run();
+                    shared).run();
             }));
 
 
@@ -0,0 +1,29 @@
+use crate::tftp_protocol::{KNOWN_OPTIONS, MAX_BLOCKSIZE};
+
+use super::defs::{ServerSettings, WriteMode};
+
+//generate the capabilities returned for a read of CAPS_NAME;
+//one "<name>\t<value>\n" line per entry
+pub fn generate(settings: &ServerSettings) -> Vec<u8> {
+    let write_mode = match settings.write_mode {
+        WriteMode::Disabled       => "disabled",
+        WriteMode::WriteNew       => "new",
+        WriteMode::WriteOverwrite => "overwrite",
+        WriteMode::WriteOverwriteBackup => "overwrite-backup",
+    };
+
+    let entries = [
+        ("options",        KNOWN_OPTIONS.join(" ")),
+        ("max-blksize",    MAX_BLOCKSIZE.to_string()),
+        ("max-windowsize", settings.max_windowsize.to_string()),
+        ("write-mode",     write_mode.to_string()),
+        ("manifest",       if settings.manifest {settings.manifest_name.clone()} else {"disabled".to_string()}),
+    ];
+
+    let mut out = Vec::new();
+    for (name, value) in entries {
+        out.extend_from_slice(format!("{}\t{}\n", name, value).as_bytes());
+    }
+
+    return out;
+}
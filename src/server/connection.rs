@@ -1,29 +1,90 @@
-use std::ffi::OsString;
-use std::io::Write;
-use std::net::{SocketAddr, UdpSocket};
+use std::io::{Cursor, Read, Write, ErrorKind};
+use std::net::{IpAddr, SocketAddr, UdpSocket};
 use std::ops::DerefMut;
 use std::time::{Instant, Duration};
-use std::{sync::mpsc::Receiver};
+use std::sync::mpsc::RecvTimeoutError;
 use std::str::{self, FromStr};
 use std::path::{Path, PathBuf};
-use std::fs::File;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::path;
+use std::process::Command;
+use std::thread;
 
+use flate2::Compression;
+use flate2::read::{DeflateEncoder, GzDecoder};
 
-use crate::server::defs::{ServerSettings,WriteMode,FileLockMap, FileLockMode};
+use crate::server::defs::{ServerSettings,WriteMode,FileLockMap, FileLockMode, DigestCache, WindowMemory, SharedState, LOCK_RETRY_INTERVAL, lock_recover};
+use crate::server::{manifest, caps, digest};
+use crate::server::transfers::{ActiveTransfer, TransferMap};
+use crate::server::queue::QueueReceiver;
+use crate::server::storage::{self, Storage, SyncWrite};
+use crate::batch;
+use crate::checksum::{Checksum, ChecksumReader};
 
 use crate::{tftp_protocol::{*, self}, tlog};
 
 pub struct Connection {
-    recv:         Receiver<Vec<u8>>,
+    recv:         QueueReceiver,        //request and its retransmits sent to the server port
     remote:       SocketAddr,
-    socket:       UdpSocket,
+    socket:       UdpSocket,            //own ephemeral port; the transfer id of the server
     settings:     ServerSettings,
     start:        Instant,
     bytecount:    usize,
+    retransmits:  usize,
     lockmap:      FileLockMap,
+    digests:      DigestCache,
+    storage:      Arc<dyn Storage>,
     locked:       Option<PathBuf>,
+    backup:       Option<(PathBuf, PathBuf)>, //(file, backup) of an overwrite; restored if the upload is aborted
     buf:          Option<Vec<u8>>,
+    option_order: Vec<String>,          //names of the request options; the OACK echoes them in this order
+    is_tsize_requested: bool,
+    tsize:        Option<u64>,          //acked in the OACK; None if unknown e.g. a fifo
+    offset:       Option<u64>,          //first byte of a resumed download; acked only if the file has it
+    xcompress:    bool,                 //the DATA blocks are deflated in both directions
+    transfers:    TransferMap,
+    progress:     Arc<AtomicU64>,       //bytes so far; shared with the entry in transfers
+    window_memory: WindowMemory,
+    window_reserved: usize,             //part of window_memory taken by this connection
+}
+
+//join a requested filename onto the root; '\\' and '/' are both accepted as separator
+//so requests of windows clients reach subdirectories; the result never leaves the root
+fn resolve_path(root_dir: &Path, path_relative: &str) -> Result<PathBuf> {
+    let request_path = path_relative.replace('\\', "/");
+
+    let is_escape = Path::new(&request_path).components().any(|x| !matches!(x, path::Component::Normal(_) | path::Component::CurDir));
+    if is_escape {
+        return Err(ErrorNumber::FileNotFound.into());
+    }
+
+    let full_path     = root_dir.join(request_path);
+
+    if !full_path.starts_with(root_dir) {
+        return Err(ErrorNumber::FileNotFound.into());
+    }
+
+    return Ok(full_path.to_path_buf());
+}
+
+//the ERROR of a file which can't be opened for reading
+fn open_read_error(err: &std::io::Error) -> ErrorResponse {
+    return match err.kind() {
+        ErrorKind::NotFound         => ErrorNumber::FileNotFound.into(),
+        ErrorKind::PermissionDenied => ErrorNumber::AccessViolation.into(),
+        _                           => ErrorNumber::NotDefined.into(),
+    };
+}
+
+//ipv4 clients of a dual stack socket use the plain ipv4 directory;
+//':' is replaced because windows doesn't allow it in file names
+fn client_dir_name(ip: IpAddr) -> String {
+    let ip = match ip {
+        IpAddr::V6(x) => x.to_ipv4_mapped().map_or(IpAddr::V6(x), IpAddr::V4),
+        x             => x,
+    };
+    return ip.to_string().replace(':', "_");
 }
 
 pub struct ParsedRequest {
@@ -35,34 +96,87 @@ pub struct ParsedRequest {
 type Result<T> = std::result::Result<T,ErrorResponse>;
 
 impl Connection {
+    fn trace_packet(&self, direction: &str, packet: &[u8]) {
+        if self.settings.trace {
+            tlog::debug!("{:?} {} {}", self.remote, direction, describe_packet(packet));
+        }
+    }
+
     fn send_raw_release(&mut self, buf: Vec<u8>) {
+        self.trace_packet("send", &buf);
         self.socket.send_to(&buf, self.remote).unwrap();
         self.buf = Some(buf);
     }
 
     fn send_raw(&mut self, packet: &[u8]) {
+        self.trace_packet("send", packet);
         self.socket.send_to(packet, self.remote).unwrap();
     }
 
     fn send_error(&mut self, error: &ErrorResponse) {
         let mut buf = self.buf.take().unwrap();
         
-        //TODO: to many allocations
-        let err_str = if let Some(x) = error.msg.as_ref() {
-            x.clone()
-        } else {
-            "unknown".to_string()
-        };
-        
         let _ = PacketBuilder::new(&mut buf)
             .opcode(Opcode::Error)
             .number16(error.number as u16)
-            .str(&err_str)
+            .str(&error.to_string())
             .separator()
             .as_bytes();
         self.send_raw_release(buf);
     }
 
+    //reject a packet which does not belong to this transfer
+    fn send_unknown_tid(&mut self, src: SocketAddr) {
+        let mut buf = Vec::new();
+        let _ = PacketBuilder::new(&mut buf)
+            .opcode(Opcode::Error)
+            .number16(ErrorNumber::UnknownTransferID as u16)
+            .str(&ErrorNumber::UnknownTransferID.to_string())
+            .separator()
+            .as_bytes();
+        let _ = self.socket.send_to(&buf, src);
+    }
+
+    //wait for the next packet of the remote on the own socket of this connection;
+    //a zero timeout only takes a packet which is already queued
+    fn recv_packet(&mut self, timeout: Duration) -> Option<Vec<u8>> {
+        let deadline = Instant::now() + timeout;
+        let mut buf = vec![0; self.settings.blocksize.max(DEFAULT_BLOCKSIZE) + DATA_OFFSET];
+        let is_poll = timeout.is_zero();
+        let _ = self.socket.set_nonblocking(is_poll);
+
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() && !is_poll {
+                return None;
+            }
+
+            if !is_poll {
+                let _ = self.socket.set_read_timeout(Some(remaining));
+            }
+            let (size, src) = match self.socket.recv_from(&mut buf) {
+                Ok(x)                                            => x,
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => {
+                    if err.kind() != ErrorKind::WouldBlock && err.kind() != ErrorKind::TimedOut {
+                        tlog::error!("{:?} recv failed; {}", self.remote, err);
+                    }
+                    return None;
+                }
+            };
+
+            if src != self.remote {
+                tlog::warning!("{:?} packet from unknown transfer id {:?}", self.remote, src);
+                self.send_unknown_tid(src);
+                continue;
+            }
+
+            buf.truncate(size);
+            self.trace_packet("recv", &buf);
+            return Some(buf);
+        }
+    }
+
     fn send_ack(&mut self, blocknr: u16) {
         let mut buf = self.buf.take().unwrap();
         let _ = PacketBuilder::new(&mut buf)
@@ -74,25 +188,64 @@ impl Connection {
     }
 
     fn get_file_path(&self, path_relative: &str) -> Result<PathBuf> {
-        let base_path    = OsString::from(&self.settings.root_dir);
-        let request_path = OsString::from(&path_relative);
-        let full_path     = Path::new(&base_path).join(request_path);
+        let root      = self.client_root();
+        let full_path = resolve_path(&root, path_relative)?;
+        self.check_symlinks(&root, &full_path)?;
+        return Ok(full_path);
+    }
 
-        if !full_path.starts_with(base_path) {
-            return Err(ErrorNumber::FileNotFound.into());
+    //a symlink below the root may point anywhere; without --follow-symlinks no component
+    //of the path may be one. The root itself is chosen by the admin and may be a symlink
+    fn check_symlinks(&self, root: &Path, full_path: &Path) -> Result<()> {
+        if self.settings.follow_symlinks {
+            return Ok(());
         }
 
-        return Ok(full_path.to_path_buf());
+        let relative = if let Ok(x) = full_path.strip_prefix(root) {x} else {return Err(ErrorNumber::AccessViolation.into());};
+        let mut path = root.to_path_buf();
+        for i_component in relative.components() {
+            path.push(i_component);
+            if self.storage.is_symlink(&path) {
+                tlog::warning!("{:?} {:?} is a symlink; refused without --follow-symlinks", self.remote, path);
+                return Err(ErrorNumber::AccessViolation.into());
+            }
+        }
+        return Ok(());
+    }
+
+    //with --isolate-by-ip every client sees only its own subdirectory of the root
+    fn client_root(&self) -> PathBuf {
+        let root = PathBuf::from(&self.settings.root_dir);
+        if !self.settings.isolate_by_ip {
+            return root;
+        }
+        return root.join(client_dir_name(self.remote.ip()));
     }
 
+    //on a conflict the lock is retried until --lock-wait is over
     fn check_lock_file(&mut self, path: &Path, mode: FileLockMode) -> bool {
-        let mut lockset = self.lockmap.lock().unwrap();
+        let deadline = Instant::now() + self.settings.lock_wait;
+
+        loop {
+            if self.try_lock_file(path, mode) {
+                return true;
+            }
+            if Instant::now() >= deadline {
+                return false;
+            }
+            thread::sleep(LOCK_RETRY_INTERVAL);
+        }
+    }
+
+    fn try_lock_file(&mut self, path: &Path, mode: FileLockMode) -> bool {
+        let mut lockset = lock_recover(&self.lockmap, "lock map");
         let lockset = lockset.deref_mut();
 
         if let Some(curr) = lockset.get_mut(path) {
             let _ = match (mode,curr) {
                (FileLockMode::Read(_mode), FileLockMode::Read(curr))   => {
                     *curr += 1; 
+                    tlog::debug!("{:?} lock read {:?}; readers = {}", self.remote, path, curr);
                     self.locked = Some(path.to_path_buf());
                     return true;
                 },    
@@ -101,12 +254,19 @@ impl Connection {
         }
         else {
             lockset.insert(path.to_path_buf(), mode);
+            tlog::debug!("{:?} lock {} {:?}", self.remote, if let FileLockMode::Write = mode {"write"} else {"read"}, path);
+            self.locked = Some(path.to_path_buf());
             return true;
         }
     }
 
     fn unlock_file(&mut self, path: &Path) {
-        let mut lockset = self.lockmap.lock().unwrap();
+        //released here; not again on drop
+        if self.locked.as_deref() == Some(path) {
+            self.locked = None;
+        }
+
+        let mut lockset = lock_recover(&self.lockmap, "lock map");
         let lockset = lockset.deref_mut();
 
         if lockset.contains_key(path) {
@@ -122,60 +282,254 @@ impl Connection {
             if is_remove {
                 lockset.remove(path);
             }
+            tlog::debug!("{:?} unlock {:?}", self.remote, path);
         }
         else {
-            tlog::warning!("WARN: {:?} double unlock file = {:?}", self.remote, path);
+            tlog::warning!("{:?} double unlock file = {:?}", self.remote, path);
+        }
+    }
+
+    //returns the directory if the request is a manifest for it
+    fn manifest_dir(&self, full_path: &Path) -> Result<Option<PathBuf>> {
+        let is_manifest_name = full_path.file_name().map_or(false, |x| *x == *self.settings.manifest_name);
+        if !is_manifest_name || self.storage.exists(full_path) {
+            return Ok(None);
+        }
+
+        let dir = if let Some(x) = full_path.parent() {x} else {return Ok(None)};
+        if !self.storage.metadata(dir).map_or(false, |x| x.is_dir) {
+            return Ok(None);
+        }
+
+        if !self.settings.manifest {
+            return Err(ErrorNumber::AccessViolation.into());
+        }
+
+        return Ok(Some(dir.to_path_buf()));
+    }
+
+    //returns the file if the request is a digest sidecar like <file>.sha256
+    fn digest_file(&self, full_path: &Path) -> Option<PathBuf> {
+        let algorithm = self.settings.checksum_manifest.as_ref()?;
+        if self.storage.exists(full_path) || full_path.extension().map_or(true, |x| *x != **algorithm) {
+            return None;
+        }
+
+        let file = full_path.with_extension("");
+        if self.check_symlinks(&self.client_root(), &file).is_err() {
+            return None;
+        }
+        let is_file = self.storage.metadata(&file).map_or(false, |x| !x.is_dir);
+        return if is_file {Some(file)} else {None};
+    }
+
+    //--auto-decompress; returns <file>.gz if the requested file doesn't exist
+    fn gz_file(&self, full_path: &Path) -> Option<PathBuf> {
+        if !self.settings.auto_decompress || self.storage.exists(full_path) {
+            return None;
+        }
+
+        let mut file = full_path.as_os_str().to_os_string();
+        file.push(".gz");
+        let file = PathBuf::from(file);
+        if self.check_symlinks(&self.client_root(), &file).is_err() {
+            return None;
         }
+        let is_file = self.storage.metadata(&file).map_or(false, |x| !x.is_dir && !x.is_special);
+        return if is_file {Some(file)} else {None};
     }
 
     fn download(&mut self, filename: &str) -> Result<()> {
+        //only a regular file is resumed; everything else is sent from the start without acking the offset
+        let offset = self.offset.take();
+
+        if self.settings.expose_caps && filename == CAPS_NAME {
+            let caps = caps::generate(&self.settings);
+            self.set_download_size(Some(caps.len() as u64));
+            return self.send_data(&mut Cursor::new(caps));
+        }
+
         let full_path     = self.get_file_path(filename)?;
 
+        if let Some(dir) = self.manifest_dir(&full_path)? {
+            let manifest = match manifest::generate(self.storage.as_ref(), &dir) {
+                Err(_)      => return Err(ErrorNumber::NotDefined.into()),
+                Ok(x) => x,
+            };
+            self.set_download_size(Some(manifest.len() as u64));
+            return self.send_data(&mut Cursor::new(manifest));
+        }
+
+        if let Some(file) = self.digest_file(&full_path) {
+            let algorithm = self.settings.checksum_manifest.clone().unwrap();
+            let hex = match digest::get(&self.digests, self.storage.as_ref(), &algorithm, &file) {
+                Err(_) => return Err(ErrorNumber::NotDefined.into()),
+                Ok(x)  => x,
+            };
+            let name = file.file_name().unwrap().to_string_lossy();
+            let sidecar = format!("{}  {}\n", hex, name);
+            self.set_download_size(Some(sidecar.len() as u64));
+            return self.send_data(&mut Cursor::new(sidecar));
+        }
+
+        //the decompressed size isn't known without reading the whole file; tsize and offset are left out
+        if let Some(file) = self.gz_file(&full_path) {
+            if !self.check_lock_file(&file, FileLockMode::Read(1)) {
+                return Err(ErrorResponse::new_custom("file is locked".to_string()));
+            }
+            let reader = self.storage.open_read(&file, 0).map_err(|x| open_read_error(&x))?;
+            tlog::info!("{:?} read {:?} decompressed", self.remote, file);
+            self.set_download_size(None);
+            return self.send_data(&mut GzDecoder::new(reader));
+        }
+
         if !self.check_lock_file(&full_path, FileLockMode::Read(1)) {
             return Err(ErrorResponse::new_custom("file is locked".to_string()));
         }
 
-        let mut file = match File::open(&full_path) {
-            Err(_)      => return Err(ErrorNumber::NotDefined.into()),
-            Ok(x) => x,
+        //a fifo or device has no size; tsize is left out instead of sending 0
+        let size = self.storage.metadata(&full_path).ok().filter(|x| !x.is_special).map(|x| x.len);
+        self.offset = offset.filter(|x| size.map_or(false, |size| *x <= size));
+
+        let mut file = self.storage.open_read(&full_path, self.offset.unwrap_or(0)).map_err(|x| open_read_error(&x))?;
+        tlog::info!("{:?} read {:?}", self.remote, full_path);
+        if let Some(offset) = self.offset {
+            tlog::info!("{:?} resume at offset {}", self.remote, offset);
+        }
+        self.set_download_size(size);
+
+        //the digest of a served file is computed while sending it; a resumed download doesn't see all of it
+        let checksum = self.settings.checksum_manifest.as_deref().and_then(Checksum::from_name).filter(|_| self.offset.unwrap_or(0) == 0);
+        if let Some(checksum) = checksum {
+            let meta = if let Ok(x) = self.storage.metadata(&full_path) {x} else {return Err(ErrorNumber::NotDefined.into())};
+            let mut reader = ChecksumReader::new(&mut file, checksum);
+            self.send_data(&mut reader)?;
+
+            digest::store(&self.digests, &full_path, &meta, reader.into_checksum().finalize_hex());
+            return Ok(());
+        }
+
+        return self.send_data(&mut file);
+    }
+
+    fn send_data(&mut self, reader: &mut dyn Read) -> Result<()> {
+        //the OACK is deferred until the file is open, a failed request gets only the ERROR
+        self.handle_extendes_request();
+
+        let mut deflate;
+        let reader: &mut dyn Read = if self.xcompress {
+            deflate = DeflateEncoder::new(reader, Compression::default());
+            &mut deflate
+        } else {
+            reader
         };
 
         let blocksize  = self.settings.blocksize;
         let windowsize = self.settings.windowsize;
 
-        let mut window_buffer = SendStateMachine::new(&mut file, blocksize, windowsize);
+        let mut window_buffer = SendStateMachine::new(reader, blocksize, windowsize);
+        window_buffer.set_rollover(self.settings.rollover.unwrap_or(0));
+        window_buffer.set_backoff(self.settings.backoff);
+        window_buffer.set_inject_delay(self.settings.inject_delay);
+        if let Some(utimeout) = self.settings.utimeout {
+            window_buffer.set_resend_timeout(utimeout);
+        }
+        let interleave_acks = self.settings.interleave_acks;
+        let batch_sends     = self.settings.batch_sends;
+        //a short utimeout has to be checked more often than once per second
+        let recv_timeout    = self.settings.utimeout.map_or(SEND_RECV_BLOCK_TIMEOUT, |x| x.min(SEND_RECV_BLOCK_TIMEOUT));
 
         while let action = window_buffer.next() {
             match action {
-                SendAction::SendBuffer(bufs) => {
-                    for i_frame in window_buffer.send_data() {
+                SendAction::SendBuffer if batch_sends => {
+                    let frames = window_buffer.next_frames();
+                    for i_frame in frames {
+                        self.trace_packet("send", i_frame);
+                    }
+                    let _ = batch::send_batch(&self.socket, Some(self.remote), frames);
+                },
+                SendAction::SendBuffer => {
+                    while let Some(i_frame) = window_buffer.next_frame() {
+                        self.trace_packet("send", i_frame);
                         let _ = self.socket.send_to(i_frame, self.remote);
+
+                        //an early ACK trims the frames which are still to be sent
+                        if interleave_acks {
+                            if let Some(data) = self.recv_packet(Duration::ZERO) {
+                                window_buffer.ack_packet(&data);
+                            }
+                        }
                     }
                 },
-                SendAction::Timeout => { return Err(ErrorResponse::new_custom("ack timeout".into()));  }
+                SendAction::Timeout => {
+                    self.retransmits = window_buffer.retransmits();
+                    return Err(ErrorResponse::new_custom("ack timeout".into()));
+                }
                 SendAction::End => break,
                 _ => {}
             }
 
-            if let Ok(data) =  self.recv.recv_timeout(SEND_RECV_BLOCK_TIMEOUT) {
+            if let Some(data) = self.recv_packet(recv_timeout) {
                 window_buffer.ack_packet(&data);
             }        
+            self.progress.store(window_buffer.read_len() as u64, Ordering::Relaxed);
         }
 
-        self.bytecount = window_buffer.read_len();
+        self.bytecount   = window_buffer.read_len();
+        self.retransmits = window_buffer.retransmits();
 
         return Ok(())
     }
 
-    fn open_upload_file(&mut self, filename: &str) -> Result<File> {
+    //--allow-upload-ext; case-insensitive; an empty list allows every name
+    fn is_upload_ext_allowed(&self, filename: &str) -> bool {
+        if self.settings.allow_upload_ext.is_empty() {
+            return true;
+        }
+        let name = filename.replace('\\', "/");
+        let ext  = if let Some(x) = Path::new(&name).extension() {x.to_string_lossy().to_lowercase()} else {return false;};
+        return self.settings.allow_upload_ext.iter().any(|x| *x == ext);
+    }
+
+    //--upload-dir; the name has to point below the directory, not at the directory itself
+    fn is_in_upload_dir(&self, filename: &str) -> bool {
+        let dir = if let Some(x) = &self.settings.upload_dir {x} else {return true;};
+        let name = filename.replace('\\', "/");
+        let path: PathBuf = Path::new(&name).components().filter(|x| !matches!(x, path::Component::CurDir)).collect();
+        return path.starts_with(dir) && path != *dir;
+    }
+
+    fn open_upload_file(&mut self, filename: &str) -> Result<Box<dyn SyncWrite>> {
         if self.settings.write_mode == WriteMode::Disabled {
             return Err(ErrorNumber::AccessViolation.into());
         }
 
+        if !self.is_upload_ext_allowed(filename) {
+            tlog::warning!("{:?} upload of {} refused; extension not in --allow-upload-ext", self.remote, filename);
+            return Err(ErrorNumber::AccessViolation.into());
+        }
+
+        if !self.is_in_upload_dir(filename) {
+            tlog::warning!("{:?} upload of {} refused; not below --upload-dir", self.remote, filename);
+            return Err(ErrorNumber::AccessViolation.into());
+        }
+
+        if self.settings.isolate_by_ip {
+            if let Err(err) = self.storage.create_dir_all(&self.client_root()) {
+                tlog::error!("{:?} create {:?} failed; {}", self.remote, self.client_root(), err);
+                return Err(ErrorNumber::AccessViolation.into());
+            }
+        }
+
         let full_path     = self.get_file_path(filename)?;
 
-        let is_file = path::Path::new(full_path.as_os_str()).exists();
-        let is_overwrite = self.settings.write_mode == WriteMode::WriteOverwrite;
+        if self.storage.metadata(&full_path).map_or(false, |x| x.is_special) {
+            return self.open_special_file(&full_path);
+        }
+
+        let is_file = self.storage.exists(&full_path);
+        let is_backup    = self.settings.write_mode == WriteMode::WriteOverwriteBackup;
+        let is_overwrite = self.settings.write_mode == WriteMode::WriteOverwrite || is_backup;
 
         if is_file && !is_overwrite {
             return Err(ErrorNumber::FileAlreadyExists.into());
@@ -185,40 +539,184 @@ impl Connection {
             return Err(ErrorResponse::new_custom("file is locked".to_string()));
         }
 
+        //keep the previous version in case the upload is bad
+        if is_file && is_backup {
+            let mut backup = full_path.clone().into_os_string();
+            backup.push(".bak");
+            if let Err(err) = self.storage.rename(&full_path, Path::new(&backup)) {
+                tlog::error!("{:?} backup of {:?} failed; {}", self.remote, full_path, err);
+                return Err(ErrorNumber::AccessViolation.into());
+            }
+            self.backup = Some((full_path.clone(), PathBuf::from(backup)));
+        }
+
         //TODO: use better varaint... like ok_or
-        return match File::create(&full_path) {
-            Err(_)      => Err(ErrorNumber::NotDefined.into()),
-            Ok(file) => Ok(file),
-        };  
+        return match self.storage.open_write(&full_path, self.settings.file_mode) {
+            Err(_)      => {
+                self.restore_backup();
+                Err(ErrorNumber::NotDefined.into())
+            },
+            Ok(file) => {
+                tlog::info!("{:?} write {:?}", self.remote, full_path);
+                Ok(file)
+            },
+        };
+    }
+
+    //a fifo is written through; the write mode doesn't apply because nothing is created or replaced
+    fn open_special_file(&mut self, full_path: &Path) -> Result<Box<dyn SyncWrite>> {
+        if !self.settings.allow_special_files {
+            tlog::warning!("{:?} {:?} is not a regular file; see --allow-special-files", self.remote, full_path);
+            return Err(ErrorNumber::AccessViolation.into());
+        }
+
+        if !self.check_lock_file(full_path, FileLockMode::Write) {
+            return Err(ErrorResponse::new_custom("file is locked".to_string()));
+        }
+
+        return match self.storage.open_special(full_path) {
+            Err(err) => {
+                tlog::error!("{:?} open special file {:?} failed; {}", self.remote, full_path, err);
+                Err(ErrorNumber::AccessViolation.into())
+            },
+            Ok(file) => {
+                tlog::info!("{:?} write special file {:?}", self.remote, full_path);
+                Ok(file)
+            },
+        };
+    }
+
+    //removes an incomplete upload; an overwritten file is back in place
+    fn discard_upload(&mut self, full_path: &Path) {
+        //a fifo stays; only a regular file is the incomplete upload
+        if !self.storage.metadata(full_path).map_or(false, |x| x.is_special) {
+            let _ = self.storage.remove(full_path);
+        }
+        self.restore_backup();
+    }
+
+    //an overwritten file is renamed back from its backup
+    fn restore_backup(&mut self) {
+        if let Some((file, backup)) = self.backup.take() {
+            if let Err(err) = self.storage.rename(&backup, &file) {
+                tlog::error!("{:?} restore of {:?} failed; {}", self.remote, file, err);
+            }
+        }
     }
 
     fn upload(&mut self, filename: &str) -> Result<()> {
+        //the announced size is rejected before anything is written
+        if let (Some(tsize), Some(max)) = (self.tsize, self.settings.max_upload_size) {
+            if tsize > max {
+                tlog::warning!("{:?} upload of tsize={} exceeds max-upload-size={}; path={}", self.remote, tsize, max, filename);
+                return Err(ErrorNumber::DiskFull.into());
+            }
+        }
+
         let timeout_msg = format!("upload timeout; path={}", filename).to_string();
         let mut file = self.open_upload_file(filename)?;
+        //the received blocks are compressed; the limit applies to the inflated data
+        let max_recv_len = if self.xcompress {None} else {self.settings.max_upload_size};
+        if self.xcompress {
+            file = storage::inflate_into(file, self.settings.max_upload_size);
+        }
+
+        //without OACK a write request is acknowledged with block 0;
+        //either reply tells the client the transfer id of this connection
+        if !self.handle_extendes_request() {
+            self.send_ack(0);
+        }
 
-        let mut ctrl_result = RecvController::new(self.settings.windowsize, self.settings.blocksize, Box::new(|action| {
+        let rollover        = self.settings.rollover.unwrap_or(0);
+        let max_upload_size = self.settings.max_upload_size;
+        let inject_delay    = self.settings.inject_delay;
+        let utimeout        = self.settings.utimeout;
+        let fsync           = self.settings.fsync;
+        let progress        = self.progress.clone();
+
+        let mut ctrl = RecvController::new(self.settings.windowsize, self.settings.blocksize, Box::new(|action| {
             match action {
                 tftp_protocol::RecvCallbackArg::WriteSink(data) => {
-                    file.write(data);
+                    file.write_all(data)?;
+                    progress.fetch_add(data.len() as u64, Ordering::Relaxed);
+                },
+                tftp_protocol::RecvCallbackArg::SinkEnd => {
+                    file.finish()?;
+                    if fsync {
+                        file.sync()?;
+                    }
                 },
-                tftp_protocol::RecvCallbackArg::Ack(ack_packet) => {
-                    let _ = self.send_raw(ack_packet);
+                tftp_protocol::RecvCallbackArg::Ack(packet) | tftp_protocol::RecvCallbackArg::Error(packet) => {
+                    let _ = self.send_raw(packet);
                 }
                 tftp_protocol::RecvCallbackArg::Recv(out_buff, timeout) => {
-                    if let Ok(data) = self.recv.recv_timeout(timeout) {
+                    if let Some(data) = self.recv_packet(timeout) {
                         out_buff.write_all(&data);
                     }
                 }
             }
-        })).run();
+            Ok(())
+        }));
+        ctrl.set_rollover(rollover);
+        ctrl.set_max_len(max_recv_len);
+        ctrl.set_inject_delay(inject_delay);
+        if let Some(utimeout) = utimeout {
+            ctrl.set_timeout(utimeout);
+        }
+        let ctrl_result = ctrl.run();
+        let is_exceeded = ctrl.is_max_len_exceeded();
+        drop(ctrl);
+        drop(file);
+
+        //an aborted upload never replaces the previous file
+        if ctrl_result.is_err() || is_exceeded {
+            let full_path = self.get_file_path(filename)?;
+            self.discard_upload(&full_path);
+        }
+
+        let is_disk_full = matches!(ctrl_result, Err(TransferError::Sink(_)));
+        if is_exceeded || is_disk_full {
+            if is_exceeded {
+                tlog::warning!("{:?} upload exceeds max-upload-size={}; path={}", self.remote, max_upload_size.unwrap(), filename);
+            } else {
+                tlog::error!("{:?} upload aborted; {}; path={}", self.remote, ctrl_result.unwrap_err(), filename);
+            }
+            return Err(ErrorNumber::DiskFull.into());
+        }
     
-        match ctrl_result {
-            Err(err) => return Err(ErrorResponse::new_custom(err.clone())),
-            _ => return Ok(())
+        ctrl_result.map_err(|x| ErrorResponse::new_custom(x.to_string()))?;
+
+        if let Some(cmd) = &self.settings.on_upload {
+            self.run_upload_hook(cmd, &self.get_file_path(filename)?);
         }
+
+        return Ok(());
     }
 
-    pub fn new(recv: Receiver<Vec<u8>>, remote: SocketAddr, socket: UdpSocket, settings: ServerSettings, lockmap: FileLockMap) -> Connection {
+    //the hook is detached; a slow hook must not delay the final ack
+    //note: it runs with the privileges of the server
+    fn run_upload_hook(&self, cmd: &str, path: &Path) {
+        let spawned = Command::new(cmd)
+            .arg(path)
+            .env("TFTP_UPLOAD_PATH", path)
+            .env("TFTP_REMOTE", self.remote.to_string())
+            .spawn();
+
+        match spawned {
+            Ok(mut child) => {
+                tlog::info!("{:?} on-upload started; pid={} path={:?}", self.remote, child.id(), path);
+                //reap the child so it doesn't stay a zombie
+                thread::spawn(move || {
+                    let _ = child.wait();
+                });
+            },
+            Err(err) => {
+                tlog::error!("{:?} on-upload {:?} failed to start; {}", self.remote, cmd, err);
+            }
+        }
+    }
+
+    pub fn new(recv: QueueReceiver, remote: SocketAddr, socket: UdpSocket, settings: ServerSettings, shared: SharedState) -> Connection {
         return Connection{
             recv:         recv,
             remote:       remote,
@@ -226,50 +724,89 @@ impl Connection {
             settings:     settings,
             start:        Instant::now(),
             bytecount:    0,
-            lockmap,
+            retransmits:  0,
+            lockmap:      shared.lockmap,
+            digests:      shared.digests,
+            storage:      shared.storage,
             locked:       Option::None,
+            backup:       Option::None,
             buf:          Some(Vec::new()),
+            option_order: Vec::new(),
+            is_tsize_requested: false,
+            tsize:        None,
+            offset:       None,
+            xcompress:    false,
+            transfers:    shared.transfers,
+            progress:     Arc::new(AtomicU64::new(0)),
+            window_memory: shared.window_memory,
+            window_reserved: 0,
         };
     }
 
     fn parsed_request(&mut self, data: &[u8]) -> Result<ParsedRequest> {
+        //checked before the options are parsed; a huge option blob is never looked at
+        if data.len() > self.settings.max_request_size {
+            tlog::warning!("{:?} request of {} bytes rejected; max-request-size={}", self.remote, data.len(), self.settings.max_request_size);
+            return Err(ErrorResponse::with_msg(ErrorNumber::IllegalOperation, "request too large".to_string()));
+        }
+
         let mut parser = PacketParser::new(&data);
 
-        let opcode = if let Some(opcode) = parser.opcode() {
-            opcode
-        } else {
-            return Err(ErrorResponse::new_custom("invalid opcode".to_string()));
+        //only RRQ and WRQ may open a connection
+        let opcode = match parser.opcode() {
+            Some(x @ (Opcode::Read | Opcode::Write)) => x,
+            //e.g. a client which lost its transfer and continues with ACK or DATA at the server port
+            Some(x) => return Err(ErrorResponse::with_msg(ErrorNumber::IllegalOperation, format!("unexpected {:?}; only RRQ or WRQ start a transfer", x))),
+            None => return Err(ErrorResponse::with_msg(ErrorNumber::IllegalOperation, "invalid opcode".to_string())),
         };
 
         let filename = if let Some(filename) = parser.string_with_separator() {
             filename
         } else {
-            return Err(ErrorResponse::new_custom("invalid filename".to_string()));
+            return Err(ErrorResponse::with_msg(ErrorNumber::IllegalOperation, "invalid filename".to_string()));
         };
 
         //TODO: make this more pretty which chaining
         //TODO: mode is currently ignored
 
-        let _mode = if let Ok(mode) = 
-            parser.string_with_separator()
-            .map_or(Err(()), |a| TransferMode::from_str(&a).to_owned())      
-        {
+        let mode = if let Some(mode) = parser.string_with_separator() {
             mode
         } else {
-            return Err(ErrorResponse::new_custom("invalid mode".to_string()));
+            return Err(ErrorResponse::with_msg(ErrorNumber::IllegalOperation, "invalid mode".to_string()));
         };
 
-        if let Ok(recv_map) = parser.extended_options() {
-            if let Ok((options,_other)) = filter_extended_options(&recv_map) {
-                self.settings.blocksize  = options.blksize    as usize;
-                self.settings.windowsize = options.windowsize as usize;
-            }
-            else {
-                tlog::warning!("{:?} recv extended options but format invalid", self.remote);
-            }
+        let _mode = TransferMode::from_str(&mode)?;
+
+        let ordered = parser.extended_options_ordered();
+        if let Ok(ordered) = &ordered {
+            self.option_order = ordered.iter().map(|(name, _)| name.clone()).collect();
         }
-        else {
-            tlog::warning!("{:?} recv extended options but format invalid", self.remote);
+
+        match ordered.and_then(|x| filter_extended_options(&x.into_iter().collect())) {
+            Ok((options,other)) => {
+                if self.settings.strict_options && !other.is_empty() {
+                    let mut names: Vec<&String> = other.keys().collect();
+                    names.sort();
+                    tlog::warning!("{:?} unknown options {:?} rejected; strict-options", self.remote, names);
+                    return Err(ErrorNumber::OptionNegotiationFailed.into());
+                }
+                self.settings.blocksize  = (options.blksize   as usize).clamp(MIN_BLOCKSIZE, MAX_BLOCKSIZE);
+                self.settings.windowsize = (options.windowsize as usize).clamp(1, self.settings.max_windowsize);
+                self.settings.rollover   = options.rollover;
+                self.settings.utimeout   = options.utimeout;
+                self.reserve_window_memory();
+
+                //a RRQ carries tsize=0; the size is known once the file is open
+                self.is_tsize_requested  = options.tsize.is_some();
+                self.tsize               = if opcode == Opcode::Write {options.tsize} else {None};
+                self.offset              = if opcode == Opcode::Read  {options.offset} else {None};
+                self.xcompress           = options.xcompress;
+            },
+            Err(err) => {
+                tlog::warning!("{:?} recv extended options but format invalid; {}", self.remote, err);
+                let msg = format!("{} {}", ErrorNumber::OptionNegotiationFailed.to_string(), err);
+                return Err(ErrorResponse::with_msg(ErrorNumber::OptionNegotiationFailed, msg));
+            }
         }
   
         return Ok(ParsedRequest {
@@ -279,37 +816,116 @@ impl Connection {
         });
     }
 
-    fn handle_extendes_request(&mut self) {
-        //send OACK
-        let mut builder = PacketBuilder::new(self.buf.as_mut().unwrap()).opcode(Opcode::Oack);
-        let mut is_oack = false;
+    //--max-total-window-memory: the windowsize is lowered to what the other connections left;
+    //a windowsize of 1 is always granted
+    fn reserve_window_memory(&mut self) {
+        self.release_window_memory();
+
+        let max = if let Some(x) = self.settings.max_total_window_memory {x} else {return;};
+        let blocksize = self.settings.blocksize;
+        let requested = self.settings.windowsize;
+        let fitting   = |total: usize| requested.min(max.saturating_sub(total) / blocksize).max(1);
+
+        let total = self.window_memory.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |total| Some(total + fitting(total) * blocksize)).unwrap();
+
+        self.settings.windowsize = fitting(total);
+        self.window_reserved     = self.settings.windowsize * blocksize;
+        if self.settings.windowsize < requested {
+            tlog::info!("{:?} windowsize {} lowered to {}; max-total-window-memory={} in use={}", self.remote, requested, self.settings.windowsize, max, total);
+        }
+    }
+
+    fn release_window_memory(&mut self) {
+        self.window_memory.fetch_sub(self.window_reserved, Ordering::SeqCst);
+        self.window_reserved = 0;
+    }
+
+    fn negotiated_options(&self) -> NegotiatedOptions {
+        return NegotiatedOptions {
+            blksize:    self.settings.blocksize,
+            windowsize: self.settings.windowsize,
+            rollover:   self.settings.rollover.unwrap_or(0),
+            tsize:      self.tsize,
+            timeout:    self.settings.utimeout,
+            offset:     self.offset,
+            xcompress:  self.xcompress,
+        };
+    }
+
+    //size of the download for the OACK; only if the client asked for it
+    fn set_download_size(&mut self, size: Option<u64>) {
+        if self.is_tsize_requested {
+            self.tsize = size;
+        }
+    }
+
+    //returns true if an OACK was sent
+    fn handle_extendes_request(&mut self) -> bool {
+        let mut acked: Vec<(&str, String)> = Vec::new();
 
         if self.settings.blocksize != DEFAULT_BLOCKSIZE {
-            builder = builder.str(BLKSIZE_STR).separator().str(&self.settings.blocksize.to_string()).separator();
-            is_oack = true;
+            acked.push((BLKSIZE_STR, self.settings.blocksize.to_string()));
         }
         if self.settings.windowsize != DEFAULT_WINDOWSIZE {
-            builder = builder.str(WINDOW_STR).separator().str(&self.settings.windowsize.to_string()).separator();
-            is_oack = true;
+            acked.push((WINDOW_STR, self.settings.windowsize.to_string()));
+        }
+        if let Some(rollover) = self.settings.rollover {
+            acked.push((ROLLOVER_STR, rollover.to_string()));
+        }
+        if let Some(utimeout) = self.settings.utimeout {
+            acked.push((UTIMEOUT_STR, utimeout.as_micros().to_string()));
+        }
+        if let Some(tsize) = self.tsize {
+            acked.push((TSIZE_STR, tsize.to_string()));
+        }
+        if let Some(offset) = self.offset {
+            acked.push((OFFSET_STR, offset.to_string()));
+        }
+        if self.xcompress {
+            acked.push((XCOMPRESS_STR, XCOMPRESS_DEFLATE.to_string()));
         }
 
-        let _ = builder;
+        if acked.is_empty() {
+            return false;
+        }
 
-        if !is_oack {
-            return;
+        //in request order; some strict clients expect the options echoed like that
+        acked.sort_by_key(|(name, _)| self.option_order.iter().position(|x| x == name));
+
+        //send OACK
+        let mut builder = PacketBuilder::new(self.buf.as_mut().unwrap()).opcode(Opcode::Oack);
+        for (name, value) in &acked {
+            builder = builder.str(name).separator().str(value).separator();
         }
+        let _ = builder;
 
         let buf = self.buf.take().unwrap();
         self.send_raw_release(buf);
+        return true;
     }
 
     pub fn run(&mut self)  {
-        let data   = &self.recv.recv_timeout(RECV_TIMEOUT).unwrap()[..];
+        let data = match self.recv.recv_timeout(RECV_TIMEOUT) {
+            Ok(x)                               => x,
+            Err(RecvTimeoutError::Timeout)      => {
+                tlog::warning!("{:?} no request received; connection dropped", self.remote);
+                return;
+            },
+            //dispatcher dropped the sender (e.g. shutdown); nothing is locked yet
+            Err(RecvTimeoutError::Disconnected) => {
+                tlog::info!("{:?} dispatcher channel closed; connection dropped", self.remote);
+                return;
+            },
+        };
+        self.trace_packet("recv", &data);
    
-        let request = match self.parsed_request(data) {
+        let request = match self.parsed_request(&data) {
             Ok(request) => request,
             Err(err) => {
-                tlog::error!("{:?} {}", self.remote, err.to_string());
+                tlog::error!("{:?} {}", self.remote, err);
+                if self.settings.dump_rejected {
+                    tlog::debug!("{:?} rejected packet len={}\n{}", self.remote, data.len(), hexdump(&data));
+                }
                 self.send_error(&err);
                 return;
             }
@@ -319,31 +935,519 @@ impl Connection {
         let filename = request.filename;
         tlog::info!("{:?} {:?} {}", self.remote, request.opcode, &filename);
 
-        self.handle_extendes_request();
+        tlog::info!("{:?} options {}", self.remote, self.negotiated_options());
+
+        lock_recover(&self.transfers, "transfer map").insert(self.remote, ActiveTransfer {
+            filename: filename.clone(),
+            opcode:   opcode,
+            bytes:    self.progress.clone(),
+            start:    self.start,
+        });
 
         let result = match opcode {
             Opcode::Read  => self.download(&filename),
             Opcode::Write => self.upload(&filename),
-            _             => return 
+            _             => return,    //refused by parsed_request
         };
 
         match result {
             Err(err) => {
-                tlog::error!("{:?} {}", self.remote, err.to_string());
+                tlog::error!("{:?} {}", self.remote, err);
                 self.send_error(&err);
             },
             _ => {},
         };
 
-        //cleanup locks
-        if let Some(ref locked) = self.locked.clone() {
+        //cleanup locks; only set when a lock was acquired
+        if let Some(locked) = self.locked.take() {
             self.unlock_file(&locked);
         }
 
         //statistics
         let runtime = self.start.elapsed().as_secs_f32();
         let mib_s      = ((self.bytecount as f32) / runtime) / 1000000.0;
-        tlog::info!("{:?} {:?} runtime = {}s; speed = {}MiB/s", self.remote, opcode, runtime, mib_s );
+        tlog::info!("{:?} {:?} runtime = {}s; speed = {}MiB/s; retransmits = {}", self.remote, opcode, runtime, mib_s, self.retransmits);
 
     }    
-}
\ No newline at end of file
+}
+
+//a panic or an early return of run must not leave the file locked for all other connections
+impl Drop for Connection {
+    fn drop(&mut self) {
+        lock_recover(&self.transfers, "transfer map").remove(&self.remote);
+        self.release_window_memory();
+
+        if let Some(locked) = self.locked.take() {
+            tlog::debug!("{:?} lock released on drop", self.remote);
+            self.unlock_file(&locked);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+    use std::sync::atomic::AtomicUsize;
+    use crate::server::queue;
+    use crate::server::storage::MemFs;
+
+    fn connection(write_mode: WriteMode, storage: &Arc<MemFs>, lockmap: &FileLockMap) -> Connection {
+        let (_sender, receiver) = queue::bounded(1);
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let remote = socket.local_addr().unwrap();
+
+        let settings = ServerSettings {
+            write_mode: write_mode,
+            ..ServerSettings::new("root")
+        };
+
+        let shared = SharedState { lockmap: lockmap.clone(), ..SharedState::new(storage.clone()) };
+        return Connection::new(receiver, remote, socket, settings, shared);
+    }
+
+    fn upload_error(conn: &mut Connection, filename: &str) -> Option<ErrorResponse> {
+        return conn.open_upload_file(filename).err();
+    }
+
+    #[test]
+    fn closed_channel_ends_connection() {
+        let storage = Arc::new(MemFs::new());
+        let lockmap: FileLockMap = Arc::new(Mutex::new(HashMap::new()));
+
+        //the helper drops the sender right away
+        let start = Instant::now();
+        connection(WriteMode::WriteOverwrite, &storage, &lockmap).run();
+        assert!(start.elapsed() < RECV_TIMEOUT);
+        assert!(lockmap.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn write_mode_enforced() {
+        let storage = Arc::new(MemFs::new());
+        let lockmap: FileLockMap = Arc::new(Mutex::new(HashMap::new()));
+        storage.insert(&Path::new("root").join("old.bin"), b"old");
+
+        let err = upload_error(&mut connection(WriteMode::Disabled, &storage, &lockmap), "new.bin").unwrap();
+        assert_eq!(err.number, ErrorNumber::AccessViolation);
+
+        let err = upload_error(&mut connection(WriteMode::WriteNew, &storage, &lockmap), "old.bin").unwrap();
+        assert_eq!(err.number, ErrorNumber::FileAlreadyExists);
+
+        let mut conn = connection(WriteMode::WriteNew, &storage, &lockmap);
+        conn.open_upload_file("new.bin").unwrap().write_all(b"new").unwrap();
+        assert_eq!(storage.get(&Path::new("root").join("new.bin")), Some(b"new".to_vec()));
+
+        let mut conn = connection(WriteMode::WriteOverwrite, &storage, &lockmap);
+        conn.open_upload_file("old.bin").unwrap().write_all(b"overwritten").unwrap();
+        assert_eq!(storage.get(&Path::new("root").join("old.bin")), Some(b"overwritten".to_vec()));
+    }
+
+    #[test]
+    fn overwrite_backup() {
+        let storage = Arc::new(MemFs::new());
+        let lockmap: FileLockMap = Arc::new(Mutex::new(HashMap::new()));
+        storage.insert(&Path::new("root").join("old.bin"), b"old");
+
+        let mut conn = connection(WriteMode::WriteOverwriteBackup, &storage, &lockmap);
+        conn.open_upload_file("old.bin").unwrap().write_all(b"new").unwrap();
+
+        assert_eq!(storage.get(&Path::new("root").join("old.bin")), Some(b"new".to_vec()));
+        assert_eq!(storage.get(&Path::new("root").join("old.bin.bak")), Some(b"old".to_vec()));
+
+        //an upload aborted by the client puts the previous version back;
+        //the socket of the test connection is its own remote
+        drop(conn);
+        let mut conn = connection(WriteMode::WriteOverwriteBackup, &storage, &lockmap);
+        conn.socket.send_to(b"\x00\x05\x00\x00cancel\x00", conn.remote).unwrap();
+        assert!(conn.upload("old.bin").is_err());
+
+        assert_eq!(storage.get(&Path::new("root").join("old.bin")), Some(b"new".to_vec()));
+        assert_eq!(storage.get(&Path::new("root").join("old.bin.bak")), None);
+    }
+
+    #[test]
+    fn write_lock_conflict() {
+        let storage = Arc::new(MemFs::new());
+        let lockmap: FileLockMap = Arc::new(Mutex::new(HashMap::new()));
+
+        let mut first = connection(WriteMode::WriteOverwrite, &storage, &lockmap);
+        let _file = first.open_upload_file("a.bin").unwrap();
+
+        let mut second = connection(WriteMode::WriteOverwrite, &storage, &lockmap);
+        assert!(upload_error(&mut second, "a.bin").is_some());
+        assert!(!second.check_lock_file(&Path::new("root").join("a.bin"), FileLockMode::Read(1)));
+
+        first.unlock_file(&Path::new("root").join("a.bin"));
+        assert!(upload_error(&mut second, "a.bin").is_none());
+    }
+
+    #[test]
+    fn read_locks_stack() {
+        let storage = Arc::new(MemFs::new());
+        let lockmap: FileLockMap = Arc::new(Mutex::new(HashMap::new()));
+        let path = Path::new("root").join("a.bin");
+        storage.insert(&path, b"data");
+
+        let mut first  = connection(WriteMode::WriteOverwrite, &storage, &lockmap);
+        let mut second = connection(WriteMode::WriteOverwrite, &storage, &lockmap);
+        let mut writer = connection(WriteMode::WriteOverwrite, &storage, &lockmap);
+        assert!(first.check_lock_file(&path, FileLockMode::Read(1)));
+        assert!(second.check_lock_file(&path, FileLockMode::Read(1)));
+
+        //the write stays blocked until the last reader is gone
+        assert_eq!(upload_error(&mut writer, "a.bin").unwrap().to_string(), "file is locked");
+        first.unlock_file(&path);
+        assert!(upload_error(&mut writer, "a.bin").is_some());
+        second.unlock_file(&path);
+        assert!(lockmap.lock().unwrap().is_empty());
+        assert!(upload_error(&mut writer, "a.bin").is_none());
+    }
+
+    #[test]
+    fn lock_released_on_drop() {
+        let storage = Arc::new(MemFs::new());
+        let lockmap: FileLockMap = Arc::new(Mutex::new(HashMap::new()));
+        let path = Path::new("root").join("a.bin");
+
+        let mut first  = connection(WriteMode::WriteOverwrite, &storage, &lockmap);
+        let mut second = connection(WriteMode::WriteOverwrite, &storage, &lockmap);
+        assert!(first.check_lock_file(&path, FileLockMode::Read(1)));
+        assert!(second.check_lock_file(&path, FileLockMode::Read(1)));
+
+        //already released like at the end of run; the drop must not take the lock of second
+        first.unlock_file(&path);
+        drop(first);
+        assert!(matches!(lockmap.lock().unwrap().get(&path), Some(FileLockMode::Read(1))));
+
+        //never released; e.g. a panic during the transfer
+        let panicked = std::thread::spawn(move || {
+            let _conn = second;
+            panic!("transfer failed");
+        }).join();
+        assert!(panicked.is_err());
+        assert!(lockmap.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn upload_traversal_rejected() {
+        let storage = Arc::new(MemFs::new());
+        let lockmap: FileLockMap = Arc::new(Mutex::new(HashMap::new()));
+
+        let err = upload_error(&mut connection(WriteMode::WriteOverwrite, &storage, &lockmap), "..\\escape.bin").unwrap();
+        assert_eq!(err.number, ErrorNumber::FileNotFound);
+        assert!(!storage.exists(Path::new("escape.bin")));
+    }
+
+    #[test]
+    fn upload_ext_allowed() {
+        let storage = Arc::new(MemFs::new());
+        let lockmap: FileLockMap = Arc::new(Mutex::new(HashMap::new()));
+        let mut conn = connection(WriteMode::WriteOverwrite, &storage, &lockmap);
+        conn.settings.allow_upload_ext = vec!["bin".to_string(), "img".to_string()];
+
+        for i_name in ["a.bin", "A.BIN", "dir/b.Img", "dir\\c.img"] {
+            assert!(upload_error(&mut conn, i_name).is_none(), "name={}", i_name);
+        }
+        for i_name in ["a.txt", "noext", "a.bin.txt", ".bin"] {
+            assert_eq!(upload_error(&mut conn, i_name).unwrap().number, ErrorNumber::AccessViolation, "name={}", i_name);
+            assert!(!storage.exists(&Path::new("root").join(i_name)));
+        }
+
+        conn.settings.allow_upload_ext.clear();
+        assert!(upload_error(&mut conn, "a.txt").is_none());
+    }
+
+    #[test]
+    fn upload_dir_only() {
+        let storage = Arc::new(MemFs::new());
+        let lockmap: FileLockMap = Arc::new(Mutex::new(HashMap::new()));
+        let mut conn = connection(WriteMode::WriteOverwrite, &storage, &lockmap);
+        conn.settings.upload_dir = Some(PathBuf::from("incoming"));
+
+        for i_name in ["incoming/a.bin", "./incoming/sub/c.bin", "incoming\\d.bin"] {
+            assert!(upload_error(&mut conn, i_name).is_none(), "name={}", i_name);
+        }
+        for i_name in ["firmware/a.bin", "incoming2/a.bin", "incoming", "a.bin"] {
+            assert_eq!(upload_error(&mut conn, i_name).unwrap().number, ErrorNumber::AccessViolation, "name={}", i_name);
+        }
+    }
+
+    #[test]
+    fn inflate_upload_limited() {
+        let storage = MemFs::new();
+        let path = Path::new("root").join("a.bin");
+        let data = vec![7u8; 1000];
+        let mut compressed = Vec::new();
+        DeflateEncoder::new(&data[..], Compression::default()).read_to_end(&mut compressed).unwrap();
+
+        let mut file = storage::inflate_into(storage.open_write(&path, None).unwrap(), Some(1000));
+        file.write_all(&compressed).unwrap();
+        file.finish().unwrap();
+        assert_eq!(storage.get(&path).unwrap(), data);
+
+        //the limit counts the inflated bytes
+        let mut file = storage::inflate_into(storage.open_write(&path, None).unwrap(), Some(999));
+        assert!(file.write_all(&compressed).and_then(|_| file.finish()).is_err());
+    }
+
+    #[test]
+    fn symlink_refused() {
+        let storage = Arc::new(MemFs::new());
+        let lockmap: FileLockMap = Arc::new(Mutex::new(HashMap::new()));
+        storage.insert(&Path::new("root").join("link.bin"), b"outside");
+        storage.insert(&Path::new("root").join("dir").join("a.bin"), b"outside");
+        storage.symlink(&Path::new("root").join("link.bin"));
+        storage.symlink(&Path::new("root").join("dir"));
+
+        let mut conn = connection(WriteMode::WriteOverwrite, &storage, &lockmap);
+        for i_name in ["link.bin", "dir/a.bin", "dir\\new.bin"] {
+            assert_eq!(conn.get_file_path(i_name).err().unwrap().number, ErrorNumber::AccessViolation, "name={}", i_name);
+        }
+        assert!(upload_error(&mut conn, "dir/new.bin").is_some());
+        assert!(conn.get_file_path("other.bin").is_ok());
+
+        conn.settings.follow_symlinks = true;
+        assert_eq!(conn.get_file_path("dir/a.bin").unwrap(), Path::new("root").join("dir").join("a.bin"));
+    }
+
+    #[test]
+    fn strict_options_rejects_unknown() {
+        let storage = Arc::new(MemFs::new());
+        let lockmap: FileLockMap = Arc::new(Mutex::new(HashMap::new()));
+        let request = b"\x00\x01a.bin\x00octet\x00blksize\x001024\x00timeout\x005\x00";
+
+        let mut conn = connection(WriteMode::WriteNew, &storage, &lockmap);
+        assert!(conn.parsed_request(request).is_ok());
+
+        let mut conn = connection(WriteMode::WriteNew, &storage, &lockmap);
+        conn.settings.strict_options = true;
+        assert_eq!(conn.parsed_request(request).err().unwrap().number, ErrorNumber::OptionNegotiationFailed);
+        assert!(conn.parsed_request(b"\x00\x01a.bin\x00octet\x00blksize\x001024\x00").is_ok());
+    }
+
+    #[test]
+    fn utimeout_negotiated() {
+        let storage = Arc::new(MemFs::new());
+        let lockmap: FileLockMap = Arc::new(Mutex::new(HashMap::new()));
+
+        let mut conn = connection(WriteMode::WriteNew, &storage, &lockmap);
+        assert!(conn.parsed_request(b"\x00\x01a.bin\x00octet\x00utimeout\x0050000\x00").is_ok());
+        assert_eq!(conn.negotiated_options().timeout, Some(Duration::from_millis(50)));
+
+        //the socket of the test connection is its own remote
+        assert!(conn.handle_extendes_request());
+        let mut buf = vec![0; 64];
+        let (len, _) = conn.socket.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"\x00\x06utimeout\x0050000\x00");
+
+        let mut conn = connection(WriteMode::WriteNew, &storage, &lockmap);
+        let err = conn.parsed_request(b"\x00\x01a.bin\x00octet\x00utimeout\x00300000000\x00").err().unwrap();
+        assert_eq!(err.number, ErrorNumber::OptionNegotiationFailed);
+    }
+
+    #[test]
+    fn window_memory_capped() {
+        let storage = Arc::new(MemFs::new());
+        let lockmap: FileLockMap = Arc::new(Mutex::new(HashMap::new()));
+        let window_memory: WindowMemory = Arc::new(AtomicUsize::new(0));
+        let request = b"\x00\x01a.bin\x00octet\x00blksize\x001024\x00windowsize\x008\x00";
+
+        let mut connections = Vec::new();
+        for _ in 0..3 {
+            let mut conn = connection(WriteMode::WriteNew, &storage, &lockmap);
+            conn.window_memory = window_memory.clone();
+            conn.settings.max_total_window_memory = Some(10 * 1024);
+            assert!(conn.parsed_request(request).is_ok());
+            connections.push(conn);
+        }
+
+        //the last one is over the cap but still gets a window of 1
+        let windowsizes: Vec<usize> = connections.iter().map(|x| x.negotiated_options().windowsize).collect();
+        assert_eq!(windowsizes, vec![8, 2, 1]);
+        assert_eq!(window_memory.load(Ordering::SeqCst), 11 * 1024);
+
+        connections.remove(0);
+        assert_eq!(window_memory.load(Ordering::SeqCst), 3 * 1024);
+
+        let mut conn = connection(WriteMode::WriteNew, &storage, &lockmap);
+        conn.window_memory = window_memory.clone();
+        conn.settings.max_total_window_memory = Some(10 * 1024);
+        assert!(conn.parsed_request(request).is_ok());
+        assert_eq!(conn.negotiated_options().windowsize, 7);
+
+        drop(conn);
+        connections.clear();
+        assert_eq!(window_memory.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn tsize_only_if_known() {
+        let storage = Arc::new(MemFs::new());
+        let lockmap: FileLockMap = Arc::new(Mutex::new(HashMap::new()));
+
+        //a RRQ gets the size of the file instead of the 0 of the request
+        let mut conn = connection(WriteMode::WriteNew, &storage, &lockmap);
+        assert!(conn.parsed_request(b"\x00\x01a.bin\x00octet\x00tsize\x000\x00").is_ok());
+        assert!(!conn.handle_extendes_request());
+        conn.set_download_size(Some(1234));
+        assert!(conn.handle_extendes_request());
+        let mut buf = vec![0; 64];
+        let (len, _) = conn.socket.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"\x00\x06tsize\x001234\x00");
+
+        //the request form with an empty value
+        let mut conn = connection(WriteMode::WriteNew, &storage, &lockmap);
+        assert!(conn.parsed_request(b"\x00\x01a.bin\x00octet\x00tsize\x00\x00").is_ok());
+        conn.set_download_size(Some(1234));
+        assert!(conn.handle_extendes_request());
+        let (len, _) = conn.socket.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"\x00\x06tsize\x001234\x00");
+
+        //unknown size e.g. a fifo; left out
+        let mut conn = connection(WriteMode::WriteNew, &storage, &lockmap);
+        assert!(conn.parsed_request(b"\x00\x01a.bin\x00octet\x00tsize\x000\x00").is_ok());
+        conn.set_download_size(None);
+        assert!(!conn.handle_extendes_request());
+
+        //not requested; never sent
+        let mut conn = connection(WriteMode::WriteNew, &storage, &lockmap);
+        assert!(conn.parsed_request(b"\x00\x01a.bin\x00octet\x00").is_ok());
+        conn.set_download_size(Some(1234));
+        assert!(!conn.handle_extendes_request());
+
+        //a WRQ is echoed; too big for max-upload-size is rejected before the file is created
+        let mut conn = connection(WriteMode::WriteNew, &storage, &lockmap);
+        conn.settings.max_upload_size = Some(1000);
+        assert!(conn.parsed_request(b"\x00\x02a.bin\x00octet\x00tsize\x001234\x00").is_ok());
+        assert_eq!(conn.negotiated_options().tsize, Some(1234));
+        assert_eq!(conn.upload("a.bin").err().unwrap().number, ErrorNumber::DiskFull);
+        assert!(!storage.exists(&Path::new("root").join("a.bin")));
+    }
+
+    #[test]
+    fn oack_in_request_order() {
+        let storage = Arc::new(MemFs::new());
+        let lockmap: FileLockMap = Arc::new(Mutex::new(HashMap::new()));
+        let requests: [(&[u8], &[u8]); 2] = [
+            (b"\x00\x01a.bin\x00octet\x00windowsize\x004\x00tsize\x000\x00blksize\x001024\x00", b"\x00\x06windowsize\x004\x00blksize\x001024\x00"),
+            (b"\x00\x01a.bin\x00octet\x00blksize\x001024\x00utimeout\x0050000\x00windowsize\x004\x00", b"\x00\x06blksize\x001024\x00utimeout\x0050000\x00windowsize\x004\x00"),
+        ];
+
+        for (request, oack) in requests {
+            let mut conn = connection(WriteMode::WriteNew, &storage, &lockmap);
+            assert!(conn.parsed_request(request).is_ok());
+            assert!(conn.handle_extendes_request());
+
+            //the socket of the test connection is its own remote
+            let mut buf = vec![0; 128];
+            let (len, _) = conn.socket.recv_from(&mut buf).unwrap();
+            assert_eq!(&buf[..len], oack);
+        }
+    }
+
+    #[test]
+    fn oversized_request_rejected() {
+        let storage = Arc::new(MemFs::new());
+        let lockmap: FileLockMap = Arc::new(Mutex::new(HashMap::new()));
+
+        //60KB of options; each one is valid on its own
+        let mut request = b"\x00\x01a.bin\x00octet\x00".to_vec();
+        for i in 0..4500 {
+            request.extend_from_slice(format!("x{:05}\x00{:06}\x00", i, i).as_bytes());
+        }
+        assert!(request.len() > 60000);
+
+        let mut conn = connection(WriteMode::WriteNew, &storage, &lockmap);
+        let err = conn.parsed_request(&request).err().unwrap();
+        assert_eq!(err.number, ErrorNumber::IllegalOperation);
+        assert!(conn.option_order.is_empty());
+
+        let mut conn = connection(WriteMode::WriteNew, &storage, &lockmap);
+        conn.settings.max_request_size = request.len();
+        assert!(conn.parsed_request(&request).is_ok());
+    }
+
+    #[test]
+    fn ack_as_first_packet() {
+        let storage = Arc::new(MemFs::new());
+        let lockmap: FileLockMap = Arc::new(Mutex::new(HashMap::new()));
+        let mut conn = connection(WriteMode::WriteNew, &storage, &lockmap);
+
+        let (sender, receiver) = queue::bounded(1);
+        conn.recv = receiver;
+        sender.send(b"\x00\x04\x00\x01".to_vec());
+        conn.run();
+
+        //the error goes back to the own socket of the helper
+        let mut buf = vec![0; 128];
+        conn.socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let (len, _) = conn.socket.recv_from(&mut buf).unwrap();
+        let err = PacketParser::new(&buf[..len]).parse_error().unwrap();
+        assert_eq!(err.number, ErrorNumber::IllegalOperation);
+        let msg = String::from_utf8_lossy(&buf[4..len]);
+        assert!(msg.contains("unexpected Ack"), "{}", msg);
+    }
+
+    #[test]
+    fn garbage_request_rejected() {
+        let storage = Arc::new(MemFs::new());
+        let lockmap: FileLockMap = Arc::new(Mutex::new(HashMap::new()));
+        let mut conn = connection(WriteMode::WriteNew, &storage, &lockmap);
+
+        for i_packet in [&b""[..], b"\x00", b"\x00\x04\x00\x01", b"\x00\x01a.bin"] {
+            let err = conn.parsed_request(i_packet).err().unwrap();
+            assert_eq!(err.number, ErrorNumber::IllegalOperation, "packet={:?}", i_packet);
+        }
+    }
+
+    #[test]
+    fn invalid_option_rejected() {
+        let storage = Arc::new(MemFs::new());
+        let lockmap: FileLockMap = Arc::new(Mutex::new(HashMap::new()));
+
+        let mut conn = connection(WriteMode::WriteNew, &storage, &lockmap);
+        let err = conn.parsed_request(b"\x00\x01a.bin\x00octet\x00blksize\x00big\x00").err().unwrap();
+        assert_eq!(err.number, ErrorNumber::OptionNegotiationFailed);
+    }
+
+    #[test]
+    fn isolate_by_ip() {
+        let storage = Arc::new(MemFs::new());
+        let lockmap: FileLockMap = Arc::new(Mutex::new(HashMap::new()));
+        storage.insert(&Path::new("root").join("shared.bin"), b"shared");
+
+        let mut conn = connection(WriteMode::WriteNew, &storage, &lockmap);
+        conn.settings.isolate_by_ip = true;
+        conn.remote = "10.0.0.5:1234".parse().unwrap();
+
+        conn.open_upload_file("a.bin").unwrap().write_all(b"a").unwrap();
+        assert_eq!(storage.get(&Path::new("root").join("10.0.0.5").join("a.bin")), Some(b"a".to_vec()));
+
+        //files of the root and of other clients are out of reach
+        assert_eq!(conn.get_file_path("shared.bin").unwrap(), Path::new("root").join("10.0.0.5").join("shared.bin"));
+        assert!(conn.get_file_path("../10.0.0.6/b.bin").is_err());
+        assert!(conn.get_file_path("..\\shared.bin").is_err());
+
+        assert_eq!(client_dir_name("::ffff:10.0.0.5".parse().unwrap()), "10.0.0.5");
+        assert_eq!(client_dir_name("fe80::1".parse().unwrap()), "fe80__1");
+    }
+
+    #[test]
+    fn resolve_path_separators() {
+        let expected = Path::new("root").join("subdir").join("file.bin");
+
+        assert_eq!(resolve_path(Path::new("root"), "subdir/file.bin").unwrap(), expected);
+        assert_eq!(resolve_path(Path::new("root"), "subdir\\file.bin").unwrap(), expected);
+    }
+
+    #[test]
+    fn resolve_path_traversal() {
+        assert!(resolve_path(Path::new("root"), "../file.bin").is_err());
+        assert!(resolve_path(Path::new("root"), "..\\file.bin").is_err());
+        assert!(resolve_path(Path::new("root"), "subdir\\..\\..\\file.bin").is_err());
+        assert!(resolve_path(Path::new("root"), "/etc/passwd").is_err());
+        assert!(resolve_path(Path::new("root"), "\\etc\\passwd").is_err());
+    }
+}
@@ -0,0 +1,57 @@
+use std::{fs::{File, OpenOptions}, io::Write, path::Path};
+
+//detach from the terminal: fork twice with setsid in between, write the pid file and
+//point stdin to /dev/null and stdout/stderr, which carry the tlog output, to the log file.
+//the working directory is kept; relative paths of e.g --on-upload stay valid.
+//has to be called before any thread is started
+#[cfg(unix)]
+pub fn daemonize(pid_file: Option<&Path>, log_file: Option<&Path>) -> Result<(), String> {
+    use std::os::unix::io::AsRawFd;
+
+    //opened before the fork so errors are still seen on the terminal
+    let log = match log_file {
+        Some(path) => OpenOptions::new().create(true).append(true).open(path),
+        None       => OpenOptions::new().write(true).open("/dev/null"),
+    }.map_err(|err| format!("open log file {:?} failed; {}", log_file, err))?;
+    let null = File::open("/dev/null").map_err(|err| format!("open /dev/null failed; {}", err))?;
+    let mut pid = match pid_file {
+        Some(path) => Some(File::create(path).map_err(|err| format!("create pid file {:?} failed; {}", path, err))?),
+        None       => None,
+    };
+
+    unsafe {
+        match libc::fork() {
+            -1 => return Err(format!("fork failed; {}", std::io::Error::last_os_error())),
+            0  => {},
+            _  => libc::_exit(0),
+        }
+        if libc::setsid() == -1 {
+            return Err(format!("setsid failed; {}", std::io::Error::last_os_error()));
+        }
+        //not a session leader anymore; can't acquire a controlling terminal again
+        match libc::fork() {
+            -1 => return Err(format!("fork failed; {}", std::io::Error::last_os_error())),
+            0  => {},
+            _  => libc::_exit(0),
+        }
+    }
+
+    if let Some(file) = pid.as_mut() {
+        writeln!(file, "{}", std::process::id()).map_err(|err| format!("write pid file failed; {}", err))?;
+    }
+
+    unsafe {
+        if libc::dup2(null.as_raw_fd(), libc::STDIN_FILENO) == -1
+            || libc::dup2(log.as_raw_fd(), libc::STDOUT_FILENO) == -1
+            || libc::dup2(log.as_raw_fd(), libc::STDERR_FILENO) == -1 {
+            return Err(format!("redirect of stdio failed; {}", std::io::Error::last_os_error()));
+        }
+    }
+
+    return Ok(());
+}
+
+#[cfg(not(unix))]
+pub fn daemonize(_pid_file: Option<&Path>, _log_file: Option<&Path>) -> Result<(), String> {
+    return Err("--daemonize is only supported on unix; use a service manager instead".to_string());
+}
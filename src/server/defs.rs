@@ -1,12 +1,16 @@
-use std::{sync::{mpsc::Sender, Arc, Mutex}, thread::JoinHandle, collections::{HashMap}, path::PathBuf, time::Duration};
+use crate::{server::{queue::QueueSender, storage::Storage, transfers::TransferMap}, tftp_protocol, tlog};
+
+use std::{sync::{atomic::AtomicUsize, Arc, Mutex, MutexGuard}, thread::JoinHandle, collections::{HashMap}, path::PathBuf, time::{Duration, SystemTime}, net::{IpAddr, Ipv4Addr}};
 
 pub const CLEANUP_TIMEOUT: Duration = Duration::from_secs(3);
+pub const LOCK_RETRY_INTERVAL: Duration = Duration::from_millis(20);
 
 #[derive(Clone,PartialEq)]
 pub enum WriteMode {
     Disabled,
     WriteNew,
     WriteOverwrite,
+    WriteOverwriteBackup,   //the existing file is renamed to <name>.bak
 }
 
 #[derive(Clone)]
@@ -15,16 +19,100 @@ pub struct ServerSettings {
     pub root_dir:         String,
     pub blocksize:        usize,
     pub windowsize:       usize,
+    pub max_windowsize:   usize,
+    pub rollover:         Option<u16>,
+    pub utimeout:         Option<Duration>,
     pub verbose:          bool,
+    pub trace:            bool,
     pub exit_with_client: bool,
     pub port:             u16,
+    pub bind:             IpAddr,
+    pub manifest:         bool,
+    pub manifest_name:    String,
+    pub max_upload_size:  Option<u64>,
+    pub expose_caps:      bool,
+    pub checksum_manifest: Option<String>,
+    pub auto_decompress:  bool,             //serve <file> from <file>.gz if only the latter exists
+    pub recv_buffer:      Option<usize>,
+    pub send_buffer:      Option<usize>,
+    pub lock_wait:        Duration,
+    pub file_mode:        Option<u32>,
+    pub on_upload:        Option<String>,
+    pub backoff:          tftp_protocol::BackoffStrategy,
+    pub inject_delay:     Option<Duration>,
+    pub strict_options:   bool,
+    pub isolate_by_ip:    bool,
+    pub dump_rejected:    bool,
+    pub interleave_acks:  bool,
+    pub batch_sends:      bool,
+    pub rate_limit_per_ip: Option<usize>,
+    pub health_port:      Option<u16>,
+    pub allow_special_files: bool,
+    pub fsync:            bool,             //sync an upload to disk before the final ack
+    pub max_request_size: usize,
+    pub stats_interval:   Option<Duration>,
+    pub follow_symlinks:  bool,
+    pub connection_queue_depth: usize,
+    pub allow_upload_ext: Vec<String>,      //lowercase without the dot; empty allows all
+    pub max_total_window_memory: Option<usize>,
+    pub upload_dir:       Option<PathBuf>,  //relative to the root; without '.', '..' and a leading '/'
+}
+
+impl ServerSettings {
+    //defaults of the command line; blocksize, windowsize, rollover and utimeout are changed by the request
+    pub fn new(root_dir: &str) -> ServerSettings {
+        ServerSettings {
+            write_mode:        WriteMode::WriteNew,
+            root_dir:          root_dir.to_string(),
+            blocksize:         tftp_protocol::DEFAULT_BLOCKSIZE,
+            windowsize:        tftp_protocol::DEFAULT_WINDOWSIZE,
+            max_windowsize:    16,
+            rollover:          None,
+            utimeout:          None,
+            verbose:           true,
+            trace:             false,
+            exit_with_client:  false,
+            port:              69,
+            bind:              IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            manifest:          false,
+            manifest_name:     tftp_protocol::MANIFEST_NAME.to_string(),
+            max_upload_size:   None,
+            expose_caps:       false,
+            checksum_manifest: None,
+            auto_decompress:   false,
+            recv_buffer:       None,
+            send_buffer:       None,
+            lock_wait:         Duration::ZERO,
+            file_mode:         None,
+            on_upload:         None,
+            backoff:           tftp_protocol::BackoffStrategy::Fixed,
+            inject_delay:      None,
+            strict_options:    false,
+            isolate_by_ip:     false,
+            dump_rejected:     false,
+            interleave_acks:   false,
+            batch_sends:       false,
+            rate_limit_per_ip: None,
+            health_port:       None,
+            allow_special_files: false,
+            fsync:             false,
+            max_request_size:  2048,
+            stats_interval:    None,
+            follow_symlinks:   false,
+            connection_queue_depth: 16,
+            allow_upload_ext:  Vec::new(),
+            max_total_window_memory: None,
+            upload_dir:        None,
+        }
+    }
 }
 
 pub struct ClientState {
-    pub tx: Sender<Vec<u8>>,
+    pub tx: QueueSender,
     pub join_handle: Option<JoinHandle<()>>,
 }
 
+#[derive(Clone,Copy)]
 pub enum FileLockMode {
     Read(usize),
     Write,
@@ -32,3 +120,70 @@ pub enum FileLockMode {
 
 pub type FileLockMap = Arc<Mutex<HashMap<PathBuf,FileLockMode>>>;
 
+pub struct FileDigest {
+    pub modified: SystemTime,
+    pub len:      u64,
+    pub hex:      String,
+}
+
+pub type DigestCache = Arc<Mutex<HashMap<PathBuf,FileDigest>>>;
+
+//windowsize * blocksize summed over all connections; see --max-total-window-memory
+pub type WindowMemory = Arc<AtomicUsize>;
+
+//handles shared by all connections of a server; each connection gets a clone
+#[derive(Clone)]
+pub struct SharedState {
+    pub lockmap:       FileLockMap,
+    pub digests:       DigestCache,
+    pub storage:       Arc<dyn Storage>,
+    pub transfers:     TransferMap,
+    pub window_memory: WindowMemory,
+}
+
+impl SharedState {
+    pub fn new(storage: Arc<dyn Storage>) -> SharedState {
+        return SharedState {
+            lockmap:       Arc::new(Mutex::new(HashMap::new())),
+            digests:       Arc::new(Mutex::new(HashMap::new())),
+            storage:       storage,
+            transfers:     Arc::new(Mutex::new(HashMap::new())),
+            window_memory: Arc::new(AtomicUsize::new(0)),
+        };
+    }
+}
+
+
+//a connection which panicked while holding the mutex must not take down all other connections;
+//the maps stay consistent because every update is a single insert or remove
+pub fn lock_recover<'a, T>(mutex: &'a Mutex<T>, name: &str) -> MutexGuard<'a, T> {
+    return match mutex.lock() {
+        Ok(x)    => x,
+        Err(err) => {
+            tlog::warning!("{} was poisoned by a panicked connection; recovered", name);
+            mutex.clear_poison();
+            err.into_inner()
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lock_recover_poisoned() {
+        let lockmap: FileLockMap = Arc::new(Mutex::new(HashMap::new()));
+
+        let poisoner = lockmap.clone();
+        let _ = std::thread::spawn(move || {
+            let _guard = poisoner.lock().unwrap();
+            panic!("connection panicked");
+        }).join();
+        assert!(lockmap.is_poisoned());
+
+        lock_recover(&lockmap, "lock map").insert(PathBuf::from("a"), FileLockMode::Write);
+        assert!(!lockmap.is_poisoned());
+        assert_eq!(lock_recover(&lockmap, "lock map").len(), 1);
+    }
+}
@@ -0,0 +1,44 @@
+use std::{io, path::Path};
+
+use crate::checksum::Checksum;
+
+use super::defs::{DigestCache, FileDigest, lock_recover};
+use super::storage::{Storage, FileInfo};
+
+//a cached digest is valid as long as size and modification time of the file are unchanged
+fn is_current(digest: &FileDigest, meta: &FileInfo) -> bool {
+    return digest.len == meta.len && meta.modified == Some(digest.modified);
+}
+
+pub fn lookup(cache: &DigestCache, path: &Path, meta: &FileInfo) -> Option<String> {
+    let cache = lock_recover(cache, "digest cache");
+    return cache.get(path).filter(|x| is_current(x, meta)).map(|x| x.hex.clone());
+}
+
+pub fn store(cache: &DigestCache, path: &Path, meta: &FileInfo, hex: String) {
+    let modified = if let Some(x) = meta.modified {x} else {return;};
+
+    let mut cache = lock_recover(cache, "digest cache");
+    cache.insert(path.to_path_buf(), FileDigest { modified: modified, len: meta.len, hex: hex });
+}
+
+//digest of the file; read from the cache or computed and cached
+pub fn get(cache: &DigestCache, storage: &dyn Storage, algorithm: &str, path: &Path) -> io::Result<String> {
+    let meta = storage.metadata(path)?;
+    if let Some(hex) = lookup(cache, path, &meta) {
+        return Ok(hex);
+    }
+
+    let mut checksum = Checksum::from_name(algorithm).unwrap();
+    let mut file = storage.open_read(path, 0)?;
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let len = io::Read::read(&mut file, &mut buf)?;
+        if len == 0 { break; }
+        checksum.update(&buf[0..len]);
+    }
+
+    let hex = checksum.finalize_hex();
+    store(cache, path, &meta, hex.clone());
+    return Ok(hex);
+}
@@ -0,0 +1,55 @@
+use std::{io::{Read, Write}, net::{SocketAddr, TcpListener, TcpStream}, sync::{Arc, Mutex}, thread, time::{Duration, Instant}};
+
+use crate::tlog;
+
+use super::defs::lock_recover;
+
+//the dispatcher loop wakes up at least once per second; a longer silence means it hangs
+const STALE_AFTER: Duration = Duration::from_secs(5);
+
+//updated by the dispatcher on every loop iteration
+#[derive(Clone)]
+pub struct Heartbeat(Arc<Mutex<Instant>>);
+
+impl Heartbeat {
+    pub fn new() -> Heartbeat {
+        return Heartbeat(Arc::new(Mutex::new(Instant::now())));
+    }
+
+    pub fn beat(&self) {
+        *lock_recover(&self.0, "heartbeat") = Instant::now();
+    }
+
+    fn is_alive(&self) -> bool {
+        return lock_recover(&self.0, "heartbeat").elapsed() < STALE_AFTER;
+    }
+}
+
+//--health-port: answers any tcp request with 200 while the dispatcher accepts requests and 503 otherwise.
+//the request itself is not parsed; enough for http probes of e.g kubernetes
+pub fn spawn(addr: SocketAddr, heartbeat: Heartbeat) -> std::io::Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    tlog::info!("health check listening on {}", addr);
+
+    thread::spawn(move || {
+        for i_stream in listener.incoming() {
+            match i_stream {
+                Ok(stream) => answer(stream, heartbeat.is_alive()),
+                Err(err)   => tlog::warning!("health check accept failed; {}", err),
+            }
+        }
+    });
+
+    return Ok(());
+}
+
+fn answer(mut stream: TcpStream, is_alive: bool) {
+    //consume what the probe sent; a peer which sends nothing gets the answer after the timeout
+    let _ = stream.set_read_timeout(Some(Duration::from_millis(200)));
+    let mut buf = [0u8; 1024];
+    let _ = stream.read(&mut buf);
+
+    let (status, body) = if is_alive {("200 OK", "ok\n")} else {("503 Service Unavailable", "stalled\n")};
+    let response = format!("HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", status, body.len(), body);
+    let _ = stream.write_all(response.as_bytes());
+}
@@ -0,0 +1,58 @@
+use std::{io, path::Path};
+
+use crate::server::storage::Storage;
+
+//generate the content of a directory manifest; the format is the same the
+//client expects for --download-dir: "<relative path>\t<size>\n" with '/' as separator
+pub fn generate(storage: &dyn Storage, dir: &Path) -> io::Result<Vec<u8>> {
+    let mut entries: Vec<(String, u64)> = Vec::new();
+    collect(storage, dir, "", &mut entries)?;
+    entries.sort();
+
+    let mut out = Vec::new();
+    for (path, size) in entries {
+        out.extend_from_slice(format!("{}\t{}\n", path, size).as_bytes());
+    }
+
+    return Ok(out);
+}
+
+fn collect(storage: &dyn Storage, dir: &Path, prefix: &str, entries: &mut Vec<(String, u64)>) -> io::Result<()> {
+    for i_path in storage.read_dir(dir)? {
+        //non utf8 names cannot be requested by the client anyway
+        let name = if let Some(x) = i_path.file_name().and_then(|x| x.to_str()) {x.to_string()} else {continue;};
+        let relative = format!("{}{}", prefix, name);
+
+        //symlinks are skipped so the manifest never leaves the directory
+        if storage.is_symlink(&i_path) {
+            continue;
+        }
+
+        let info = storage.metadata(&i_path)?;
+        if info.is_dir {
+            collect(storage, &i_path, &format!("{}/", relative), entries)?;
+        } else if !info.is_special {
+            entries.push((relative, info.len));
+        }
+    }
+
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::storage::MemFs;
+
+    #[test]
+    fn generate_from_storage() {
+        let storage = MemFs::new();
+        storage.insert(&Path::new("root").join("a.bin"), b"abc");
+        storage.insert(&Path::new("root").join("sub").join("b.bin"), b"b");
+        storage.insert(&Path::new("root").join("link").join("c.bin"), b"c");
+        storage.symlink(&Path::new("root").join("link"));
+
+        let manifest = generate(&storage, Path::new("root")).unwrap();
+        assert_eq!(String::from_utf8(manifest).unwrap(), "a.bin\t3\nsub/b.bin\t1\n");
+    }
+}
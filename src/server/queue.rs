@@ -0,0 +1,109 @@
+use std::{collections::VecDeque, sync::{mpsc::RecvTimeoutError, Arc, Condvar, Mutex}, time::{Duration, Instant}};
+
+use crate::server::defs::lock_recover;
+
+//datagrams of the dispatcher for one connection; bounded by --connection-queue-depth.
+//a full queue drops its oldest datagram; TFTP recovers from the loss with a retransmit
+struct Queue {
+    packets:     VecDeque<Vec<u8>>,
+    depth:       usize,
+    is_closed:   bool,              //the sender is gone
+}
+
+pub struct QueueSender {
+    shared: Arc<(Mutex<Queue>, Condvar)>,
+}
+
+pub struct QueueReceiver {
+    shared: Arc<(Mutex<Queue>, Condvar)>,
+}
+
+pub fn bounded(depth: usize) -> (QueueSender, QueueReceiver) {
+    let shared = Arc::new((Mutex::new(Queue { packets: VecDeque::new(), depth: depth.max(1), is_closed: false }), Condvar::new()));
+    return (QueueSender { shared: shared.clone() }, QueueReceiver { shared: shared });
+}
+
+impl QueueSender {
+    //returns the number of dropped datagrams; 0 or 1
+    pub fn send(&self, packet: Vec<u8>) -> usize {
+        let (queue, cond) = &*self.shared;
+        let mut queue = lock_recover(queue, "connection queue");
+
+        let mut dropped = 0;
+        if queue.packets.len() >= queue.depth {
+            queue.packets.pop_front();
+            dropped = 1;
+        }
+        queue.packets.push_back(packet);
+        cond.notify_one();
+
+        return dropped;
+    }
+}
+
+impl Drop for QueueSender {
+    fn drop(&mut self) {
+        let (queue, cond) = &*self.shared;
+        lock_recover(queue, "connection queue").is_closed = true;
+        cond.notify_all();
+    }
+}
+
+impl QueueReceiver {
+    //like mpsc::Receiver::recv_timeout; queued datagrams are returned even after the sender is gone
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<Vec<u8>, RecvTimeoutError> {
+        let deadline = Instant::now() + timeout;
+        let (queue, cond) = &*self.shared;
+        let mut queue = lock_recover(queue, "connection queue");
+
+        loop {
+            if let Some(packet) = queue.packets.pop_front() {
+                return Ok(packet);
+            }
+            if queue.is_closed {
+                return Err(RecvTimeoutError::Disconnected);
+            }
+
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(RecvTimeoutError::Timeout);
+            }
+            queue = match cond.wait_timeout(queue, remaining) {
+                Ok((x, _))  => x,
+                Err(err)    => err.into_inner().0,
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn full_queue_drops_oldest() {
+        let (sender, receiver) = bounded(2);
+
+        assert_eq!(sender.send(vec![1]), 0);
+        assert_eq!(sender.send(vec![2]), 0);
+        assert_eq!(sender.send(vec![3]), 1);
+        drop(sender);
+
+        assert_eq!(receiver.recv_timeout(Duration::ZERO), Ok(vec![2]));
+        assert_eq!(receiver.recv_timeout(Duration::ZERO), Ok(vec![3]));
+        assert_eq!(receiver.recv_timeout(Duration::ZERO), Err(RecvTimeoutError::Disconnected));
+    }
+
+    #[test]
+    fn recv_waits_for_sender() {
+        let (sender, receiver) = bounded(1);
+        assert_eq!(receiver.recv_timeout(Duration::from_millis(10)), Err(RecvTimeoutError::Timeout));
+
+        let handle = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            sender.send(vec![1]);
+        });
+        assert_eq!(receiver.recv_timeout(Duration::from_secs(5)), Ok(vec![1]));
+        handle.join().unwrap();
+    }
+}
@@ -0,0 +1,93 @@
+use std::{collections::{HashMap, VecDeque}, net::IpAddr, time::{Duration, Instant}};
+
+use crate::tlog;
+
+//requests of --rate-limit-per-ip are counted over this sliding window
+pub const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(1);
+
+struct IpRequests {
+    accepted:   VecDeque<Instant>,
+    is_limited: bool,               //only the first dropped request is logged
+}
+
+//limits the new connections per source ip; checked by the dispatcher before a Connection is created
+pub struct RateLimiter {
+    limit:      usize,
+    window:     Duration,
+    requests:   HashMap<IpAddr, IpRequests>,
+    last_purge: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(limit: usize, window: Duration) -> RateLimiter {
+        RateLimiter {
+            limit:      limit,
+            window:     window,
+            requests:   HashMap::new(),
+            last_purge: Instant::now(),
+        }
+    }
+
+    //true if the request is within the limit; only accepted requests are counted
+    pub fn allow(&mut self, ip: IpAddr, now: Instant) -> bool {
+        self.purge(now);
+
+        let window  = self.window;
+        let entry   = self.requests.entry(ip).or_insert_with(|| IpRequests { accepted: VecDeque::new(), is_limited: false });
+        while entry.accepted.front().map_or(false, |x| now.duration_since(*x) >= window) {
+            entry.accepted.pop_front();
+        }
+
+        if entry.accepted.len() >= self.limit {
+            if !entry.is_limited {
+                tlog::warning!("{} exceeds {} requests per {:?}; further requests are dropped", ip, self.limit, window);
+            }
+            entry.is_limited = true;
+            return false;
+        }
+
+        entry.is_limited = false;
+        entry.accepted.push_back(now);
+        return true;
+    }
+
+    //forget ips without requests in the window; a flood of spoofed sources must not grow the map forever
+    fn purge(&mut self, now: Instant) {
+        if now.duration_since(self.last_purge) < self.window {
+            return;
+        }
+
+        let window = self.window;
+        self.requests.retain(|_, x| x.accepted.back().map_or(false, |last| now.duration_since(*last) < window));
+        self.last_purge = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sliding_window_per_ip() {
+        let start   = Instant::now();
+        let flooder = IpAddr::from([10, 0, 0, 1]);
+        let other   = IpAddr::from([10, 0, 0, 2]);
+        let mut limiter = RateLimiter::new(3, Duration::from_secs(1));
+
+        for _ in 0..3 {
+            assert!(limiter.allow(flooder, start));
+        }
+        assert!(!limiter.allow(flooder, start + Duration::from_millis(500)));
+        assert!(limiter.allow(other, start + Duration::from_millis(500)));
+
+        //the first requests left the window; the dropped one doesn't count
+        for _ in 0..3 {
+            assert!(limiter.allow(flooder, start + Duration::from_millis(1000)));
+        }
+        assert!(!limiter.allow(flooder, start + Duration::from_millis(1000)));
+
+        //idle ips are forgotten
+        limiter.allow(other, start + Duration::from_secs(5));
+        assert_eq!(limiter.requests.len(), 1);
+    }
+}
@@ -0,0 +1,320 @@
+use std::{fs::{self, File, OpenOptions}, io::{self, Read, Seek, SeekFrom, Write}, path::{Path, PathBuf}, time::SystemTime};
+
+#[cfg(test)]
+use std::{collections::{HashMap, HashSet}, io::Cursor, sync::{Arc, Mutex}};
+
+use flate2::write::DeflateDecoder;
+
+use crate::tlog;
+
+//what a connection needs to know about a file or directory
+#[derive(Clone,Copy)]
+pub struct FileInfo {
+    pub len:      u64,
+    pub modified: Option<SystemTime>,
+    pub is_dir:   bool,
+    pub is_special: bool,   //neither file nor directory e.g a fifo or device
+}
+
+//writer of an upload; sync is called before the final ack with --fsync
+pub trait SyncWrite: Write + Send {
+    fn sync(&mut self) -> io::Result<()>;
+
+    //writes what is still buffered e.g. the end of a compressed upload; called before sync
+    fn finish(&mut self) -> io::Result<()> {
+        return Ok(());
+    }
+}
+
+impl SyncWrite for File {
+    fn sync(&mut self) -> io::Result<()> {
+        return self.sync_all();
+    }
+}
+
+//a fifo or device has no data on disk; fsync of a fifo fails with EINVAL
+struct SpecialFile(File);
+
+impl Write for SpecialFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        return self.0.write(buf);
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        return self.0.flush();
+    }
+}
+
+impl SyncWrite for SpecialFile {
+    fn sync(&mut self) -> io::Result<()> {
+        return Ok(());
+    }
+}
+
+//a compressed upload (xcompress) is inflated into the file; max limits the inflated
+//bytes like --max-upload-size, the compressed size says nothing about them
+pub fn inflate_into(file: Box<dyn SyncWrite>, max: Option<u64>) -> Box<dyn SyncWrite> {
+    return Box::new(DeflateDecoder::new(LimitedWrite { inner: file, left: max }));
+}
+
+impl<W: SyncWrite> SyncWrite for DeflateDecoder<W> {
+    fn sync(&mut self) -> io::Result<()> {
+        return self.get_mut().sync();
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.try_finish()?;
+        return self.get_mut().finish();
+    }
+}
+
+struct LimitedWrite {
+    inner: Box<dyn SyncWrite>,
+    left:  Option<u64>,                 //None is unlimited
+}
+
+impl Write for LimitedWrite {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.left.map_or(false, |x| buf.len() as u64 > x) {
+            return Err(io::Error::new(io::ErrorKind::Other, "allocation exceeded"));
+        }
+        let len = self.inner.write(buf)?;
+        self.left = self.left.map(|x| x - len as u64);
+        return Ok(len);
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        return self.inner.flush();
+    }
+}
+
+impl SyncWrite for LimitedWrite {
+    fn sync(&mut self) -> io::Result<()> {
+        return self.inner.sync();
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        return self.inner.finish();
+    }
+}
+
+//file access of a connection; RealFs on the server, MemFs in tests
+pub trait Storage: Send + Sync {
+    //reading starts at offset; the resumed download of a client
+    fn open_read(&self, path: &Path, offset: u64) -> io::Result<Box<dyn Read + Send>>;
+    //creates or truncates the file; mode sets the unix permissions
+    fn open_write(&self, path: &Path, mode: Option<u32>) -> io::Result<Box<dyn SyncWrite>>;
+    //writes into an existing special file e.g a fifo; never creates or truncates
+    fn open_special(&self, path: &Path) -> io::Result<Box<dyn SyncWrite>>;
+    fn exists(&self, path: &Path) -> bool;
+    //the path itself is a symlink; its target isn't looked at
+    fn is_symlink(&self, path: &Path) -> bool;
+    fn metadata(&self, path: &Path) -> io::Result<FileInfo>;
+    fn remove(&self, path: &Path) -> io::Result<()>;
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+    fn create_dir_all(&self, path: &Path) -> io::Result<()>;
+    //paths of the entries directly below the directory
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>>;
+}
+
+pub struct RealFs;
+
+impl Storage for RealFs {
+    fn open_read(&self, path: &Path, offset: u64) -> io::Result<Box<dyn Read + Send>> {
+        let mut file = File::open(path)?;
+        if offset > 0 {
+            file.seek(SeekFrom::Start(offset))?;
+        }
+        return Ok(Box::new(file));
+    }
+
+    fn open_write(&self, path: &Path, mode: Option<u32>) -> io::Result<Box<dyn SyncWrite>> {
+        let file = File::create(path)?;
+
+        #[cfg(unix)]
+        if let Some(mode) = mode {
+            use std::os::unix::fs::PermissionsExt;
+            if let Err(err) = file.set_permissions(fs::Permissions::from_mode(mode)) {
+                tlog::warning!("set file mode {:o} of {:?} failed; {}", mode, path, err);
+            }
+        }
+        #[cfg(not(unix))]
+        let _ = mode;
+
+        return Ok(Box::new(file));
+    }
+
+    fn open_special(&self, path: &Path) -> io::Result<Box<dyn SyncWrite>> {
+        //a fifo without reader fails with ENXIO instead of blocking the connection
+        #[cfg(unix)]
+        {
+            use std::os::unix::{fs::OpenOptionsExt, io::AsRawFd};
+
+            let file = OpenOptions::new().write(true).custom_flags(libc::O_NONBLOCK).open(path)?;
+            unsafe {
+                let flags = libc::fcntl(file.as_raw_fd(), libc::F_GETFL);
+                libc::fcntl(file.as_raw_fd(), libc::F_SETFL, flags & !libc::O_NONBLOCK);
+            }
+            return Ok(Box::new(SpecialFile(file)));
+        }
+        #[cfg(not(unix))]
+        return Ok(Box::new(SpecialFile(OpenOptions::new().write(true).open(path)?)));
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        return path.exists();
+    }
+
+    fn is_symlink(&self, path: &Path) -> bool {
+        return fs::symlink_metadata(path).map_or(false, |x| x.file_type().is_symlink());
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<FileInfo> {
+        let meta = fs::metadata(path)?;
+        return Ok(FileInfo {
+            len:      meta.len(),
+            modified: meta.modified().ok(),
+            is_dir:   meta.is_dir(),
+            is_special: !meta.is_file() && !meta.is_dir(),
+        });
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        return fs::remove_file(path);
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        return fs::rename(from, to);
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        return fs::create_dir_all(path);
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        return fs::read_dir(path)?.map(|x| x.map(|entry| entry.path())).collect();
+    }
+}
+
+//in-memory files; a directory exists as long as a file below it exists
+#[cfg(test)]
+pub struct MemFs {
+    files:    Mutex<HashMap<PathBuf, Arc<Mutex<Vec<u8>>>>>,
+    symlinks: Mutex<HashSet<PathBuf>>,
+}
+
+#[cfg(test)]
+struct MemWriter(Arc<Mutex<Vec<u8>>>);
+
+#[cfg(test)]
+impl Write for MemWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        return Ok(buf.len());
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+impl SyncWrite for MemWriter {
+    fn sync(&mut self) -> io::Result<()> {
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+impl MemFs {
+    pub fn new() -> MemFs {
+        MemFs { files: Mutex::new(HashMap::new()), symlinks: Mutex::new(HashSet::new()) }
+    }
+
+    //marks path as symlink; the files below it or at it are what it resolves to
+    pub fn symlink(&self, path: &Path) {
+        self.symlinks.lock().unwrap().insert(path.to_path_buf());
+    }
+
+    pub fn insert(&self, path: &Path, data: &[u8]) {
+        self.files.lock().unwrap().insert(path.to_path_buf(), Arc::new(Mutex::new(data.to_vec())));
+    }
+
+    pub fn get(&self, path: &Path) -> Option<Vec<u8>> {
+        return self.files.lock().unwrap().get(path).map(|x| x.lock().unwrap().clone());
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        return self.files.lock().unwrap().keys().any(|x| x != path && x.starts_with(path));
+    }
+}
+
+#[cfg(test)]
+impl Storage for MemFs {
+    fn open_read(&self, path: &Path, offset: u64) -> io::Result<Box<dyn Read + Send>> {
+        let data = self.get(path).ok_or(io::Error::from(io::ErrorKind::NotFound))?;
+        let mut cursor = Cursor::new(data);
+        cursor.set_position(offset);
+        return Ok(Box::new(cursor));
+    }
+
+    fn open_write(&self, path: &Path, _mode: Option<u32>) -> io::Result<Box<dyn SyncWrite>> {
+        let data = Arc::new(Mutex::new(Vec::new()));
+        self.files.lock().unwrap().insert(path.to_path_buf(), data.clone());
+        return Ok(Box::new(MemWriter(data)));
+    }
+
+    //there are no special files in memory; appends like a fifo would
+    fn open_special(&self, path: &Path) -> io::Result<Box<dyn SyncWrite>> {
+        let data = self.files.lock().unwrap().get(path).cloned().ok_or(io::Error::from(io::ErrorKind::NotFound))?;
+        return Ok(Box::new(MemWriter(data)));
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        return self.get(path).is_some() || self.is_dir(path);
+    }
+
+    fn is_symlink(&self, path: &Path) -> bool {
+        return self.symlinks.lock().unwrap().contains(path);
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<FileInfo> {
+        if let Some(data) = self.get(path) {
+            return Ok(FileInfo { len: data.len() as u64, modified: None, is_dir: false, is_special: false });
+        }
+        if self.is_dir(path) {
+            return Ok(FileInfo { len: 0, modified: None, is_dir: true, is_special: false });
+        }
+        return Err(io::ErrorKind::NotFound.into());
+    }
+
+    fn remove(&self, path: &Path) -> io::Result<()> {
+        return self.files.lock().unwrap().remove(path).map(|_| ()).ok_or(io::ErrorKind::NotFound.into());
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut files = self.files.lock().unwrap();
+        let data = files.remove(from).ok_or(io::Error::from(io::ErrorKind::NotFound))?;
+        files.insert(to.to_path_buf(), data);
+        return Ok(());
+    }
+
+    //directories only exist implicitly
+    fn create_dir_all(&self, _path: &Path) -> io::Result<()> {
+        return Ok(());
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        if !self.is_dir(path) {
+            return Err(io::ErrorKind::NotFound.into());
+        }
+        let files = self.files.lock().unwrap();
+        let mut entries: Vec<PathBuf> = files.keys()
+            .filter_map(|x| x.strip_prefix(path).ok()?.components().next())
+            .map(|x| path.join(x))
+            .collect();
+        entries.sort();
+        entries.dedup();
+        return Ok(entries);
+    }
+}
@@ -0,0 +1,91 @@
+use std::{collections::HashMap, net::SocketAddr, sync::{atomic::{AtomicU64, Ordering}, Arc, Mutex}, time::{Duration, Instant}};
+
+use crate::{server::defs::lock_recover, tftp_protocol::Opcode, tlog};
+
+//an in-flight transfer; inserted by the connection once the request is accepted and removed on its drop
+pub struct ActiveTransfer {
+    pub filename: String,
+    pub opcode:   Opcode,
+    pub bytes:    Arc<AtomicU64>,       //updated by the connection without locking the map
+    pub start:    Instant,
+}
+
+pub type TransferMap = Arc<Mutex<HashMap<SocketAddr,ActiveTransfer>>>;
+
+//one line per transfer; oldest first
+pub fn table(transfers: &TransferMap, now: Instant) -> Vec<String> {
+    let transfers = lock_recover(transfers, "transfer map");
+
+    let mut entries: Vec<(&SocketAddr, &ActiveTransfer)> = transfers.iter().collect();
+    entries.sort_by_key(|(_, x)| x.start);
+
+    return entries.iter().map(|(remote, x)| {
+        let direction = if x.opcode == Opcode::Read {"download"} else {"upload"};
+        let elapsed   = now.saturating_duration_since(x.start);
+        format!("{:?} {} {}; bytes = {}; elapsed = {:.1}s", remote, direction, x.filename, x.bytes.load(Ordering::Relaxed), elapsed.as_secs_f32())
+    }).collect();
+}
+
+pub fn log_table(transfers: &TransferMap) {
+    let lines = table(transfers, Instant::now());
+    tlog::info!("active transfers = {}", lines.len());
+    for i_line in lines {
+        tlog::info!("  {}", i_line);
+    }
+}
+
+//logs the table every interval; called from the dispatcher loop
+pub struct StatsTimer {
+    interval: Duration,
+    last:     Instant,
+}
+
+impl StatsTimer {
+    pub fn new(interval: Duration) -> StatsTimer {
+        StatsTimer {
+            interval: interval,
+            last:     Instant::now(),
+        }
+    }
+
+    pub fn poll(&mut self, transfers: &TransferMap) {
+        if self.last.elapsed() < self.interval {
+            return;
+        }
+        self.last = Instant::now();
+        log_table(transfers);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn table_oldest_first() {
+        let transfers: TransferMap = Arc::new(Mutex::new(HashMap::new()));
+        let now = Instant::now();
+
+        let upload_bytes = Arc::new(AtomicU64::new(0));
+        transfers.lock().unwrap().insert("127.0.0.1:2000".parse().unwrap(), ActiveTransfer {
+            filename: "up.bin".to_string(),
+            opcode:   Opcode::Write,
+            bytes:    upload_bytes.clone(),
+            start:    now - Duration::from_secs(1),
+        });
+        transfers.lock().unwrap().insert("127.0.0.1:1000".parse().unwrap(), ActiveTransfer {
+            filename: "down.bin".to_string(),
+            opcode:   Opcode::Read,
+            bytes:    Arc::new(AtomicU64::new(512)),
+            start:    now - Duration::from_secs(3),
+        });
+
+        //the connection counts on after the entry was inserted
+        upload_bytes.store(1024, Ordering::Relaxed);
+
+        assert_eq!(table(&transfers, now), vec![
+            "127.0.0.1:1000 download down.bin; bytes = 512; elapsed = 3.0s".to_string(),
+            "127.0.0.1:2000 upload up.bin; bytes = 1024; elapsed = 1.0s".to_string(),
+        ]);
+    }
+}
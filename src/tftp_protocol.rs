@@ -1,5 +1,9 @@
 mod recv;
 mod send;
+#[cfg(any(test, feature = "testutil"))]
+pub mod testutil;
+#[cfg(test)]
+mod replay;
 
 pub use recv::*;
 pub use send::*;
@@ -7,6 +11,7 @@ pub use send::*;
 use std::collections::HashMap;
 use std::ops::Range;
 use std::str::FromStr;
+use std::sync::{Arc, atomic::{AtomicBool, Ordering}};
 use std::time::{Duration, Instant};
 use std::default::Default;
 
@@ -15,23 +20,37 @@ use num_traits::FromPrimitive;
 
 pub const DEFAULT_BLOCKSIZE:  usize            = 512;
 pub const DEFAULT_WINDOWSIZE: usize            = 1;
-pub const MAX_BLOCKSIZE:      usize            = 1024;
+pub const MIN_BLOCKSIZE:      usize            = 8;
+pub const MAX_BLOCKSIZE:      usize            = 65464;             //rfc2348
 pub const MAX_PACKET_SIZE:    usize            = MAX_BLOCKSIZE + DATA_BLOCK_NUM.end;
 
 pub const SEND_RECV_BLOCK_TIMEOUT:  Duration   = Duration::from_millis(1000);
 pub const RESEND_TIMEOUT:           Duration   = Duration::from_millis(2000);
+pub const MAX_RESEND_TIMEOUT:       Duration   = Duration::from_millis(16000);   //cap of the exponential backoff
 pub const RECV_TIMEOUT:             Duration   = Duration::from_millis(6500);
-pub const RECV_ACK_TIMEOUT:         Duration   = Duration::from_millis(2500);
+pub const HANDSHAKE_TIMEOUT:        Duration   = Duration::from_millis(2000);   //wait for the answer to RRQ/WRQ before it is sent again
 
 pub const RETRY_COUNT:              usize      = 3;                 //rename to MAX_RETRIES
+pub const MAX_STALLED_WINDOWS:      usize      = 8;                 //windows of DATA without a committed block before the receiver gives up
 
 pub const OPCODE_LEN:         usize            = 2;
 pub const ACK_LEN:            usize            = 4;
 pub const DATA_OFFSET:        usize            = 4;
 pub const DATA_BLOCK_NUM:     Range<usize>     = 2..4;
-pub const PACKET_SIZE_MAX:    usize            = 4096;
 pub const BLKSIZE_STR:        &str             = "blksize";
 pub const WINDOW_STR:         &str             = "windowsize";
+pub const ROLLOVER_STR:       &str             = "rollover";
+pub const UTIMEOUT_STR:       &str             = "utimeout";          //microseconds; like tftp-hpa
+pub const TSIZE_STR:          &str             = "tsize";             //rfc2349; left out when the size is unknown
+pub const OFFSET_STR:         &str             = "offset";            //not standardized; RRQ starts at this byte. Blocks are still numbered from 1
+pub const XCOMPRESS_STR:      &str             = "xcompress";         //not standardized; the DATA blocks carry a compressed stream
+pub const XCOMPRESS_DEFLATE:  &str             = "deflate";           //the only codec of xcompress; raw deflate (rfc1951)
+//every option known to filter_extended_options; reported by .tftp-caps
+pub const KNOWN_OPTIONS:      [&str; 7]        = [BLKSIZE_STR, WINDOW_STR, ROLLOVER_STR, UTIMEOUT_STR, TSIZE_STR, OFFSET_STR, XCOMPRESS_STR];
+pub const MIN_UTIMEOUT:       Duration         = Duration::from_millis(1);
+pub const MAX_UTIMEOUT:       Duration         = Duration::from_secs(255);   //largest timeout of rfc2349
+pub const MANIFEST_NAME:      &str             = ".tftp-manifest";
+pub const CAPS_NAME:          &str             = ".tftp-caps";
 
 #[derive(Clone,Copy,Debug,PartialEq, FromPrimitive,ToPrimitive)]
 pub enum Opcode {
@@ -44,7 +63,7 @@ pub enum Opcode {
 }
 
 #[allow(dead_code)]
-#[derive(Clone,Copy,Debug, FromPrimitive,ToPrimitive)]
+#[derive(Clone,Copy,Debug,PartialEq, FromPrimitive,ToPrimitive)]
 pub enum ErrorNumber {
     NotDefined           = 0,
     FileNotFound         = 1,
@@ -54,13 +73,42 @@ pub enum ErrorNumber {
     UnknownTransferID    = 5,
     FileAlreadyExists    = 6,
     NoSuchUser           = 7,
+    OptionNegotiationFailed = 8, //rfc2347
+}
+
+#[derive(Clone,Debug,PartialEq)]
+pub enum ParseError {
+    BadUtf8,
+    UnterminatedString,
+    UnpairedOption(String),
+    BadNumber(String),
+    OutOfRange(String),
+    UnknownMode(String),
 }
 
+#[derive(Debug)]
 pub struct ErrorResponse {
     pub number: ErrorNumber,
     pub msg:    Option<String>,
 }
 
+//why a transfer ended before the last block
+#[derive(Debug)]
+pub enum TransferError {
+    Timeout,
+    Remote(ErrorResponse),   //ERROR packet received from the peer
+    Aborted(String),
+    Cancelled,               //stopped by the CancelToken; the peer got an ERROR packet
+    Sink(std::io::Error),    //the received data couldn't be written; the peer got a DiskFull ERROR
+}
+
+//set to true by the owner of a transfer to stop it; checked once per loop iteration
+pub type CancelToken = Arc<AtomicBool>;
+
+pub fn is_cancelled(token: &Option<CancelToken>) -> bool {
+    return token.as_ref().map_or(false, |x| x.load(Ordering::Relaxed));
+}
+
 #[allow(dead_code)]
 #[derive(Clone,Copy,Debug)]
 pub enum TransferMode {
@@ -118,8 +166,12 @@ impl<'a> PacketParser<'a> {
         &self.buf[self.pos..]
     }
 
+    pub fn peek_opcode(&self) -> Option<Opcode> {
+        return parse_opcode_raw(self.remaining_bytes());
+    }
+
     pub fn opcode(&mut self) -> Option<Opcode> {
-        let result = parse_opcode_raw(self.remaining_bytes());
+        let result = self.peek_opcode();
      
         if result.is_some() {
             self.pos += OPCODE_LEN;
@@ -152,23 +204,38 @@ impl<'a> PacketParser<'a> {
         return Option::None;
     }
 
-    pub fn extended_options(&mut self) -> Result<HashMap<String,String>,()> {
-        let mut ret = HashMap::new();
+    pub fn extended_options(&mut self) -> Result<HashMap<String,String>,ParseError> {
+        return self.extended_options_ordered().map(|x| x.into_iter().collect());
+    }
+
+    //options in the order of the packet; e.g to echo them in request order
+    pub fn extended_options_ordered(&mut self) -> Result<Vec<(String,String)>,ParseError> {
+        let mut ret = Vec::new();
         let mut lastkey: Option<String> = Option::None;
 
-        for i in self.remaining_bytes().split(|x| x == &0) {
+        let data = self.remaining_bytes();
+        if data.last().map_or(false, |x| *x != 0) {
+            return Err(ParseError::UnterminatedString);
+        }
+
+        for i in data.split(|x| x == &0) {
             let field = if let Ok(x) = String::from_utf8(i.into()) {x} 
-                else {return Err(())};
+                else {return Err(ParseError::BadUtf8)};
             
             if let Some(ref x) = lastkey {
-                ret.insert(x.clone(), field);
+                ret.push((x.clone(), field));
                 let _ = lastkey.take();
             }  else {
                 lastkey = Some(field);
             }
         }    
         
-        //TODO: check for the last 0
+        //the split after the last 0 leaves an empty key
+        if let Some(key) = lastkey {
+            if !key.is_empty() {
+                return Err(ParseError::UnpairedOption(key));
+            }
+        }
 
         return Ok(ret);
     }
@@ -221,6 +288,21 @@ impl<'a> PacketParser<'a> {
 
 
 
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ParseError::BadUtf8               => write!(f, "string is not valid utf8"),
+            ParseError::UnterminatedString    => write!(f, "string is not terminated with 0"),
+            ParseError::UnpairedOption(name)  => write!(f, "option {} has no value", name),
+            ParseError::BadNumber(name)       => write!(f, "option {} is not a valid number", name),
+            ParseError::OutOfRange(name)      => write!(f, "option {} is out of range", name),
+            ParseError::UnknownMode(mode)     => write!(f, "unknown transfer mode {}", mode),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
 impl ToString for ErrorNumber {
     fn to_string(&self) -> String {
         return match *self {
@@ -232,21 +314,38 @@ impl ToString for ErrorNumber {
             ErrorNumber::UnknownTransferID   => "Unknown transfer ID.".to_string(),
             ErrorNumber::FileAlreadyExists   => "File already exists.".to_string(),
             ErrorNumber::NoSuchUser          => "No such user.".to_string(),
+            ErrorNumber::OptionNegotiationFailed => "Option negotiation failed.".to_string(),
         };    
     }
 }
 
-impl ToString for ErrorResponse {
-    fn to_string(&self) -> String {
+impl std::fmt::Display for ErrorResponse {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         if let Some(msg) = &self.msg {
-            msg.clone()
+            write!(f, "{}", msg)
         }
         else {
-           self.number.to_string() 
+            write!(f, "{}", self.number.to_string())
+        }
+    }
+}
+
+impl std::error::Error for ErrorResponse {}
+
+impl std::fmt::Display for TransferError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            TransferError::Timeout      => write!(f, "timeout"),
+            TransferError::Remote(err)  => write!(f, "{}", err),
+            TransferError::Aborted(msg) => write!(f, "{}", msg),
+            TransferError::Cancelled    => write!(f, "cancelled"),
+            TransferError::Sink(err)    => write!(f, "write failed; {}", err),
         }
     }
 }
 
+impl std::error::Error for TransferError {}
+
 impl  ErrorResponse {
     pub fn new_custom(msg: String) -> ErrorResponse {
         ErrorResponse {
@@ -254,6 +353,21 @@ impl  ErrorResponse {
             msg: Some(msg)
         }
     }
+
+    pub fn with_msg(number: ErrorNumber, msg: String) -> ErrorResponse {
+        ErrorResponse {
+            number: number,
+            msg: Some(msg)
+        }
+    }
+
+    pub fn write_packet(&self, buf: &mut Vec<u8>) {
+        PacketBuilder::new(buf)
+            .opcode(Opcode::Error)
+            .number16(self.number as u16)
+            .str(&self.to_string())
+            .separator();
+    }
 }
 
 
@@ -264,6 +378,12 @@ impl From<ErrorNumber> for ErrorResponse {
     }
 }
 
+impl From<ParseError> for ErrorResponse {
+    fn from(err: ParseError) -> Self {
+        ErrorResponse::new_custom(err.to_string())
+    }
+}
+
 
 
 pub fn raw_to_num<T: Copy + From<u8> + core::ops::BitOrAssign + core::ops::Shl<usize,Output=T>+Default>(data: &[u8]) -> Option<T> {
@@ -304,16 +424,63 @@ impl ToString for TransferMode {
 }
 
 impl FromStr for TransferMode {
-    type Err = ();
+    type Err = ParseError;
 
+    //rfc1350: any combination of upper and lower case; e.g "OCTET" of some PXE roms
     fn from_str(s: &str) -> Result<Self,Self::Err> {
-             if s == "netascii" { Ok(TransferMode::Netascii)}
-        else if s == "octet"    { Ok(TransferMode::Octet)}
-        else if s == "mail"     { Ok(TransferMode::Mail)}
-        else {return Err(())}
+             if s.eq_ignore_ascii_case("netascii") { Ok(TransferMode::Netascii)}
+        else if s.eq_ignore_ascii_case("octet")    { Ok(TransferMode::Octet)}
+        else if s.eq_ignore_ascii_case("mail")     { Ok(TransferMode::Mail)}
+        else {return Err(ParseError::UnknownMode(s.to_string()))}
+    }
+}
+
+
+//one line summary of a packet; used for --trace
+pub fn describe_packet(data: &[u8]) -> String {
+    let mut pp = PacketParser::new(data);
+    let opcode = if let Some(x) = pp.peek_opcode() {x} else {return format!("Invalid len={}", data.len())};
+
+    if opcode == Opcode::Error {
+        return match pp.parse_error() {
+            Some(err) => format!("Error number={:?} msg={}", err.number, err),
+            None      => format!("Error len={}", data.len()),
+        };
     }
+
+    pp.opcode();
+
+    return match opcode {
+        Opcode::Read | Opcode::Write => {
+            let filename = pp.string_with_separator().unwrap_or_default();
+            let mode     = pp.string_with_separator().unwrap_or_default();
+            format!("{:?} file={} mode={} options={:?}", opcode, filename, mode, pp.extended_options().unwrap_or_default())
+        },
+        Opcode::Data => {
+            let blocknr = pp.number16().unwrap_or_default();
+            format!("Data block={} len={}", blocknr, pp.remaining_bytes().len())
+        },
+        Opcode::Ack => {
+            format!("Ack block={}", pp.number16().unwrap_or_default())
+        },
+        _ => {
+            format!("{:?} options={:?}", opcode, pp.extended_options().unwrap_or_default())
+        },
+    };
 }
 
+//offset, hex and ascii column of a raw packet; 16 bytes per line
+pub fn hexdump(data: &[u8]) -> String {
+    let mut lines = Vec::new();
+
+    for (i, chunk) in data.chunks(16).enumerate() {
+        let hex: Vec<String> = chunk.iter().map(|x| format!("{:02x}", x)).collect();
+        let ascii: String    = chunk.iter().map(|&x| if x.is_ascii_graphic() || x == b' ' {x as char} else {'.'}).collect();
+        lines.push(format!("{:04x}  {:<47}  |{}|", i*16, hex.join(" "), ascii));
+    }
+
+    return lines.join("\n");
+}
 
 pub fn parse_opcode(raw: u16) -> Option<Opcode> {
     match raw {
@@ -380,6 +547,11 @@ impl<'a> PacketBuilder<'a> {
 pub struct ExtendedOptions {
     pub blksize:    u16,
     pub windowsize: u16,
+    pub rollover:   Option<u16>,
+    pub utimeout:   Option<Duration>,
+    pub tsize:      Option<u64>,
+    pub offset:     Option<u64>,
+    pub xcompress:  bool,
 }
 
 impl ExtendedOptions {
@@ -387,21 +559,100 @@ impl ExtendedOptions {
         ExtendedOptions {
             blksize:    DEFAULT_BLOCKSIZE  as u16,
             windowsize: DEFAULT_WINDOWSIZE as u16,
+            rollover:   None,
+            utimeout:   None,
+            tsize:      None,
+            offset:     None,
+            xcompress:  false,
+        }
+    }
+}
+
+//options both peers agreed on after the handshake
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub struct NegotiatedOptions {
+    pub blksize:    usize,
+    pub windowsize: usize,
+    pub rollover:   u16,
+    pub tsize:      Option<u64>,
+    pub timeout:    Option<Duration>,
+    pub offset:     Option<u64>,
+    pub xcompress:  bool,
+}
+
+impl NegotiatedOptions {
+    pub fn new() -> NegotiatedOptions {
+        NegotiatedOptions {
+            blksize:    DEFAULT_BLOCKSIZE,
+            windowsize: DEFAULT_WINDOWSIZE,
+            rollover:   0,
+            tsize:      None,
+            timeout:    None,
+            offset:     None,
+            xcompress:  false,
         }
     }
 }
 
-pub fn filter_extended_options(options: &HashMap<String,String>) -> Result<(ExtendedOptions, HashMap<String,String>), ()> {
+impl std::fmt::Display for NegotiatedOptions {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "blksize={}; windowsize={}; rollover={}", self.blksize, self.windowsize, self.rollover)?;
+        if let Some(tsize) = self.tsize {
+            write!(f, "; tsize={}", tsize)?;
+        }
+        if let Some(timeout) = self.timeout {
+            write!(f, "; timeout={}s", timeout.as_secs_f32())?;
+        }
+        if let Some(offset) = self.offset {
+            write!(f, "; offset={}", offset)?;
+        }
+        if self.xcompress {
+            write!(f, "; xcompress={}", XCOMPRESS_DEFLATE)?;
+        }
+        return Ok(());
+    }
+}
+
+pub fn filter_extended_options(options: &HashMap<String,String>) -> Result<(ExtendedOptions, HashMap<String,String>), ParseError> {
     let mut known  = ExtendedOptions::new();
     let mut unknown = HashMap::new();
     
     for (name,value) in options {
         match name.as_str() {
             BLKSIZE_STR => {
-                known.blksize    = if let Ok(x) = u16::from_str_radix(&value, 10) {x} else {return Err(());};
+                known.blksize    = if let Ok(x) = u16::from_str_radix(&value, 10) {x} else {return Err(ParseError::BadNumber(name.clone()));};
             },
             WINDOW_STR  => {
-                known.windowsize = if let Ok(x) = u16::from_str_radix(&value, 10) {x} else {return Err(());};
+                known.windowsize = if let Ok(x) = u16::from_str_radix(&value, 10) {x} else {return Err(ParseError::BadNumber(name.clone()));};
+            },
+            ROLLOVER_STR => {
+                known.rollover = match value.as_str() {
+                    "0" => Some(0),
+                    "1" => Some(1),
+                    _   => return Err(ParseError::BadNumber(name.clone())),
+                };
+            },
+            UTIMEOUT_STR => {
+                let micros = if let Ok(x) = u64::from_str_radix(&value, 10) {x} else {return Err(ParseError::BadNumber(name.clone()));};
+                let timeout = Duration::from_micros(micros);
+                if timeout < MIN_UTIMEOUT || timeout > MAX_UTIMEOUT {
+                    return Err(ParseError::OutOfRange(name.clone()));
+                }
+                known.utimeout = Some(timeout);
+            },
+            //some clients ask for the size with an empty value instead of 0
+            TSIZE_STR if value.is_empty() => {
+                known.tsize = Some(0);
+            },
+            TSIZE_STR => {
+                known.tsize = if let Ok(x) = u64::from_str_radix(&value, 10) {Some(x)} else {return Err(ParseError::BadNumber(name.clone()));};
+            },
+            OFFSET_STR => {
+                known.offset = if let Ok(x) = u64::from_str_radix(&value, 10) {Some(x)} else {return Err(ParseError::BadNumber(name.clone()));};
+            },
+            //another codec is left unknown and thus not acked
+            XCOMPRESS_STR if value.eq_ignore_ascii_case(XCOMPRESS_DEFLATE) => {
+                known.xcompress = true;
             },
             _                 => {
                 unknown.insert(name.clone(), value.clone());
@@ -412,146 +663,50 @@ pub fn filter_extended_options(options: &HashMap<String,String>) -> Result<(Exte
     return Ok((known, unknown));
 }
 
-fn ring_diff(a: u16, b: u16) -> usize {
-    return if a <= b {
-        a.abs_diff(b) as usize
-    } else {
-        (u8::MAX as usize + b as usize + 1) - a as usize
-    };
-}
-
-//RecvStateMachine
-//  is deprecated; use RecvController as soon as it stable
-pub struct RecvStateMachine<'a> {
-    windowssize:      usize,
-    blksize:          usize,
-    bufs:             Vec<Option<Vec<u8>>>,
-    acked:            u16,
-    is_end:           bool,
-    is_timeout:       bool,
-    timeout:          OneshotTimer,
-    lost_ack_timeout: Option<Instant>,
-    writer:           &'a mut dyn std::io::Write,
-}
-
-impl<'a> RecvStateMachine<'a> {
-    pub fn new(writer: &'a mut dyn std::io::Write, blksize: usize, windowssize: usize) -> Self {
-        RecvStateMachine {
-            windowssize: windowssize,
-            blksize: blksize,
-            bufs: vec![None; windowssize],
-            acked: 0,
-            is_end: false,
-            is_timeout: false,
-            timeout: OneshotTimer::new(RECV_TIMEOUT),
-            lost_ack_timeout: None,
-            writer: writer
-        }
-    }
+//number of blocks from a to b; with rollover=1 the block 0 is skipped after a wrap
+fn block_diff(a: u16, b: u16, rollover: u16) -> usize {
+    let diff = b.wrapping_sub(a) as usize;
+    let is_wrap = b < a;
 
-    pub fn is_end(&self) -> bool {
-        return self.is_end;
-    }
+    return if is_wrap && rollover == 1 && diff > 0 {diff - 1} else {diff};
+}
 
-    pub fn is_timeout(&self) -> bool {
-        return self.is_end && self.is_timeout;
+fn block_add(a: u16, count: usize, rollover: u16) -> u16 {
+    let mut ret = a;
+    for _ in 0..count {
+        ret = if ret == u16::MAX {rollover} else {ret + 1};
     }
+    return ret;
+}
 
-    pub fn insert_frame(&mut self, data: &[u8]) {
-        let _ = self.timeout.is_timeout();
-
-        let mut pp = PacketParser::new(data);
-        let is_data = pp.opcode_expect(Opcode::Data);
-        let blocknr = pp.number16().unwrap();
-        let data = pp.remaining_bytes();
-
-        if !is_data {return};
-
-        let diff = ring_diff(self.acked, blocknr); 
-        if diff > self.windowssize || diff == 0 { return; }
-        
-        let idx = diff.overflowing_sub(1).0;
-
-        self.bufs[idx] = Some(Vec::from(data));
-        self.lost_ack_timeout = None;
+//wait before a retransmit of the send window
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum BackoffStrategy {
+    Fixed,
+    Exponential { cap: Duration },
+}
 
-        self.timeout.explicit_start();
+impl BackoffStrategy {
+    pub fn from_name(name: &str) -> Option<BackoffStrategy> {
+        return match name {
+            "fixed"       => Some(BackoffStrategy::Fixed),
+            "exponential" => Some(BackoffStrategy::Exponential { cap: MAX_RESEND_TIMEOUT }),
+            _             => None,
+        };
     }
 
-    pub fn sync(&mut self) -> Option<u16> {
-        let (ready_blocks, is_last) = self.is_complete();
-        let is_blocks = ready_blocks > 0;
-        let is_all_blocks = ready_blocks == self.windowssize || is_blocks && is_last;
-        let is_timeout = self.timeout.is_timeout();
-
-        self.is_end = is_last;
-
-        if let Some(lost_ack_timeout) = self.lost_ack_timeout {
-            if lost_ack_timeout.elapsed() > RECV_ACK_TIMEOUT {
-                self.lost_ack_timeout = Some(Instant::now());
-                return Some(self.acked)
-            }
-        }
-
-        if !is_blocks && is_timeout {
-            self.is_timeout = true;
-            self.is_end     = true;
-            return None;
-        }
-        
-        if !is_timeout && !is_all_blocks {
-            return None;
-        }
-
-        for i in 0..ready_blocks {
-            self.writer.write(self.bufs[i].as_ref().unwrap().as_ref()).expect("write to file failed");
-        }
-        for _ in 0..ready_blocks {
-            self.bufs.remove(0);
-            self.bufs.push(None);
-        }
-
-        self.acked  = self.acked.overflowing_add(ready_blocks as u16).0;
-        self.is_end = is_last;
-
-        if !self.is_end {
-            self.lost_ack_timeout = Some(Instant::now());
-        }
-    
-        return Some(self.acked);
-    }     
-
-    fn is_complete(&self) -> (usize,bool) {
-        if self.is_end { return (0, true); }
-
-        let mut ready_blocks = 0;
-        let mut is_last = false;
-
-        for i in &self.bufs {
-            if let Some(data) =  i {
-                ready_blocks+=1;
-                if data.len() < self.blksize {
-                    is_last = true;
-                    break;
-                }
-            } else {
-                return (ready_blocks, is_last);
-            }
-        }
-
-        return (ready_blocks, is_last);
+    //retry 0 is the wait after the first transmission
+    pub fn timeout(&self, base: Duration, retry: usize) -> Duration {
+        return match self {
+            BackoffStrategy::Fixed              => base,
+            BackoffStrategy::Exponential { cap } => {
+                let factor = 1u32.checked_shl(retry as u32).unwrap_or(u32::MAX);
+                base.checked_mul(factor).unwrap_or(*cap).min(*cap)
+            },
+        };
     }
 }
 
-
-
-
-
-
-
-
-
-
 //TODO: move this to another place
 #[derive(Debug)]
 pub struct OneshotTimer {
@@ -580,5 +735,159 @@ impl OneshotTimer {
     pub fn reset(&mut self) {
         self.start = None;
     }
+
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hexdump_lines() {
+        assert_eq!(hexdump(&[]), "");
+        assert_eq!(hexdump(b"\x00\x01a.txt\x00"), "0000  00 01 61 2e 74 78 74 00                          |..a.txt.|");
+
+        let dump = hexdump(&[0x41; 17]);
+        let lines: Vec<&str> = dump.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].ends_with("|AAAAAAAAAAAAAAAA|"));
+        assert!(lines[1].starts_with("0010  41  "));
+    }
+
+    #[test]
+    fn block_diff_wrap() {
+        assert_eq!(block_diff(0, 1, 0), 1);
+        assert_eq!(block_diff(10, 14, 0), 4);
+        assert_eq!(block_diff(65535, 0, 0), 1);
+        assert_eq!(block_diff(65534, 2, 0), 4);
+        assert_eq!(block_diff(65535, 1, 1), 1);
+        assert_eq!(block_diff(65534, 2, 1), 3);
+    }
+
+    #[test]
+    fn block_add_wrap() {
+        assert_eq!(block_add(0, 1, 0), 1);
+        assert_eq!(block_add(65535, 1, 0), 0);
+        assert_eq!(block_add(65535, 1, 1), 1);
+        assert_eq!(block_add(65534, 3, 1), 2);
+    }
+
+    #[test]
+    fn error_number_option_negotiation() {
+        assert_eq!(ErrorNumber::from_u16(8), Some(ErrorNumber::OptionNegotiationFailed));
+        assert_eq!(ErrorNumber::from_u16(9), None);
+
+        let mut buf = Vec::new();
+        ErrorResponse::from(ErrorNumber::OptionNegotiationFailed).write_packet(&mut buf);
+        let err = PacketParser::new(&buf).parse_error().unwrap();
+        assert_eq!(err.number, ErrorNumber::OptionNegotiationFailed);
+    }
+
+    #[test]
+    fn utimeout_option() {
+        let utimeout = |value: &str| {
+            let options = HashMap::from([(UTIMEOUT_STR.to_string(), value.to_string())]);
+            filter_extended_options(&options).map(|(known, _)| known.utimeout)
+        };
+
+        assert_eq!(utimeout("50000"), Ok(Some(Duration::from_millis(50))));
+        assert_eq!(utimeout("255000000"), Ok(Some(MAX_UTIMEOUT)));
+        assert_eq!(utimeout("999"), Err(ParseError::OutOfRange(UTIMEOUT_STR.to_string())));
+        assert_eq!(utimeout("255000001"), Err(ParseError::OutOfRange(UTIMEOUT_STR.to_string())));
+        assert_eq!(utimeout("1s"), Err(ParseError::BadNumber(UTIMEOUT_STR.to_string())));
+    }
+
+    #[test]
+    fn tsize_empty_value() {
+        let packet = b"\x00\x01a.bin\x00octet\x00tsize\x00\x00blksize\x001024\x00";
+        let mut pp = PacketParser::new(packet);
+        assert_eq!(pp.opcode(), Some(Opcode::Read));
+        assert_eq!(pp.string_with_separator().as_deref(), Some("a.bin"));
+        assert_eq!(pp.string_with_separator().as_deref(), Some("octet"));
+
+        let options = pp.extended_options_ordered().unwrap();
+        assert_eq!(options, vec![
+            (TSIZE_STR.to_string(), "".to_string()),
+            (BLKSIZE_STR.to_string(), "1024".to_string()),
+        ]);
+
+        let (known, unknown) = filter_extended_options(&options.into_iter().collect()).unwrap();
+        assert_eq!(known.tsize, Some(0));
+        assert_eq!(known.blksize, 1024);
+        assert!(unknown.is_empty());
+    }
+
+    #[test]
+    fn known_options_accepted() {
+        let value = |name: &str| match name {
+            UTIMEOUT_STR  => "1000",
+            XCOMPRESS_STR => XCOMPRESS_DEFLATE,
+            _             => "1",
+        };
+        let options: HashMap<String, String> = KNOWN_OPTIONS.iter().map(|x| (x.to_string(), value(x).to_string())).collect();
+        let (_, unknown) = filter_extended_options(&options).unwrap();
+        assert!(unknown.is_empty(), "{:?}", unknown);
+    }
+
+    #[test]
+    fn xcompress_deflate_only() {
+        let options: HashMap<String, String> = [(XCOMPRESS_STR.to_string(), "Deflate".to_string())].into_iter().collect();
+        let (known, unknown) = filter_extended_options(&options).unwrap();
+        assert!(known.xcompress);
+        assert!(unknown.is_empty());
+
+        let options: HashMap<String, String> = [(XCOMPRESS_STR.to_string(), "zstd".to_string())].into_iter().collect();
+        let (known, unknown) = filter_extended_options(&options).unwrap();
+        assert!(!known.xcompress);
+        assert_eq!(unknown.get(XCOMPRESS_STR).map(|x| x.as_str()), Some("zstd"));
+    }
+
+    #[test]
+    fn backoff_timeout() {
+        let base = Duration::from_millis(100);
+
+        let fixed = BackoffStrategy::Fixed;
+        assert_eq!(fixed.timeout(base, 0), base);
+        assert_eq!(fixed.timeout(base, 5), base);
+
+        let exponential = BackoffStrategy::Exponential { cap: Duration::from_millis(500) };
+        assert_eq!(exponential.timeout(base, 0), Duration::from_millis(100));
+        assert_eq!(exponential.timeout(base, 1), Duration::from_millis(200));
+        assert_eq!(exponential.timeout(base, 2), Duration::from_millis(400));
+        assert_eq!(exponential.timeout(base, 3), Duration::from_millis(500));
+        assert_eq!(exponential.timeout(base, 100), Duration::from_millis(500));
+    }
+
+    #[test]
+    fn oneshot_timer_timeout() {
+        let mut timer = OneshotTimer::new(Duration::from_millis(20));
+
+        //first call starts the timer
+        assert!(!timer.is_timeout());
+        assert!(!timer.is_timeout());
+
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(timer.is_timeout());
+
+        timer.reset();
+        assert!(!timer.is_timeout());
+    }
+
+    #[test]
+    fn next_frames_whole_burst() {
+        let data = vec![7u8; 20];
+        let mut reader = std::io::Cursor::new(&data);
+        let mut window_buffer = SendStateMachine::new(&mut reader, 8, 4);
+
+        assert!(matches!(window_buffer.next(), SendAction::SendBuffer));
+        assert!(window_buffer.next_frame().is_some());
+
+        //the rest of the burst; the short block ends the window
+        let frames: Vec<usize> = window_buffer.next_frames().iter().map(|x| x.len()).collect();
+        assert_eq!(frames, vec![DATA_OFFSET + 8, DATA_OFFSET + 4]);
+        assert!(window_buffer.next_frames().is_empty());
+    }
+}
@@ -1,113 +1,299 @@
 use super::*;
 
+use std::io;
+
+use crate::tlog;
+
+//only the results of WriteSink and SinkEnd are checked; a failure ends the transfer with a DiskFull ERROR
 pub enum RecvCallbackArg<'a> {
     WriteSink(&'a [u8]),
+    SinkEnd,                            //the last block was written; before its ACK e.g. for fsync
     Ack(&'a [u8]),
+    Error(&'a [u8]),                    //sent to the peer before the transfer is given up
     Recv(&'a mut Vec<u8>, Duration),
 }
 
 pub struct RecvController<'a> {
     windowssize:      usize,
     blksize:          usize,
-    callback:         Box<dyn FnMut(RecvCallbackArg) + 'a>,
+    callback:         Box<dyn FnMut(RecvCallbackArg) -> io::Result<()> + 'a>,
     acked:            u16,
+    rollover:         u16,
+    max_len:          Option<u64>,
+    written:          u64,
+    uncommitted:      usize,                //DATA packets since the last commit; see MAX_STALLED_WINDOWS
+    cancel:           Option<CancelToken>,
+    inject_delay:     Option<Duration>,
+    timeout:          Duration,
     window_buf:       Vec<Option<Vec<u8>>>, //TODO: use ringbuffer
+    #[cfg(debug_assertions)]
+    window_blocknr:   Vec<Option<u16>>,     //block numbers of window_buf; only for check_commit
     ack_buf:          Vec<u8>,
 }
 
 impl<'a> RecvController<'a> {
 
-    pub fn new(windowsize: usize, blksize: usize, callback: Box<dyn FnMut(RecvCallbackArg) + 'a>) -> RecvController<'a> {
+    pub fn new(windowsize: usize, blksize: usize, callback: Box<dyn FnMut(RecvCallbackArg) -> io::Result<()> + 'a>) -> RecvController<'a> {
         RecvController {
             windowssize: windowsize,
             blksize: blksize,
             callback: callback,
             acked: 0,
+            rollover: 0,
+            max_len: None,
+            written: 0,
+            uncommitted: 0,
+            cancel: None,
+            inject_delay: None,
+            timeout: RECV_TIMEOUT,
             window_buf: vec![None; windowsize],
-            ack_buf: vec![0;MAX_PACKET_SIZE],
+            #[cfg(debug_assertions)]
+            window_blocknr: vec![None; windowsize],
+            ack_buf: vec![0;ACK_LEN],
         }
     }
 
-    pub fn run(&mut self) -> Result<(), String> {
+    //block number following 65535; 0 or 1
+    pub fn set_rollover(&mut self, rollover: u16) {
+        self.rollover = rollover;
+    }
+
+    //abort the transfer before more than max_len bytes are passed to the sink
+    pub fn set_max_len(&mut self, max_len: Option<u64>) {
+        self.max_len = max_len;
+    }
+
+    pub fn set_cancel(&mut self, cancel: Option<CancelToken>) {
+        self.cancel = cancel;
+    }
+
+    //diagnostics; sleep before every ACK to reproduce timing problems
+    pub fn set_inject_delay(&mut self, delay: Option<Duration>) {
+        self.inject_delay = delay;
+    }
+
+    //wait for the next DATA before the ACK is sent again; negotiated with utimeout
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    //payload passed to the sink; without headers; counterpart of SendStateMachine::read_len
+    pub fn written_len(&self) -> u64 {
+        return self.written;
+    }
+
+    pub fn is_max_len_exceeded(&self) -> bool {
+        return self.max_len.map_or(false, |x| self.written > x);
+    }
+
+    pub fn run(&mut self) -> Result<(), TransferError> {
         let mut  bufs:  Vec<Option<Vec<u8>>> = vec![None; self.windowssize];
 
         loop { 
-            self.fill_window()?;
-            if self.write_window() { return Ok(()); }
+            if is_cancelled(&self.cancel) {
+                return Err(self.send_cancelled());
+            }
+
+            let idx = self.fill_window()?;
+            let is_last = self.write_window()?;
+            if !is_last && self.is_burst_end(idx) && !self.is_max_len_exceeded() {
+                self.ack_gap()?;
+            }
+            if self.is_max_len_exceeded() { return Err(TransferError::Aborted("allocation exceeded".into())); }
+            if is_last { return Ok(()); }
         }
     }
 
-    fn write_window(&mut self) -> bool {
+    fn write_window(&mut self) -> Result<bool, TransferError> {
         let (write_count, is_last) = self.is_complete();
 
         if write_count == self.windowssize || is_last {
             //println!("write_window={}; is_last={}; windowsize={}; acked={}", write_count,is_last, self.windowssize, self.acked);
 
-            for i_write in 0..write_count {
-                let data = RecvCallbackArg::WriteSink(&(self.window_buf[i_write].as_ref().unwrap()));
-                (self.callback)(data);
+            if !self.commit(write_count)? {
+                return Ok(false);
+            }
+
+            return Ok(is_last);
+        }
+
+        return Ok(false);
+    }
+
+    //pass the first write_count blocks to the sink and ack them;
+    //nothing is acked when the sink fails
+    fn commit(&mut self, write_count: usize) -> Result<bool, TransferError> {
+        #[cfg(debug_assertions)]
+        self.check_commit(write_count);
+
+        let window_len: usize = self.window_buf[0..write_count].iter().map(|x| x.as_ref().unwrap().len()).sum();
+        let is_last = write_count > 0 && self.window_buf[write_count - 1].as_ref().unwrap().len() < self.blksize;
+        self.written += window_len as u64;
+        if self.is_max_len_exceeded() {
+            return Ok(false);
+        }
+
+        for i_write in 0..write_count {
+            let data = RecvCallbackArg::WriteSink(&(self.window_buf[i_write].as_ref().unwrap()));
+            if let Err(err) = (self.callback)(data) {
+                return Err(self.send_sink_error(err));
             }
-            for i_write in 0..write_count {
-                self.window_buf.remove(0);
-                self.window_buf.push(None);
+        }
+        if is_last {
+            if let Err(err) = (self.callback)(RecvCallbackArg::SinkEnd) {
+                return Err(self.send_sink_error(err));
+            }
+        }
+        for _ in 0..write_count {
+            self.window_buf.remove(0);
+            self.window_buf.push(None);
+            #[cfg(debug_assertions)]
+            {
+                self.window_blocknr.remove(0);
+                self.window_blocknr.push(None);
             }
+        }
 
-            self.incr_send_ack(write_count);
+        self.uncommitted = 0;
+        self.incr_send_ack(write_count);
+        return Ok(true);
+    }
 
-            return is_last;
+    //the committed slots have to be the blocks after acked without a gap;
+    //a broken window logic would otherwise corrupt the data silently
+    #[cfg(debug_assertions)]
+    fn check_commit(&self, write_count: usize) {
+        for i in 0..write_count {
+            let expected = block_add(self.acked, i + 1, self.rollover);
+            debug_assert!(self.window_buf[i].is_some(), "commit of empty slot {}; acked={}", i, self.acked);
+            debug_assert_eq!(self.window_blocknr[i], Some(expected), "commit of slot {} out of order; acked={}", i, self.acked);
         }
+    }
 
-        return false;
+    //the last block of a burst is buffered but an earlier one is still missing;
+    //the contiguous blocks are committed and acked so the sender retransmits from the gap
+    //instead of waiting for its timeout
+    fn ack_gap(&mut self) -> Result<(), TransferError> {
+        let (write_count, _) = self.is_complete();
+        tlog::debug!("gap after block {}; ack {} in-order blocks", self.acked, write_count);
+
+        if write_count == 0 {
+            self.resend_ack();
+        } else {
+            self.commit(write_count)?;
+        }
+        return Ok(());
     }
 
-    fn fill_window(&mut self) -> Result<(), String> {
+    //the slot idx is the last of the window or holds the short end block;
+    //false after the window was committed
+    fn is_burst_end(&self, idx: usize) -> bool {
+        let data = if let Some(data) = &self.window_buf[idx] {data} else {return false;};
+        return idx + 1 == self.windowssize || data.len() < self.blksize;
+    }
+
+    //returns the window slot of the buffered block
+    fn fill_window(&mut self) -> Result<usize, TransferError> {
         let mut buf: Vec<u8> = Vec::new();
         
-        for i_retry in 0..RETRY_COUNT {
-            if i_retry > 0 && self.acked > 0 {
-                buf.clear();
-                self.resend_ack();
+        //only a timeout counts as retry; stray or duplicate packets are dropped
+        let mut i_retry = 0;
+        while i_retry < RETRY_COUNT {
+            if is_cancelled(&self.cancel) {
+                return Err(self.send_cancelled());
             }
 
             buf.clear();
-            (self.callback)(RecvCallbackArg::Recv(&mut buf, RECV_TIMEOUT));
+            let _ = (self.callback)(RecvCallbackArg::Recv(&mut buf, self.timeout));
 
-            if buf.is_empty() {continue;}
+            if buf.is_empty() {
+                i_retry += 1;
+                if i_retry < RETRY_COUNT && self.acked > 0 {
+                    self.resend_ack();
+                }
+                continue;
+            }
 
             //parse packet
             let mut pp = PacketParser::new(&buf);
-            let is_data = pp.opcode_expect(Opcode::Data);
-            if !is_data {continue;}
+            match pp.peek_opcode() {
+                Some(Opcode::Data)  => {},
+                Some(Opcode::Error) => return Err(TransferError::Remote(pp.parse_error().unwrap())),
+                _                   => continue,
+            }
+            pp.opcode();
 
             let blocknr = if let Some(blocknr) = pp.number16() {blocknr} else {continue;};
+
+            //a block which is lost every time keeps the sender busy with the same window;
+            //the recv timeout never fires because the duplicates keep coming
+            self.uncommitted += 1;
+            if self.uncommitted > MAX_STALLED_WINDOWS * self.windowssize {
+                return Err(self.send_stalled());
+            }
             let data = pp.remaining_bytes();
 
             //fit blocknummer in our windows
-            let diff = ring_diff(self.acked, blocknr); 
-            if diff > self.windowssize || diff == 0 { continue; }
+            let diff = block_diff(self.acked, blocknr, self.rollover); 
+            if diff > self.windowssize || diff == 0 {
+                tlog::debug!("drop block {}: {}; acked={}; windowsize={}", blocknr, self.classify_dropped(blocknr), self.acked, self.windowssize);
+                continue;
+            }
             let idx = diff.overflowing_sub(1).0;
 
             if self.window_buf[idx].is_some() {
+                tlog::debug!("drop block {}: duplicate/old; already buffered", blocknr);
                 continue;
             }
 
             self.window_buf[idx] = Some(data.to_owned());
+            #[cfg(debug_assertions)]
+            {
+                self.window_blocknr[idx] = Some(blocknr);
+            }
 
-            return Ok(());
+            return Ok(idx);
         }
         
-        return Err("timeout".into());
+        return Err(TransferError::Timeout);
+    }
+
+    fn send_stalled(&mut self) -> TransferError {
+        let msg = format!("no block after {} committed in {} windows", self.acked, MAX_STALLED_WINDOWS);
+        let mut buf = Vec::new();
+        ErrorResponse::new_custom(msg.clone()).write_packet(&mut buf);
+        let _ = (self.callback)(RecvCallbackArg::Error(&buf));
+        return TransferError::Aborted(msg);
+    }
+
+    //e.g. ENOSPC; the peer is told that the disk is full
+    fn send_sink_error(&mut self, err: io::Error) -> TransferError {
+        let mut buf = Vec::new();
+        ErrorResponse::with_msg(ErrorNumber::DiskFull, err.to_string()).write_packet(&mut buf);
+        let _ = (self.callback)(RecvCallbackArg::Error(&buf));
+        return TransferError::Sink(err);
+    }
+
+    fn send_cancelled(&mut self) -> TransferError {
+        let mut buf = Vec::new();
+        ErrorResponse::new_custom(TransferError::Cancelled.to_string()).write_packet(&mut buf);
+        let _ = (self.callback)(RecvCallbackArg::Error(&buf));
+        return TransferError::Cancelled;
     }
 
     fn send_ack(&mut self, blocknr: u16) {
+        if let Some(delay) = self.inject_delay {
+            std::thread::sleep(delay);
+        }
+
         PacketBuilder::new(&mut self.ack_buf)
             .opcode(Opcode::Ack)
             .number16(blocknr);
-        (self.callback)(RecvCallbackArg::Ack(&self.ack_buf));
+        let _ = (self.callback)(RecvCallbackArg::Ack(&self.ack_buf));
     }
 
     fn incr_send_ack(&mut self, window_count: usize) {
-        self.acked = self.acked.overflowing_add(window_count as u16).0 ;
+        self.acked = block_add(self.acked, window_count, self.rollover);
 
         //println!("incr_send_ack acked={}", self.acked);
         self.send_ack(self.acked);
@@ -117,6 +303,16 @@ impl<'a> RecvController<'a> {
         self.send_ack(self.acked);
     }
 
+    //blocks up to half the sequence space behind acked are stale retransmits;
+    //everything else lies ahead of the window
+    fn classify_dropped(&self, blocknr: u16) -> &'static str {
+        if self.acked.wrapping_sub(blocknr) < u16::MAX / 2 {
+            "duplicate/old"
+        } else {
+            "future/too-far"
+        }
+    }
+
     fn is_complete(&self) -> (usize,bool) {
         let mut ready_blocks = 0;
         let mut is_last = false;
@@ -138,6 +334,127 @@ impl<'a> RecvController<'a> {
 
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn lost_block_stalls() {
+        let mut count = 0;
+        let mut error = Vec::new();
+        let mut ctrl = RecvController::new(4, 8, Box::new(|action| {
+            match action {
+                //block 1 never arrives; the sender repeats the rest of the window without end
+                RecvCallbackArg::Recv(buf, _) if count < 10000 => {
+                    let blocknr = 2 + (count % 3) as u16;
+                    count += 1;
+                    PacketBuilder::new(buf).opcode(Opcode::Data).number16(blocknr).raw_data(&[0; 8]);
+                },
+                RecvCallbackArg::Error(packet) => error = packet.to_vec(),
+                _ => {},
+            }
+            Ok(())
+        }));
+
+        let result = ctrl.run();
+        drop(ctrl);
+
+        assert!(matches!(result, Err(TransferError::Aborted(_))), "{:?}", result);
+        assert_eq!(count, MAX_STALLED_WINDOWS * 4 + 1);
+        assert_eq!(PacketParser::new(&error).peek_opcode(), Some(Opcode::Error));
+    }
+
+    //fails like a full disk once limit bytes are written
+    struct FullWriter {
+        written: Vec<u8>,
+        limit:   usize,
+    }
+
+    impl Write for FullWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let len = buf.len().min(self.limit - self.written.len());
+            if len == 0 {
+                return Err(io::Error::new(io::ErrorKind::Other, "no space left on device"));
+            }
+            self.written.extend_from_slice(&buf[..len]);
+            return Ok(len);
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            return Ok(());
+        }
+    }
+
+    #[test]
+    fn sink_error_disk_full() {
+        let mut writer = FullWriter { written: Vec::new(), limit: 20 };
+        let mut blocknr = 0u16;
+        let mut acks    = Vec::new();
+        let mut error   = Vec::new();
+        let mut ctrl = RecvController::new(2, 8, Box::new(|action| {
+            match action {
+                RecvCallbackArg::WriteSink(data) => writer.write_all(data)?,
+                RecvCallbackArg::Recv(buf, _) if blocknr < 10 => {
+                    blocknr += 1;
+                    PacketBuilder::new(buf).opcode(Opcode::Data).number16(blocknr).raw_data(&[blocknr as u8; 8]);
+                },
+                RecvCallbackArg::Ack(packet)   => acks.push(u16::from_be_bytes([packet[2], packet[3]])),
+                RecvCallbackArg::Error(packet) => error = packet.to_vec(),
+                _ => {},
+            }
+            Ok(())
+        }));
+
+        let result = ctrl.run();
+        drop(ctrl);
+
+        //the second window fails in block 3; it is not acked
+        assert!(matches!(result, Err(TransferError::Sink(_))), "{:?}", result);
+        assert_eq!(acks, vec![2]);
+        assert_eq!(writer.written.len(), 20);
+        assert_eq!(blocknr, 4);
+        assert_eq!(PacketParser::new(&error).parse_error().unwrap().number, ErrorNumber::DiskFull);
+    }
+
+    #[test]
+    fn sink_end_before_last_ack() {
+        let mut blocknr = 0u16;
+        let mut events  = Vec::new();
+        let mut ctrl = RecvController::new(2, 8, Box::new(|action| {
+            match action {
+                RecvCallbackArg::Recv(buf, _) if blocknr < 3 => {
+                    blocknr += 1;
+                    let len = if blocknr == 3 {4} else {8};
+                    PacketBuilder::new(buf).opcode(Opcode::Data).number16(blocknr).raw_data(&vec![0; len]);
+                },
+                RecvCallbackArg::WriteSink(data) => events.push(format!("write {}", data.len())),
+                RecvCallbackArg::SinkEnd         => events.push("end".to_string()),
+                RecvCallbackArg::Ack(packet)     => events.push(format!("ack {}", u16::from_be_bytes([packet[2], packet[3]]))),
+                _ => {},
+            }
+            Ok(())
+        }));
+
+        assert!(ctrl.run().is_ok());
+        drop(ctrl);
+
+        assert_eq!(events, vec!["write 8", "write 8", "ack 2", "write 4", "end", "ack 3"]);
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    #[should_panic(expected = "out of order")]
+    fn commit_out_of_order_panics() {
+        let mut ctrl = RecvController::new(2, 8, Box::new(|_| Ok(())));
+
+        //block 2 in the slot of block 1
+        ctrl.window_buf[0]     = Some(vec![0; 8]);
+        ctrl.window_blocknr[0] = Some(2);
+        let _ = ctrl.commit(1);
+    }
+}
+
 // pub fn test_run() {
 // let mut x = 0;
 // {
@@ -0,0 +1,103 @@
+//replays raw packets of real clients through the parser; one file per UDP payload.
+//<name>.bin is the payload and <name>.txt the expected decode() of it
+use super::*;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+//captures of the repository; TFTP_CAPTURES points to another directory e.g with private captures
+fn captures_dir() -> PathBuf {
+    if let Ok(dir) = std::env::var("TFTP_CAPTURES") {
+        return PathBuf::from(dir);
+    }
+    return Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("captures");
+}
+
+fn decode_options(pp: &mut PacketParser) -> String {
+    let options = match pp.extended_options() {
+        Ok(x)    => x,
+        Err(err) => return format!("options invalid; {}", err),
+    };
+
+    //sorted; the order of the HashMap is random
+    let mut pairs: Vec<String> = options.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+    pairs.sort();
+
+    return match filter_extended_options(&options) {
+        Ok(_)    => format!("options=[{}]", pairs.join(", ")),
+        Err(err) => format!("options=[{}] invalid; {}", pairs.join(", "), err),
+    };
+}
+
+//stable one line form of everything the parser reads from a packet
+pub fn decode(packet: &[u8]) -> String {
+    let raw = if let Some(x) = raw_to_num::<u16>(packet) {x} else {return format!("Short len={}", packet.len())};
+    let opcode = if let Some(x) = parse_opcode(raw) {x} else {return format!("UnknownOpcode {}", raw)};
+    let mut pp = PacketParser::new(packet);
+
+    if opcode == Opcode::Error {
+        return match pp.parse_error() {
+            Some(err) => format!("Error number={:?} msg={}", err.number, err),
+            None      => format!("Error invalid len={}", packet.len()),
+        };
+    }
+
+    pp.opcode();
+
+    return match opcode {
+        Opcode::Read | Opcode::Write => {
+            let filename = if let Some(x) = pp.string_with_separator() {x} else {return format!("{:?} filename invalid", opcode)};
+            let mode = match pp.string_with_separator().map(|x| TransferMode::from_str(&x)) {
+                Some(Ok(x))    => format!("{:?}", x),
+                Some(Err(err)) => return format!("{:?} file={} mode invalid; {}", opcode, filename, err),
+                None           => return format!("{:?} file={} mode missing", opcode, filename),
+            };
+            format!("{:?} file={} mode={} {}", opcode, filename, mode, decode_options(&mut pp))
+        },
+        Opcode::Data => {
+            let blocknr = if let Some(x) = pp.number16() {x} else {return "Data block missing".to_string()};
+            format!("Data block={} len={}", blocknr, pp.remaining_bytes().len())
+        },
+        Opcode::Ack => {
+            let blocknr = if let Some(x) = pp.number16() {x} else {return "Ack block missing".to_string()};
+            format!("Ack block={} trailing={}", blocknr, pp.remaining_bytes().len())
+        },
+        _ => {
+            format!("{:?} {}", opcode, decode_options(&mut pp))
+        },
+    };
+}
+
+//returns a message for every capture which decodes differently than expected
+pub fn replay_dir(dir: &Path) -> Vec<String> {
+    let mut packets: Vec<PathBuf> = fs::read_dir(dir)
+        .unwrap_or_else(|err| panic!("cannot read captures {:?}; {}", dir, err))
+        .map(|x| x.unwrap().path())
+        .filter(|x| x.extension().map_or(false, |ext| ext == "bin"))
+        .collect();
+    packets.sort();
+    assert!(!packets.is_empty(), "no captures in {:?}", dir);
+
+    let mut mismatches = Vec::new();
+    for i_packet in packets {
+        let decoded  = decode(&fs::read(&i_packet).unwrap());
+        let expected = fs::read_to_string(i_packet.with_extension("txt")).ok();
+
+        match expected {
+            Some(x) if x.trim_end() == decoded => {},
+            Some(x) => mismatches.push(format!("{:?}\n  expected: {}\n  decoded:  {}", i_packet, x.trim_end(), decoded)),
+            None    => mismatches.push(format!("{:?} has no .txt; decoded: {}", i_packet, decoded)),
+        }
+    }
+
+    return mismatches;
+}
+
+mod tests {
+    use super::*;
+
+    #[test]
+    fn captures_decode() {
+        let mismatches = replay_dir(&captures_dir());
+        assert!(mismatches.is_empty(), "{}", mismatches.join("\n"));
+    }
+}
@@ -1,8 +1,9 @@
 use super::*;
 
 #[derive(Debug)]
-pub enum SendAction<'a> {
-    SendBuffer(&'a Vec<Vec<u8>>),
+pub enum SendAction {
+    SendBuffer,                     //the window is ready; its frames are taken with next_frame
+    Cancel(Vec<u8>),                //ERROR packet for the peer; the transfer is over
     NoOp,
     Timeout,
     End,
@@ -13,14 +14,22 @@ pub struct SendStateMachine<'a>
     windowssize:   usize,
     blksize:       usize,
     bufs:          Vec<Vec<u8>>,
+    burst_pos:     usize,           //index in bufs of the next frame of next_frame
     acked:         u16,
+    rollover:      u16,
     new_acked:     bool,
+    is_ack_seen:   bool,            //an ACK of acked arrived; only a repeated one reports a gap
     reader:        &'a mut dyn std::io::Read,
     is_reader_end: bool,
     is_end:        bool,
     timeout:       OneshotTimer,
+    resend_timeout: Duration,
+    backoff:       BackoffStrategy,
     retry:         usize,
+    retransmits:   usize,
     data_read:     usize,
+    cancel:        Option<CancelToken>,
+    inject_delay:  Option<Duration>,
 }
 
 impl<'a> SendStateMachine<'a> {
@@ -29,23 +38,57 @@ impl<'a> SendStateMachine<'a> {
             windowssize: windowssize,
             blksize: blksize,
             bufs: vec![],
+            burst_pos: 0,
             acked: 0,
+            rollover: 0,
             new_acked: true,
+            is_ack_seen: false,
             reader: reader,
             is_reader_end: false,
             is_end: false,
             timeout: OneshotTimer::new(RESEND_TIMEOUT),
+            resend_timeout: RESEND_TIMEOUT,
+            backoff: BackoffStrategy::Fixed,
             retry: RETRY_COUNT,
+            retransmits: 0,
             data_read: 0,
+            cancel: None,
+            inject_delay: None,
         }
     }
 
-    pub fn fill_level(&self) -> usize {
-        return self.bufs.len();
+    //wait for an ACK before the window is sent again; negotiated with utimeout
+    pub fn set_resend_timeout(&mut self, timeout: Duration) {
+        self.timeout = OneshotTimer::new(timeout);
+        self.resend_timeout = timeout;
+    }
+
+    pub fn set_backoff(&mut self, backoff: BackoffStrategy) {
+        self.backoff = backoff;
+    }
+
+    pub fn set_cancel(&mut self, cancel: Option<CancelToken>) {
+        self.cancel = cancel;
+    }
+
+    //diagnostics; sleep once per DATA block before a window is handed out for sending
+    pub fn set_inject_delay(&mut self, delay: Option<Duration>) {
+        self.inject_delay = delay;
+    }
+
+    fn sleep_inject_delay(&self) {
+        if let Some(delay) = self.inject_delay {
+            std::thread::sleep(delay * self.bufs.len() as u32);
+        }
+    }
+
+    //block number following 65535; 0 or 1
+    pub fn set_rollover(&mut self, rollover: u16) {
+        self.rollover = rollover;
     }
 
-    pub fn send_data(&self) -> &Vec<Vec<u8>> {
-        return &self.bufs;
+    pub fn fill_level(&self) -> usize {
+        return self.bufs.len();
     }
 
     pub fn next(&mut self) -> SendAction {
@@ -55,13 +98,22 @@ impl<'a> SendStateMachine<'a> {
             return SendAction::End;
         }
 
+        if is_cancelled(&self.cancel) {
+            self.is_end = true;
+            let mut buf = Vec::new();
+            ErrorResponse::new_custom(TransferError::Cancelled.to_string()).write_packet(&mut buf);
+            return SendAction::Cancel(buf);
+        }
+
         if !self.is_reader_end {
             self.impl_next();
         };
 
         if self.new_acked {
             self.new_acked  = false;
-            return SendAction::SendBuffer(&self.bufs);
+            self.burst_pos  = 0;
+            self.sleep_inject_delay();
+            return SendAction::SendBuffer;
         }
         
         if self.timeout.is_timeout() {
@@ -70,7 +122,13 @@ impl<'a> SendStateMachine<'a> {
             }
             else {
                 self.retry -= 1;
-                return SendAction::SendBuffer(&self.bufs);
+                self.retransmits += 1;
+                self.sleep_inject_delay();
+                //every retry waits again; the backoff decides how long
+                self.timeout.set_timeout(self.backoff.timeout(self.resend_timeout, RETRY_COUNT - self.retry));
+                self.timeout.explicit_start();
+                self.burst_pos = 0;
+                return SendAction::SendBuffer;
             }
         };
 
@@ -78,21 +136,54 @@ impl<'a> SendStateMachine<'a> {
 
     }
 
+    //frames of the window handed out by SendBuffer one at a time; an ack between two frames
+    //trims the already acked ones and the burst continues with the refilled window
+    //instead of starting over
+    pub fn next_frame(&mut self) -> Option<&Vec<u8>> {
+        if self.new_acked && !self.is_end {
+            self.new_acked = false;
+            if !self.is_reader_end {
+                self.impl_next();
+            }
+        }
+
+        let frame = self.bufs.get(self.burst_pos)?;
+        self.burst_pos += 1;
+        return Some(frame);
+    }
+
+    //the rest of the burst at once; for --batch-sends, which cannot check for acks in between
+    pub fn next_frames(&mut self) -> &[Vec<u8>] {
+        if self.new_acked && !self.is_end {
+            self.new_acked = false;
+            if !self.is_reader_end {
+                self.impl_next();
+            }
+        }
+
+        let start = self.burst_pos.min(self.bufs.len());
+        self.burst_pos = self.bufs.len();
+        return &self.bufs[start..];
+    }
+
     pub fn read_len(&self) -> usize {
         return self.data_read;
     }
 
+    //number of windows sent again after a timeout; over the whole transfer
+    pub fn retransmits(&self) -> usize {
+        return self.retransmits;
+    }
+
     fn impl_next(&mut self) {  
         for i in self.fill_level()..self.windowssize {
             let mut filebuf    = vec![0u8; self.blksize];
-            let mut packet_buf = vec![0u8; MAX_BLOCKSIZE];
+            let mut packet_buf = Vec::with_capacity(self.blksize + DATA_OFFSET);
 
-            let read_len  =  self.reader.read(filebuf.as_mut()).unwrap();   //TODO: make proper error handling
+            let read_len  =  read_block(self.reader, filebuf.as_mut()).unwrap();   //TODO: make proper error handling
 
             //fill header
-            let next_blknum = self.acked
-                .overflowing_add(i as u16).0
-                .overflowing_add(1).0;
+            let next_blknum = block_add(self.acked, i + 1, self.rollover);
 
             PacketBuilder::new(packet_buf.as_mut())
                 .opcode(Opcode::Data)
@@ -103,6 +194,7 @@ impl<'a> SendStateMachine<'a> {
 
             self.data_read += read_len;
 
+            //a file of exactly n blocks gets an additional empty block as end marker
             if read_len < self.blksize {
                 self.is_reader_end = true;
                 break;
@@ -123,17 +215,31 @@ impl<'a> SendStateMachine<'a> {
     }
 
     pub fn ack(&mut self, blknum: u16) {
-        let diff = ring_diff(self.acked, blknum);
+        let diff = block_diff(self.acked, blknum, self.rollover);
 
         if diff > self.windowssize {
             return;
         }
-        
+
+        //rfc1123: a duplicate ACK alone must not resend the window (Sorcerer's Apprentice).
+        //the first ACK 0 acknowledges the OACK or the WRQ; only a repeated ACK after the
+        //whole burst is out reports a gap at the start of the window
+        if diff == 0 {
+            if self.is_ack_seen && self.burst_pos == self.bufs.len() && !self.bufs.is_empty() {
+                self.new_acked = true;
+                self.burst_pos = 0;
+            }
+            self.is_ack_seen = true;
+            return;
+        }
+        self.is_ack_seen = true;
+
         for _ in 0..diff {
             self.new_acked = true;
             self.bufs.remove(0);
-            self.acked = self.acked.overflowing_add(1).0;
+            self.acked = block_add(self.acked, 1, self.rollover);
         }
+        self.burst_pos = self.burst_pos.saturating_sub(diff);
 
         if self.is_reader_end && self.bufs.is_empty() {
             self.is_end = true;
@@ -141,9 +247,24 @@ impl<'a> SendStateMachine<'a> {
 
         if self.new_acked {
             self.timeout.reset();
+            self.timeout.set_timeout(self.resend_timeout);
             self.retry = RETRY_COUNT;
         }
         
     }
 
 }
+
+//a short read of a pipe or socket is not the end of the data; only a short block marks the end
+fn read_block(reader: &mut dyn std::io::Read, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match reader.read(&mut buf[len..]) {
+            Ok(0)    => break,
+            Ok(x)    => len += x,
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        }
+    }
+    return Ok(len);
+}
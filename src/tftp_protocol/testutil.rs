@@ -0,0 +1,473 @@
+use std::collections::VecDeque;
+use std::io::Cursor;
+
+use super::*;
+
+//upper bound of sender polls for a single recv of the receiver
+const PUMP_LIMIT: usize = 10000;
+
+//in-memory datagram channel which drops and reorders packets deterministically
+pub struct LossyChannel {
+    queue:    VecDeque<Vec<u8>>,
+    count:    usize,
+    drop_nth: usize,
+    reorder:  bool,
+    hold_nth: usize,
+    held:     Option<Vec<u8>>,
+}
+
+impl LossyChannel {
+    //drop_nth: every nth packet is lost; 0 means no loss
+    //reorder:  every second packet overtakes its predecessor
+    pub fn new(drop_nth: usize, reorder: bool) -> LossyChannel {
+        LossyChannel {
+            queue:    VecDeque::new(),
+            count:    0,
+            drop_nth: drop_nth,
+            reorder:  reorder,
+            hold_nth: 0,
+            held:     None,
+        }
+    }
+
+    //the first of every hold_nth packets is held back behind the following ones
+    pub fn with_hold_back(mut self, hold_nth: usize) -> LossyChannel {
+        self.hold_nth = hold_nth;
+        return self;
+    }
+
+    pub fn lossless() -> LossyChannel {
+        LossyChannel::new(0, false)
+    }
+
+    pub fn push(&mut self, packet: &[u8]) {
+        self.count += 1;
+
+        if self.drop_nth != 0 && self.count % self.drop_nth == 0 {
+            return;
+        }
+
+        if self.hold_nth > 1 && self.count % self.hold_nth == 1 && self.held.is_none() {
+            self.held = Some(packet.to_vec());
+            return;
+        }
+
+        if self.reorder && self.count % 2 == 0 && !self.queue.is_empty() {
+            self.queue.insert(self.queue.len() - 1, packet.to_vec());
+        } else {
+            self.queue.push_back(packet.to_vec());
+        }
+
+        if self.hold_nth > 1 && self.count % self.hold_nth == 0 {
+            if let Some(held) = self.held.take() {
+                self.queue.push_back(held);
+            }
+        }
+    }
+
+    pub fn pop(&mut self) -> Option<Vec<u8>> {
+        if self.queue.is_empty() {
+            return self.held.take();
+        }
+        return self.queue.pop_front();
+    }
+}
+
+//transfer data from a SendStateMachine to a RecvController without sockets;
+//the sender is polled whenever the receiver waits for the next packet
+pub fn transfer(data: &[u8], blksize: usize, windowsize: usize, data_channel: LossyChannel, ack_channel: LossyChannel) -> Result<Vec<u8>, String> {
+    return transfer_rollover(data, blksize, windowsize, 0, data_channel, ack_channel);
+}
+
+pub fn transfer_rollover(data: &[u8], blksize: usize, windowsize: usize, rollover: u16, data_channel: LossyChannel, ack_channel: LossyChannel) -> Result<Vec<u8>, String> {
+    let mut reader = Cursor::new(data.to_vec());
+    return transfer_reader(&mut reader, blksize, windowsize, rollover, data_channel, ack_channel);
+}
+
+pub fn transfer_reader(reader: &mut dyn std::io::Read, blksize: usize, windowsize: usize, rollover: u16, data_channel: LossyChannel, ack_channel: LossyChannel) -> Result<Vec<u8>, String> {
+    return transfer_counted(reader, blksize, windowsize, rollover, data_channel, ack_channel).map(|(received, _)| received);
+}
+
+//also returns the retransmits of the sender; used by benches/windowsize.rs
+pub fn transfer_counted(reader: &mut dyn std::io::Read, blksize: usize, windowsize: usize, rollover: u16, mut data_channel: LossyChannel, mut ack_channel: LossyChannel) -> Result<(Vec<u8>, usize), String> {
+    let mut sender = SendStateMachine::new(reader, blksize, windowsize);
+    sender.set_resend_timeout(Duration::from_millis(1));
+    sender.set_rollover(rollover);
+
+    let mut received: Vec<u8> = Vec::new();
+
+    let mut receiver = RecvController::new(windowsize, blksize, Box::new(|action| {
+        match action {
+            RecvCallbackArg::WriteSink(data) => {
+                received.extend_from_slice(data);
+            },
+            RecvCallbackArg::SinkEnd => {},
+            RecvCallbackArg::Ack(packet) | RecvCallbackArg::Error(packet) => {
+                ack_channel.push(packet);
+            },
+            RecvCallbackArg::Recv(out_buff, _timeout) => {
+                for _ in 0..PUMP_LIMIT {
+                    while let Some(ack) = ack_channel.pop() {
+                        sender.ack_packet(&ack);
+                    }
+
+                    if let Some(packet) = data_channel.pop() {
+                        out_buff.extend_from_slice(&packet);
+                        return Ok(());
+                    }
+
+                    match sender.next() {
+                        SendAction::SendBuffer => {
+                            while let Some(i_frame) = sender.next_frame() {
+                                data_channel.push(i_frame);
+                            }
+                        },
+                        SendAction::NoOp => std::thread::sleep(Duration::from_micros(100)),
+                        _ => return Ok(()),
+                    }
+                }
+            },
+        }
+        Ok(())
+    }));
+    receiver.set_rollover(rollover);
+    let result  = receiver.run();
+    let written = receiver.written_len();
+    drop(receiver);
+
+    if result.is_ok() && written != received.len() as u64 {
+        return Err(format!("written_len={} but {} bytes received", written, received.len()));
+    }
+
+    let retransmits = sender.retransmits();
+    return result.map(|_| (received, retransmits)).map_err(|x| x.to_string());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn generate_data(size: usize) -> Vec<u8> {
+        return (0..size).map(|x| x as u8).collect();
+    }
+
+    #[test]
+    fn lossless_all_windowsizes() {
+        let data = generate_data(10 * 512 + 100);
+
+        for windowsize in [1, 2, 4, 8, 16] {
+            let received = transfer(&data, 512, windowsize, LossyChannel::lossless(), LossyChannel::lossless());
+            assert_eq!(received, Ok(data.clone()), "windowsize={}", windowsize);
+        }
+    }
+
+    #[test]
+    fn empty_data() {
+        //a single DATA block without payload ends the transfer
+        for windowsize in [1, 4] {
+            let received = transfer(&[], 512, windowsize, LossyChannel::lossless(), LossyChannel::lossless());
+            assert_eq!(received, Ok(vec![]), "windowsize={}", windowsize);
+
+            let received = transfer(&[], 512, windowsize, LossyChannel::new(2, false), LossyChannel::lossless());
+            assert_eq!(received, Ok(vec![]), "windowsize={} with loss", windowsize);
+        }
+    }
+
+    //reads at most 100 bytes at once like a pipe
+    struct ShortReader(Cursor<Vec<u8>>);
+
+    impl std::io::Read for ShortReader {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let len = buf.len().min(100);
+            return self.0.read(&mut buf[..len]);
+        }
+    }
+
+    #[test]
+    fn exact_blocksize_multiple() {
+        //the last full block is followed by an empty one
+        for (blocks, windowsize) in [(1, 1), (4, 1), (4, 2), (4, 4), (3, 4)] {
+            let data = generate_data(blocks * 512);
+            let received = transfer(&data, 512, windowsize, LossyChannel::lossless(), LossyChannel::lossless());
+            assert_eq!(received, Ok(data.clone()), "blocks={} windowsize={}", blocks, windowsize);
+        }
+    }
+
+    #[test]
+    fn short_block_mid_window() {
+        //blocks 1..4 fill the first window; 5, 6 and the short 7 are slot 0..2 of the second
+        let data = generate_data(6 * 512 + 100);
+        let blocknr = |frame: &Vec<u8>| u16::from_be_bytes([frame[2], frame[3]]);
+
+        let mut reader = Cursor::new(data.clone());
+        let mut sender = SendStateMachine::new(&mut reader, 512, 4);
+        let mut frames = Vec::new();
+
+        assert!(matches!(sender.next(), SendAction::SendBuffer));
+        while let Some(frame) = sender.next_frame() {
+            frames.push(frame.clone());
+        }
+        sender.ack(4);
+        assert!(matches!(sender.next(), SendAction::SendBuffer));
+        while let Some(frame) = sender.next_frame() {
+            frames.push(frame.clone());
+        }
+        assert_eq!(frames.iter().map(blocknr).collect::<Vec<u16>>(), (1..=7).collect::<Vec<u16>>());
+        assert_eq!(frames[6].len(), DATA_OFFSET + 100);
+        sender.ack(7);
+        assert!(matches!(sender.next(), SendAction::End));
+
+        //the receiver ends with the short block; no further recv, no timeout
+        let mut incoming: VecDeque<Vec<u8>> = frames.into_iter().collect();
+        let mut received = Vec::new();
+        let mut acks     = Vec::new();
+        let mut recvs    = 0;
+        let mut receiver = RecvController::new(4, 512, Box::new(|action| {
+            match action {
+                RecvCallbackArg::WriteSink(data) => received.extend_from_slice(data),
+                RecvCallbackArg::Ack(packet)     => acks.push(u16::from_be_bytes([packet[2], packet[3]])),
+                RecvCallbackArg::Recv(out_buff, _) => {
+                    recvs += 1;
+                    if let Some(packet) = incoming.pop_front() {
+                        out_buff.extend_from_slice(&packet);
+                    }
+                },
+                _ => {},
+            }
+            Ok(())
+        }));
+        assert!(receiver.run().is_ok());
+        drop(receiver);
+
+        assert_eq!(received, data);
+        assert_eq!(acks, vec![4, 7]);
+        assert_eq!(recvs, 7);
+
+        //the short block in every slot of the last window; never a retransmit
+        for extra_blocks in 0..4 {
+            let data = generate_data((8 + extra_blocks) * 512 + 100);
+            let mut reader = Cursor::new(data.clone());
+            let received = transfer_counted(&mut reader, 512, 4, 0, LossyChannel::lossless(), LossyChannel::lossless());
+            assert_eq!(received, Ok((data, 0)), "short block at slot {}", extra_blocks);
+        }
+    }
+
+    #[test]
+    fn short_reads() {
+        for size in [0, 512, 3 * 512, 3 * 512 + 17] {
+            let data = generate_data(size);
+            let mut reader = ShortReader(Cursor::new(data.clone()));
+            let received = transfer_reader(&mut reader, 512, 2, 0, LossyChannel::lossless(), LossyChannel::lossless());
+            assert_eq!(received, Ok(data), "size={}", size);
+        }
+    }
+
+    #[test]
+    fn data_loss_all_windowsizes() {
+        let data = generate_data(10 * 512 + 100);
+
+        for windowsize in [1, 2, 4, 8] {
+            let received = transfer(&data, 512, windowsize, LossyChannel::new(5, false), LossyChannel::lossless());
+            assert_eq!(received, Ok(data.clone()), "windowsize={}", windowsize);
+        }
+    }
+
+    #[test]
+    fn data_reorder() {
+        let data = generate_data(10 * 512 + 100);
+
+        for windowsize in [2, 4, 8] {
+            let received = transfer(&data, 512, windowsize, LossyChannel::new(0, true), LossyChannel::lossless());
+            assert_eq!(received, Ok(data.clone()), "windowsize={}", windowsize);
+        }
+    }
+
+    #[test]
+    fn data_reorder_heavy() {
+        let data = generate_data(40 * 512 + 100);
+
+        for windowsize in [4, 8, 16] {
+            let received = transfer(&data, 512, windowsize, LossyChannel::new(0, false).with_hold_back(windowsize), LossyChannel::lossless());
+            assert_eq!(received, Ok(data.clone()), "windowsize={}", windowsize);
+
+            let received = transfer(&data, 512, windowsize, LossyChannel::new(7, true).with_hold_back(windowsize / 2), LossyChannel::new(5, false));
+            assert_eq!(received, Ok(data.clone()), "windowsize={} with loss", windowsize);
+        }
+    }
+
+    #[test]
+    fn ack_of_oack_sends_window_once() {
+        let data = generate_data(6 * 512 + 100);
+        let blocknr = |frame: &Vec<u8>| u16::from_be_bytes([frame[2], frame[3]]);
+
+        let mut reader = Cursor::new(data);
+        let mut sender = SendStateMachine::new(&mut reader, 512, 4);
+        let mut frames = Vec::new();
+
+        //OACK -> ACK 0; the ACK arrives after the first burst is out
+        assert!(matches!(sender.next(), SendAction::SendBuffer));
+        while let Some(frame) = sender.next_frame() {
+            frames.push(blocknr(frame));
+        }
+        sender.ack(0);
+        assert!(matches!(sender.next(), SendAction::NoOp));
+
+        sender.ack(4);
+        assert!(matches!(sender.next(), SendAction::SendBuffer));
+        while let Some(frame) = sender.next_frame() {
+            frames.push(blocknr(frame));
+        }
+        assert_eq!(frames, (1..=7).collect::<Vec<u16>>());
+
+        //a repeated ACK 4 after the whole burst reports the lost block 5
+        sender.ack(4);
+        assert!(matches!(sender.next(), SendAction::SendBuffer));
+        assert_eq!(sender.next_frame().map(blocknr), Some(5));
+    }
+
+    #[test]
+    fn gap_acked_before_timeout() {
+        let data_packet = |blocknr: u16| {
+            let mut packet = vec![0, 3];
+            packet.extend_from_slice(&blocknr.to_be_bytes());
+            packet.extend_from_slice(&[0u8; 512]);
+            packet
+        };
+        //blocks 2..4 of the first window, then block 1 and 6..8 of the next one
+        let mut incoming: VecDeque<Vec<u8>> = [2, 3, 4, 1, 6, 7, 8].iter().map(|x| data_packet(*x)).collect();
+        let mut acks: Vec<u16> = Vec::new();
+
+        let mut receiver = RecvController::new(4, 512, Box::new(|action| {
+            match action {
+                RecvCallbackArg::Ack(packet) => acks.push(u16::from_be_bytes([packet[2], packet[3]])),
+                RecvCallbackArg::Recv(out_buff, _) => {
+                    if let Some(packet) = incoming.pop_front() {
+                        out_buff.extend_from_slice(&packet);
+                    }
+                },
+                _ => {},
+            }
+            Ok(())
+        }));
+        receiver.set_timeout(Duration::from_millis(1));
+        let _ = receiver.run();
+        drop(receiver);
+
+        //the gap at block 1 is reported by ack 0; the gap at block 5 by ack 4; no timeout in between
+        assert_eq!(&acks[..3], &[0, 4, 4]);
+    }
+
+    #[test]
+    fn block_number_rollover() {
+        //more than 65535 blocks of 1 byte
+        let data = generate_data(70000);
+
+        for rollover in [0, 1] {
+            let received = transfer_rollover(&data, 1, 16, rollover, LossyChannel::lossless(), LossyChannel::lossless());
+            assert_eq!(received, Ok(data.clone()), "rollover={}", rollover);
+        }
+    }
+
+    #[test]
+    fn retransmits_counted() {
+        let data = generate_data(100);
+        let mut reader = Cursor::new(data);
+        let mut sender = SendStateMachine::new(&mut reader, 512, 1);
+        sender.set_resend_timeout(Duration::from_millis(1));
+
+        //no ack ever arrives
+        for _ in 0..PUMP_LIMIT {
+            match sender.next() {
+                SendAction::Timeout => break,
+                _ => std::thread::sleep(Duration::from_micros(500)),
+            }
+        }
+
+        assert_eq!(sender.retransmits(), RETRY_COUNT);
+    }
+
+    #[test]
+    fn send_cancelled() {
+        let data = generate_data(10 * 512);
+        let mut reader = Cursor::new(data);
+        let mut sender = SendStateMachine::new(&mut reader, 512, 1);
+        let cancel: CancelToken = Arc::new(AtomicBool::new(false));
+        sender.set_cancel(Some(cancel.clone()));
+
+        assert!(matches!(sender.next(), SendAction::SendBuffer));
+
+        cancel.store(true, Ordering::Relaxed);
+        match sender.next() {
+            SendAction::Cancel(packet) => assert_eq!(packet, b"\x00\x05\x00\x00cancelled\x00"),
+            other => panic!("expected Cancel; got {:?}", other),
+        }
+        assert!(matches!(sender.next(), SendAction::End));
+    }
+
+    #[test]
+    fn recv_cancelled() {
+        let cancel: CancelToken = Arc::new(AtomicBool::new(true));
+        let mut sent: Vec<Vec<u8>> = Vec::new();
+
+        let mut receiver = RecvController::new(1, 512, Box::new(|action| {
+            if let RecvCallbackArg::Error(packet) = action {
+                sent.push(packet.to_vec());
+            }
+            Ok(())
+        }));
+        receiver.set_cancel(Some(cancel));
+        let result = receiver.run();
+        drop(receiver);
+
+        assert!(matches!(result, Err(TransferError::Cancelled)));
+        assert_eq!(sent, vec![b"\x00\x05\x00\x00cancelled\x00".to_vec()]);
+    }
+
+    #[test]
+    fn inject_delay_per_block() {
+        let data = generate_data(3 * 512 + 100);
+        let mut reader = Cursor::new(data);
+        let mut sender = SendStateMachine::new(&mut reader, 512, 4);
+        sender.set_inject_delay(Some(Duration::from_millis(10)));
+
+        //one delay for each of the 4 blocks of the window
+        let start = Instant::now();
+        assert!(matches!(sender.next(), SendAction::SendBuffer));
+        assert_eq!(sender.fill_level(), 4);
+        assert!(start.elapsed() >= Duration::from_millis(40));
+    }
+
+    #[test]
+    fn early_ack_trims_burst() {
+        let data = generate_data(20 * 512);
+        let mut reader = Cursor::new(data);
+        let mut sender = SendStateMachine::new(&mut reader, 512, 8);
+        let blocknr = |frame: &Vec<u8>| u16::from_be_bytes([frame[2], frame[3]]);
+
+        assert!(matches!(sender.next(), SendAction::SendBuffer));
+        let mut sent = Vec::new();
+        for _ in 0..3 {
+            sent.push(blocknr(sender.next_frame().unwrap()));
+        }
+
+        //blocks 1 and 2 are acked while the window is sent; the burst goes on with the refilled window
+        sender.ack(2);
+        while let Some(frame) = sender.next_frame() {
+            sent.push(blocknr(frame));
+        }
+        assert_eq!(sent, (1..=10).collect::<Vec<u16>>());
+
+        //nothing is sent twice
+        assert!(matches!(sender.next(), SendAction::NoOp));
+    }
+
+    #[test]
+    fn ack_loss() {
+        let data = generate_data(10 * 512 + 100);
+
+        for windowsize in [1, 2, 4, 8] {
+            let received = transfer(&data, 512, windowsize, LossyChannel::lossless(), LossyChannel::new(3, false));
+            assert_eq!(received, Ok(data.clone()), "windowsize={}", windowsize);
+        }
+    }
+}
@@ -1,5 +1,6 @@
 use std::{fmt::write, os::windows::process};
 use std::process::exit;
+use std::sync::RwLock;
 
 #[derive(Copy, Clone, Debug)]
 pub enum LogType {
@@ -23,16 +24,65 @@ impl std::fmt::Display for LogType {
 }
 
 
+//destination of all messages of the macros below; an application which embeds the
+//client or server installs its own with set_logger e.g. to forward them to log or tracing
+pub trait Logger: Send + Sync {
+    fn log(&self, log_type: LogType, msg: &str);
+}
+
+//used until set_logger is called; errors go to stderr, everything else to stdout
+#[cfg_attr(feature = "log", allow(dead_code))]
+pub struct StdLogger;
+
+impl Logger for StdLogger {
+    fn log(&self, log_type: LogType, msg: &str) {
+        let full_msg = format!("{:<10}: {} ", log_type, msg);
+
+        match log_type {
+            LogType::Error => {
+                eprintln!("{}", full_msg);
+            },
+            _     => {
+                println!("{}", full_msg)
+            } ,
+        }
+    }
+}
+
+//feature log; used until set_logger is called. The application initializes the log backend e.g. env_logger
+#[cfg(feature = "log")]
+pub struct LogCrateLogger;
+
+#[cfg(feature = "log")]
+impl Logger for LogCrateLogger {
+    fn log(&self, log_type: LogType, msg: &str) {
+        match log_type {
+            LogType::Error   => log::error!(target: "tftp", "{}", msg),
+            LogType::Warning => log::warn!(target: "tftp", "{}", msg),
+            LogType::Info    => log::info!(target: "tftp", "{}", msg),
+            LogType::Debug   => log::debug!(target: "tftp", "{}", msg),
+        }
+    }
+}
+
+#[cfg(feature = "log")]
+const DEFAULT_LOGGER: LogCrateLogger = LogCrateLogger;
+#[cfg(not(feature = "log"))]
+const DEFAULT_LOGGER: StdLogger = StdLogger;
+
+static LOGGER: RwLock<Option<Box<dyn Logger>>> = RwLock::new(None);
+
+//the binary keeps the default
+#[allow(dead_code)]
+pub fn set_logger(logger: Box<dyn Logger>) {
+    *LOGGER.write().unwrap_or_else(|x| x.into_inner()) = Some(logger);
+}
+
 pub fn out(log_type: LogType, msg: &str) {
-    let full_msg = format!("{:<10}: {} ", log_type, msg);
-
-    match log_type {
-        LogType::Error => {
-            eprintln!("{}", full_msg);
-        },
-        _     => {
-            println!("{}", full_msg)
-        } ,
+    let logger = LOGGER.read().unwrap_or_else(|x| x.into_inner());
+    match logger.as_ref() {
+        Some(x) => x.log(log_type, msg),
+        None    => DEFAULT_LOGGER.log(log_type, msg),
     }
 }
 
@@ -64,3 +114,30 @@ pub(crate) use error;
 pub(crate) use warning;
 pub(crate) use info;
 pub(crate) use debug;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use crate::tlog;
+
+    struct CaptureLogger(Arc<Mutex<Vec<String>>>);
+
+    impl Logger for CaptureLogger {
+        fn log(&self, log_type: LogType, msg: &str) {
+            self.0.lock().unwrap().push(format!("{} {}", log_type, msg));
+        }
+    }
+
+    #[test]
+    fn macros_use_installed_logger() {
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        set_logger(Box::new(CaptureLogger(lines.clone())));
+
+        //other tests running at the same time may log too
+        tlog::warning!("captured {}", 42);
+        set_logger(Box::new(StdLogger));
+
+        assert!(lines.lock().unwrap().contains(&"Warning captured 42".to_string()));
+    }
+}
@@ -25,6 +25,16 @@ fn download_mult_blocksize() -> Result<(), Box<dyn std::error::Error>> {
     tftp_transfer(&generate_data(3*512), true, 55003)
 }
 
+#[test]
+fn download_empty() -> Result<(), Box<dyn std::error::Error>> {
+    tftp_transfer(&[], true, 55016)
+}
+
+#[test]
+fn upload_empty() -> Result<(), Box<dyn std::error::Error>> {
+    tftp_transfer(&[], false, 55017)
+}
+
 #[test]
 fn upload_smaller_blocksize() -> Result<(), Box<dyn std::error::Error>> {
     tftp_transfer(&[0,1,2,3], false, 55004)
@@ -128,4 +138,893 @@ fn compare(l: &Path, r: &Path) {
     let mut is_same = *l == *r;
 
     assert!(is_same);
-}
\ No newline at end of file
+}
+#[test]
+fn second_request_during_transfer() -> Result<(), Box<dyn std::error::Error>> {
+    let port = 55007;
+    let server_root = assert_fs::TempDir::new().unwrap().into_persistent();
+    server_root.child("download.bin").write_binary(&generate_data(3*512)).unwrap();
+
+    let cmd_path = Command::cargo_bin("tftp").unwrap().get_program().to_os_string();
+    let mut server = std::process::Command::new(cmd_path)
+        .arg("server")
+        .arg("--rootdir").arg(server_root.path())
+        .arg("--port").arg(format!("{}",port))
+        .spawn().unwrap();
+
+    thread::sleep(Duration::from_secs(2));
+
+    //first transfer stays in progress because block 1 is never acknowledged
+    let (first, first_tid) = read_request(port);
+    let (second, second_tid) = read_request(port);
+
+    let _ = server.kill();
+    let _ = server.wait();
+
+    assert_ne!(first_tid.port(), port);
+    assert_ne!(second_tid.port(), port);
+    assert_ne!(first_tid, second_tid);
+    assert_eq!(&first[0..4], &[0,3,0,1]);
+    assert_eq!(&second[0..4], &[0,3,0,1]);
+
+    Ok(())
+}
+
+//send a RRQ to the server port and return the first DATA packet and its source
+fn read_request(port: u16) -> (Vec<u8>, std::net::SocketAddr) {
+    let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    socket.send_to(b"\x00\x01download.bin\x00octet\x00", ("127.0.0.1", port)).unwrap();
+
+    let mut buf = vec![0; 1024];
+    let (size, src) = socket.recv_from(&mut buf).unwrap();
+    buf.truncate(size);
+
+    return (buf, src);
+}
+
+#[test]
+fn oversized_windowsize_clamped() -> Result<(), Box<dyn std::error::Error>> {
+    let port = 55008;
+    let server_root = assert_fs::TempDir::new().unwrap().into_persistent();
+    server_root.child("download.bin").write_binary(&generate_data(3*512)).unwrap();
+
+    let cmd_path = Command::cargo_bin("tftp").unwrap().get_program().to_os_string();
+    let mut server = std::process::Command::new(cmd_path)
+        .arg("server")
+        .arg("--rootdir").arg(server_root.path())
+        .arg("--port").arg(format!("{}",port))
+        .arg("--max-windowsize").arg("16")
+        .spawn().unwrap();
+
+    thread::sleep(Duration::from_secs(2));
+
+    let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    socket.send_to(b"\x00\x01download.bin\x00octet\x00windowsize\x0065535\x00", ("127.0.0.1", port)).unwrap();
+
+    let mut buf = vec![0; 1024];
+    let (size, _) = socket.recv_from(&mut buf).unwrap();
+    buf.truncate(size);
+
+    let _ = server.kill();
+    let _ = server.wait();
+
+    assert_eq!(buf, b"\x00\x06windowsize\x0016\x00");
+
+    Ok(())
+}
+
+#[test]
+fn no_options_request() -> Result<(), Box<dyn std::error::Error>> {
+    let port = 55009;
+    let client_root = assert_fs::TempDir::new().unwrap().into_persistent();
+
+    //stands in for a legacy server; only the request is inspected
+    let legacy_server = std::net::UdpSocket::bind(("127.0.0.1", port)).unwrap();
+    legacy_server.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+    let cmd_path = Command::cargo_bin("tftp").unwrap().get_program().to_os_string();
+    let mut client = std::process::Command::new(cmd_path)
+        .current_dir(client_root.path())
+        .arg("client")
+        .arg("--remote").arg(format!("127.0.0.1:{}", port))
+        .arg("--download").arg("download.bin")
+        .arg("--no-options")
+        .spawn().unwrap();
+
+    let mut buf = vec![0; 1024];
+    let (size, _) = legacy_server.recv_from(&mut buf).unwrap();
+    buf.truncate(size);
+
+    let _ = client.kill();
+    let _ = client.wait();
+
+    assert_eq!(buf, b"\x00\x01download.bin\x00octet\x00");
+
+    Ok(())
+}
+
+#[test]
+fn ignored_options_fall_back() -> Result<(), Box<dyn std::error::Error>> {
+    let port = 55010;
+    let client_root = assert_fs::TempDir::new().unwrap().into_persistent();
+    let data = generate_data(512 + 100);
+
+    //legacy server which answers a request with options directly with DATA 1
+    let legacy_server = std::net::UdpSocket::bind(("127.0.0.1", port)).unwrap();
+    legacy_server.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+    let cmd_path = Command::cargo_bin("tftp").unwrap().get_program().to_os_string();
+    let mut client = std::process::Command::new(cmd_path)
+        .current_dir(client_root.path())
+        .arg("client")
+        .arg("--remote").arg(format!("127.0.0.1:{}", port))
+        .arg("--download").arg("download.bin")
+        .arg("--blksize").arg("1024")
+        .spawn().unwrap();
+
+    let mut buf = vec![0; 1024];
+    let (_, client_addr) = legacy_server.recv_from(&mut buf).unwrap();
+
+    for (i_block, i_chunk) in data.chunks(512).enumerate() {
+        let block = (i_block + 1) as u16;
+        let mut packet = vec![0, 3];
+        packet.extend_from_slice(&block.to_be_bytes());
+        packet.extend_from_slice(i_chunk);
+        legacy_server.send_to(&packet, client_addr).unwrap();
+
+        let (size, _) = legacy_server.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..size], &[0, 4, (block >> 8) as u8, block as u8]);
+    }
+
+    assert!(client.wait().unwrap().success());
+    assert_eq!(fs::read(client_root.path().join("download.bin")).unwrap(), data);
+
+    Ok(())
+}
+
+#[test]
+fn download_no_clobber() -> Result<(), Box<dyn std::error::Error>> {
+    let client_root = assert_fs::TempDir::new().unwrap().into_persistent();
+    client_root.child("download.bin").write_binary(b"local").unwrap();
+
+    //fails before anything is sent; no server needed
+    let output = Command::cargo_bin("tftp").unwrap()
+        .current_dir(client_root.path())
+        .arg("client")
+        .arg("--remote").arg("127.0.0.1:55011")
+        .arg("--download").arg("download.bin")
+        .output().unwrap();
+
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("already exists"));
+
+    assert_eq!(fs::read(client_root.path().join("download.bin")).unwrap(), b"local");
+
+    Ok(())
+}
+
+//server with the given write mode and two files: existing.bin on the server and upload.bin on the client
+fn write_mode_setup(port: u16, writemode: &str) -> (std::process::Child, assert_fs::TempDir, assert_fs::TempDir) {
+    let server_root = assert_fs::TempDir::new().unwrap().into_persistent();
+    let client_root = assert_fs::TempDir::new().unwrap().into_persistent();
+    server_root.child("existing.bin").write_binary(b"existing").unwrap();
+    client_root.child("upload.bin").write_binary(&generate_data(3*512 + 10)).unwrap();
+
+    let cmd_path = Command::cargo_bin("tftp").unwrap().get_program().to_os_string();
+    let server = std::process::Command::new(cmd_path)
+        .arg("server")
+        .arg("--rootdir").arg(server_root.path())
+        .arg("--port").arg(format!("{}",port))
+        .arg("--writemode").arg(writemode)
+        .spawn().unwrap();
+
+    thread::sleep(Duration::from_secs(2));
+
+    return (server, server_root, client_root);
+}
+
+//returns the exit code of the client
+fn upload_as(port: u16, client_root: &Path, remote: &str) -> i32 {
+    let output = Command::cargo_bin("tftp").unwrap()
+        .current_dir(client_root)
+        .arg("client")
+        .arg("--remote").arg(format!("127.0.0.1:{}", port))
+        .arg("--upload").arg("upload.bin").arg(remote)
+        .output().unwrap();
+
+    return output.status.code().unwrap();
+}
+
+#[test]
+fn write_mode_disabled() -> Result<(), Box<dyn std::error::Error>> {
+    let port = 55012;
+    let (mut server, server_root, client_root) = write_mode_setup(port, "disabled");
+
+    let new_code      = upload_as(port, client_root.path(), "new.bin");
+    let existing_code = upload_as(port, client_root.path(), "existing.bin");
+
+    let _ = server.kill();
+    let _ = server.wait();
+
+    //exit code 4: access violation
+    assert_eq!(new_code, 4);
+    assert_eq!(existing_code, 4);
+    assert!(!server_root.path().join("new.bin").exists());
+    assert_eq!(fs::read(server_root.path().join("existing.bin")).unwrap(), b"existing");
+
+    Ok(())
+}
+
+#[test]
+fn write_mode_new() -> Result<(), Box<dyn std::error::Error>> {
+    let port = 55013;
+    let (mut server, server_root, client_root) = write_mode_setup(port, "new");
+
+    let existing_code = upload_as(port, client_root.path(), "existing.bin");
+    let new_code      = upload_as(port, client_root.path(), "new.bin");
+
+    let _ = server.kill();
+    let _ = server.wait();
+
+    //exit code 6: file already exists
+    assert_eq!(existing_code, 6);
+    assert_eq!(fs::read(server_root.path().join("existing.bin")).unwrap(), b"existing");
+    assert_eq!(new_code, 0);
+    compare(&client_root.path().join("upload.bin"), &server_root.path().join("new.bin"));
+
+    Ok(())
+}
+
+#[test]
+fn write_mode_overwrite() -> Result<(), Box<dyn std::error::Error>> {
+    let port = 55014;
+    let (mut server, server_root, client_root) = write_mode_setup(port, "overwrite");
+
+    let existing_code = upload_as(port, client_root.path(), "existing.bin");
+
+    let _ = server.kill();
+    let _ = server.wait();
+
+    assert_eq!(existing_code, 0);
+    compare(&client_root.path().join("upload.bin"), &server_root.path().join("existing.bin"));
+
+    Ok(())
+}
+
+//receives the remaining blocks of a transfer started with read_request
+fn finish_read(socket: &std::net::UdpSocket, first: Vec<u8>, tid: std::net::SocketAddr) -> Vec<u8> {
+    let mut data   = Vec::new();
+    let mut packet = first;
+
+    loop {
+        data.extend_from_slice(&packet[4..]);
+        socket.send_to(&[0, 4, packet[2], packet[3]], tid).unwrap();
+        if packet.len() < 4 + 512 {
+            return data;
+        }
+
+        let mut buf = vec![0; 1024];
+        let (size, _) = socket.recv_from(&mut buf).unwrap();
+        buf.truncate(size);
+        packet = buf;
+    }
+}
+
+#[test]
+fn concurrent_reads_block_write() -> Result<(), Box<dyn std::error::Error>> {
+    let port = 55015;
+    let server_root = assert_fs::TempDir::new().unwrap().into_persistent();
+    let client_root = assert_fs::TempDir::new().unwrap().into_persistent();
+    let data = generate_data(3*512 + 10);
+    server_root.child("download.bin").write_binary(&data).unwrap();
+    client_root.child("download.bin").write_binary(b"new content").unwrap();
+
+    let cmd_path = Command::cargo_bin("tftp").unwrap().get_program().to_os_string();
+    let mut server = std::process::Command::new(cmd_path)
+        .arg("server")
+        .arg("--rootdir").arg(server_root.path())
+        .arg("--port").arg(format!("{}",port))
+        .arg("--writemode").arg("overwrite")
+        .spawn().unwrap();
+
+    thread::sleep(Duration::from_secs(2));
+
+    //both reads hold their lock until block 1 is acknowledged
+    let request = |socket: &std::net::UdpSocket| {
+        socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        socket.send_to(b"\x00\x01download.bin\x00octet\x00", ("127.0.0.1", port)).unwrap();
+        let mut buf = vec![0; 1024];
+        let (size, src) = socket.recv_from(&mut buf).unwrap();
+        buf.truncate(size);
+        (buf, src)
+    };
+    let first_socket  = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let second_socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let (first, first_tid)   = request(&first_socket);
+    let (second, second_tid) = request(&second_socket);
+
+    let upload = Command::cargo_bin("tftp").unwrap()
+        .current_dir(client_root.path())
+        .arg("client")
+        .arg("--remote").arg(format!("127.0.0.1:{}", port))
+        .arg("--upload").arg("download.bin")
+        .output().unwrap();
+
+    let first_data  = finish_read(&first_socket, first, first_tid);
+    let second_data = finish_read(&second_socket, second, second_tid);
+
+    let _ = server.kill();
+    let _ = server.wait();
+
+    assert!(!upload.status.success());
+    assert!(String::from_utf8_lossy(&upload.stderr).contains("file is locked"));
+    assert_eq!(first_data, data);
+    assert_eq!(second_data, data);
+    assert_eq!(fs::read(server_root.path().join("download.bin")).unwrap(), data);
+
+    Ok(())
+}
+
+#[test]
+fn server_settings_from_env() -> Result<(), Box<dyn std::error::Error>> {
+    let port = 55018;
+    let server_root = assert_fs::TempDir::new().unwrap().into_persistent();
+    server_root.child("download.bin").write_binary(&[0,1,2,3]).unwrap();
+
+    let cmd_path = Command::cargo_bin("tftp").unwrap().get_program().to_os_string();
+    let mut server = std::process::Command::new(cmd_path)
+        .arg("server")
+        .env("TFTP_ROOT", server_root.path())
+        .env("TFTP_PORT", format!("{}", port))
+        .spawn().unwrap();
+
+    thread::sleep(Duration::from_secs(2));
+
+    let (buf, _) = read_request(port);
+
+    let _ = server.kill();
+    let _ = server.wait();
+
+    assert_eq!(buf, b"\x00\x03\x00\x01\x00\x01\x02\x03");
+
+    Ok(())
+}
+
+#[test]
+fn upload_stdin() -> Result<(), Box<dyn std::error::Error>> {
+    use std::io::Write;
+
+    let port = 55019;
+    let (mut server, server_root, client_root) = write_mode_setup(port, "new");
+    let data = generate_data(3*512 + 10);
+
+    let mut client = Command::cargo_bin("tftp").unwrap()
+        .current_dir(client_root.path())
+        .arg("client")
+        .arg("--remote").arg(format!("127.0.0.1:{}", port))
+        .arg("--upload").arg("-").arg("piped.bin")
+        .stdin(std::process::Stdio::piped())
+        .spawn().unwrap();
+
+    //written in pieces which don't match the blocksize
+    let mut stdin = client.stdin.take().unwrap();
+    for i_chunk in data.chunks(300) {
+        stdin.write_all(i_chunk).unwrap();
+        thread::sleep(Duration::from_millis(10));
+    }
+    drop(stdin);
+    let status = client.wait().unwrap();
+
+    let _ = server.kill();
+    let _ = server.wait();
+
+    assert_eq!(status.code(), Some(0));
+    assert_eq!(fs::read(server_root.path().join("piped.bin")).unwrap(), data);
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn daemonize_writes_pid_and_log() -> Result<(), Box<dyn std::error::Error>> {
+    let port = 55020;
+    let server_root = assert_fs::TempDir::new().unwrap().into_persistent();
+    server_root.child("download.bin").write_binary(&[0,1,2,3]).unwrap();
+    let pid_file = server_root.path().join("tftp.pid");
+    let log_file = server_root.path().join("tftp.log");
+
+    //returns as soon as the daemon is detached
+    let output = Command::cargo_bin("tftp").unwrap()
+        .arg("server")
+        .arg("--rootdir").arg(server_root.path())
+        .arg("--port").arg(format!("{}", port))
+        .arg("--daemonize")
+        .arg("--pid-file").arg(&pid_file)
+        .arg("--log-file").arg(&log_file)
+        .output().unwrap();
+    assert_eq!(output.status.code(), Some(0));
+
+    thread::sleep(Duration::from_secs(1));
+    let (buf, _) = read_request(port);
+    thread::sleep(Duration::from_millis(200));
+
+    let pid = fs::read_to_string(&pid_file).unwrap();
+    let _ = std::process::Command::new("kill").arg(pid.trim()).status();
+
+    assert_eq!(buf, b"\x00\x03\x00\x01\x00\x01\x02\x03");
+    assert!(fs::read_to_string(&log_file).unwrap().contains("Read download.bin"));
+
+    Ok(())
+}
+
+#[test]
+fn health_port_answers() -> Result<(), Box<dyn std::error::Error>> {
+    use std::io::{Read, Write};
+
+    let port = 55021;
+    let health_port = 55022;
+    let server_root = assert_fs::TempDir::new().unwrap().into_persistent();
+
+    let cmd_path = Command::cargo_bin("tftp").unwrap().get_program().to_os_string();
+    let mut server = std::process::Command::new(cmd_path)
+        .arg("server")
+        .arg("--rootdir").arg(server_root.path())
+        .arg("--port").arg(format!("{}", port))
+        .arg("--health-port").arg(format!("{}", health_port))
+        .spawn().unwrap();
+
+    thread::sleep(Duration::from_secs(2));
+
+    let mut stream = std::net::TcpStream::connect(("127.0.0.1", health_port)).unwrap();
+    stream.write_all(b"GET /healthz HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+
+    let _ = server.kill();
+    let _ = server.wait();
+
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "response={}", response);
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn upload_into_fifo() -> Result<(), Box<dyn std::error::Error>> {
+    use std::io::Read;
+
+    let port = 55023;
+    let server_root = assert_fs::TempDir::new().unwrap().into_persistent();
+    let client_root = assert_fs::TempDir::new().unwrap().into_persistent();
+    let data = generate_data(3*512 + 10);
+    client_root.child("upload.bin").write_binary(&data).unwrap();
+
+    let fifo = server_root.path().join("ingest");
+    assert!(std::process::Command::new("mkfifo").arg(&fifo).status().unwrap().success());
+
+    let cmd_path = Command::cargo_bin("tftp").unwrap().get_program().to_os_string();
+    let mut server = std::process::Command::new(cmd_path)
+        .arg("server")
+        .arg("--rootdir").arg(server_root.path())
+        .arg("--port").arg(format!("{}", port))
+        .arg("--allow-special-files")
+        .spawn().unwrap();
+
+    //the server only writes into a fifo which has a reader
+    let reader = thread::spawn(move || {
+        let mut received = Vec::new();
+        File::open(&fifo).unwrap().read_to_end(&mut received).unwrap();
+        received
+    });
+
+    thread::sleep(Duration::from_secs(2));
+    let code = upload_as(port, client_root.path(), "ingest");
+    let received = reader.join().unwrap();
+
+    let _ = server.kill();
+    let _ = server.wait();
+
+    assert_eq!(code, 0);
+    assert_eq!(received, data);
+
+    Ok(())
+}
+
+#[test]
+fn print_oack_no_transfer() -> Result<(), Box<dyn std::error::Error>> {
+    let port = 55024;
+    let server_root = assert_fs::TempDir::new().unwrap().into_persistent();
+    let client_root = assert_fs::TempDir::new().unwrap().into_persistent();
+    server_root.child("download.bin").write_binary(&generate_data(4096)).unwrap();
+
+    let cmd_path = Command::cargo_bin("tftp").unwrap().get_program().to_os_string();
+    let mut server = std::process::Command::new(cmd_path)
+        .arg("server")
+        .arg("--rootdir").arg(server_root.path())
+        .arg("--port").arg(format!("{}", port))
+        .spawn().unwrap();
+
+    thread::sleep(Duration::from_secs(2));
+
+    let output = Command::cargo_bin("tftp").unwrap()
+        .current_dir(client_root.path())
+        .arg("client")
+        .arg("--remote").arg(format!("127.0.0.1:{}", port))
+        .arg("--download").arg("download.bin")
+        .arg("--blksize").arg("1024")
+        .arg("--print-oack")
+        .output().unwrap();
+
+    let _ = server.kill();
+    let _ = server.wait();
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(output.status.code(), Some(0));
+    assert!(stdout.contains("oack blksize = 1024"), "{}", stdout);
+    assert!(!client_root.path().join("download.bin").exists());
+
+    Ok(())
+}
+
+#[test]
+fn probe_reports_rtt() -> Result<(), Box<dyn std::error::Error>> {
+    let port = 55029;
+    let server_root = assert_fs::TempDir::new().unwrap().into_persistent();
+
+    let probe = |port: u16| {
+        Command::cargo_bin("tftp").unwrap()
+            .arg("client")
+            .arg("--remote").arg(format!("127.0.0.1:{}", port))
+            .arg("--probe")
+            .output().unwrap()
+    };
+
+    //nothing listens yet
+    assert_eq!(probe(port).status.code(), Some(8));
+
+    let cmd_path = Command::cargo_bin("tftp").unwrap().get_program().to_os_string();
+    let mut server = std::process::Command::new(cmd_path)
+        .arg("server")
+        .arg("--rootdir").arg(server_root.path())
+        .arg("--port").arg(format!("{}", port))
+        .spawn().unwrap();
+
+    thread::sleep(Duration::from_secs(2));
+
+    let output = probe(port);
+
+    let _ = server.kill();
+    let _ = server.wait();
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(output.status.code(), Some(0), "{}", stdout);
+    assert!(stdout.contains("rtt = "), "{}", stdout);
+
+    Ok(())
+}
+
+#[test]
+fn download_continue() -> Result<(), Box<dyn std::error::Error>> {
+    let port = 55030;
+    let server_root = assert_fs::TempDir::new().unwrap().into_persistent();
+    let client_root = assert_fs::TempDir::new().unwrap().into_persistent();
+    let data = generate_data(5000);
+    server_root.child("download.bin").write_binary(&data).unwrap();
+
+    //differs from the server; only kept if the server skips these bytes
+    client_root.child("download.bin.part").write_binary(&vec![0xaa; 1300]).unwrap();
+
+    let cmd_path = Command::cargo_bin("tftp").unwrap().get_program().to_os_string();
+    let mut server = std::process::Command::new(cmd_path)
+        .arg("server")
+        .arg("--rootdir").arg(server_root.path())
+        .arg("--port").arg(format!("{}", port))
+        .spawn().unwrap();
+
+    thread::sleep(Duration::from_secs(2));
+
+    let output = Command::cargo_bin("tftp").unwrap()
+        .current_dir(client_root.path())
+        .arg("client")
+        .arg("--remote").arg(format!("127.0.0.1:{}", port))
+        .arg("--download").arg("download.bin")
+        .arg("--continue")
+        .output().unwrap();
+
+    let _ = server.kill();
+    let _ = server.wait();
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(output.status.code(), Some(0), "{}", stdout);
+    assert!(stdout.contains("continue at offset 1300"), "{}", stdout);
+
+    let mut expected = vec![0xaa; 1300];
+    expected.extend_from_slice(&data[1300..]);
+    assert_eq!(std::fs::read(client_root.path().join("download.bin")).unwrap(), expected);
+    assert!(!client_root.path().join("download.bin.part").exists());
+
+    Ok(())
+}
+
+#[test]
+fn download_auto_decompress() -> Result<(), Box<dyn std::error::Error>> {
+    use std::io::Write;
+
+    let port = 55031;
+    let server_root = assert_fs::TempDir::new().unwrap().into_persistent();
+    let client_root = assert_fs::TempDir::new().unwrap().into_persistent();
+    let data = generate_data(5000);
+
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(&data).unwrap();
+    server_root.child("download.bin.gz").write_binary(&encoder.finish().unwrap()).unwrap();
+
+    let cmd_path = Command::cargo_bin("tftp").unwrap().get_program().to_os_string();
+    let mut server = std::process::Command::new(cmd_path)
+        .arg("server")
+        .arg("--rootdir").arg(server_root.path())
+        .arg("--port").arg(format!("{}", port))
+        .arg("--auto-decompress")
+        .spawn().unwrap();
+
+    thread::sleep(Duration::from_secs(2));
+
+    let output = Command::cargo_bin("tftp").unwrap()
+        .current_dir(client_root.path())
+        .arg("client")
+        .arg("--remote").arg(format!("127.0.0.1:{}", port))
+        .arg("--download").arg("download.bin")
+        .arg("--tsize")
+        .output().unwrap();
+
+    //the compressed file itself is still served as it is
+    let gz_code = download_as(port, client_root.path(), "download.bin.gz");
+
+    let _ = server.kill();
+    let _ = server.wait();
+
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stdout));
+    assert_eq!(std::fs::read(client_root.path().join("download.bin")).unwrap(), data);
+    assert_eq!(gz_code, 0);
+    assert_eq!(std::fs::read(client_root.path().join("download.bin.gz")).unwrap(), std::fs::read(server_root.path().join("download.bin.gz")).unwrap());
+
+    Ok(())
+}
+
+#[test]
+fn xcompress_both_directions() -> Result<(), Box<dyn std::error::Error>> {
+    let port = 55032;
+    let server_root = assert_fs::TempDir::new().unwrap().into_persistent();
+    let client_root = assert_fs::TempDir::new().unwrap().into_persistent();
+    let data = generate_data(5000);
+    server_root.child("download.bin").write_binary(&data).unwrap();
+    client_root.child("upload.bin").write_binary(&data).unwrap();
+
+    let cmd_path = Command::cargo_bin("tftp").unwrap().get_program().to_os_string();
+    let mut server = std::process::Command::new(cmd_path)
+        .arg("server")
+        .arg("--rootdir").arg(server_root.path())
+        .arg("--port").arg(format!("{}", port))
+        .arg("--writemode").arg("overwrite")
+        .stdout(std::process::Stdio::piped())
+        .spawn().unwrap();
+
+    thread::sleep(Duration::from_secs(2));
+
+    let transfer = |direction: &str, name: &str| {
+        Command::cargo_bin("tftp").unwrap()
+            .current_dir(client_root.path())
+            .arg("client")
+            .arg("--remote").arg(format!("127.0.0.1:{}", port))
+            .arg(direction).arg(name)
+            .arg("--xcompress")
+            .output().unwrap()
+    };
+    let download = transfer("--download", "download.bin");
+    let upload   = transfer("--upload", "upload.bin");
+
+    let _ = server.kill();
+    let server_log = server.wait_with_output().unwrap();
+
+    for i_output in [&download, &upload] {
+        assert_eq!(i_output.status.code(), Some(0), "{}", String::from_utf8_lossy(&i_output.stdout));
+    }
+    let server_log = String::from_utf8_lossy(&server_log.stdout);
+    assert_eq!(server_log.matches("xcompress=deflate").count(), 2, "{}", server_log);
+    assert_eq!(std::fs::read(client_root.path().join("download.bin")).unwrap(), data);
+    assert_eq!(std::fs::read(server_root.path().join("upload.bin")).unwrap(), data);
+
+    Ok(())
+}
+
+#[test]
+fn download_not_found() -> Result<(), Box<dyn std::error::Error>> {
+    let port = 55025;
+    let server_root = assert_fs::TempDir::new().unwrap().into_persistent();
+    let client_root = assert_fs::TempDir::new().unwrap().into_persistent();
+
+    let cmd_path = Command::cargo_bin("tftp").unwrap().get_program().to_os_string();
+    let mut server = std::process::Command::new(cmd_path)
+        .arg("server")
+        .arg("--rootdir").arg(server_root.path())
+        .arg("--port").arg(format!("{}", port))
+        .spawn().unwrap();
+
+    thread::sleep(Duration::from_secs(2));
+
+    let output = Command::cargo_bin("tftp").unwrap()
+        .current_dir(client_root.path())
+        .arg("client")
+        .arg("--remote").arg(format!("127.0.0.1:{}", port))
+        .arg("--download").arg("missing.bin")
+        .arg("--keep-partial")
+        .output().unwrap();
+
+    let _ = server.kill();
+    let _ = server.wait();
+
+    //no local file is created before the server accepted the request
+    assert_eq!(output.status.code(), Some(3));
+    assert!(!client_root.path().join("missing.bin").exists());
+    assert!(!client_root.path().join("missing.bin.part").exists());
+
+    Ok(())
+}
+
+#[test]
+fn request_sent_again() -> Result<(), Box<dyn std::error::Error>> {
+    let port = 55026;
+    let client_root = assert_fs::TempDir::new().unwrap().into_persistent();
+
+    //a slow server which misses the first request
+    let server = std::net::UdpSocket::bind(("127.0.0.1", port)).unwrap();
+    server.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+
+    let mut client = Command::cargo_bin("tftp").unwrap()
+        .current_dir(client_root.path())
+        .arg("client")
+        .arg("--remote").arg(format!("127.0.0.1:{}", port))
+        .arg("--download").arg("missing.bin")
+        .arg("--blksize").arg("1024")
+        .spawn().unwrap();
+
+    let mut first = vec![0; 512];
+    let (size, _) = server.recv_from(&mut first).unwrap();
+    first.truncate(size);
+
+    let mut second = vec![0; 512];
+    let (size, src) = server.recv_from(&mut second).unwrap();
+    second.truncate(size);
+    server.send_to(b"\x00\x05\x00\x01not found\x00", src).unwrap();
+
+    let status = client.wait().unwrap();
+
+    assert_eq!(first, second);
+    assert_eq!(status.code(), Some(3));
+
+    Ok(())
+}
+
+fn download_as(port: u16, client_root: &Path, remote: &str) -> i32 {
+    return Command::cargo_bin("tftp").unwrap()
+        .current_dir(client_root)
+        .arg("client")
+        .arg("--remote").arg(format!("127.0.0.1:{}", port))
+        .arg("--download").arg(remote)
+        .status().unwrap().code().unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn symlink_outside_root() -> Result<(), Box<dyn std::error::Error>> {
+    let outside = assert_fs::TempDir::new().unwrap().into_persistent();
+    outside.child("secret.bin").write_binary(b"secret").unwrap();
+
+    let server_root = assert_fs::TempDir::new().unwrap().into_persistent();
+    std::os::unix::fs::symlink(outside.path().join("secret.bin"), server_root.path().join("file_link.bin")).unwrap();
+    std::os::unix::fs::symlink(outside.path(), server_root.path().join("dir_link")).unwrap();
+
+    for (port, follow_arg, expected_code) in [(55027, "--no-follow-symlinks", 4), (55028, "--follow-symlinks", 0)] {
+        let client_root = assert_fs::TempDir::new().unwrap().into_persistent();
+        let cmd_path = Command::cargo_bin("tftp").unwrap().get_program().to_os_string();
+        let mut server = std::process::Command::new(cmd_path)
+            .arg("server")
+            .arg("--rootdir").arg(server_root.path())
+            .arg("--port").arg(format!("{}", port))
+            .arg(follow_arg)
+            .spawn().unwrap();
+
+        thread::sleep(Duration::from_secs(2));
+        let file_code = download_as(port, client_root.path(), "file_link.bin");
+        let dir_code  = download_as(port, client_root.path(), "dir_link/secret.bin");
+
+        let _ = server.kill();
+        let _ = server.wait();
+
+        assert_eq!((file_code, dir_code), (expected_code, expected_code), "{}", follow_arg);
+        assert_eq!(client_root.path().join("secret.bin").exists(), expected_code == 0);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn client_errors_exit_code() -> Result<(), Box<dyn std::error::Error>> {
+    let client_root = assert_fs::TempDir::new().unwrap();
+
+    let client = |remote: &str, local: &str| {
+        Command::cargo_bin("tftp").unwrap()
+            .current_dir(client_root.path())
+            .arg("client")
+            .arg("--remote").arg(remote)
+            .arg("--upload").arg(local)
+            .output().unwrap()
+    };
+
+    //a generic error and not a panic
+    assert_eq!(client("no:valid:remote", "missing.bin").status.code(), Some(1));
+    assert_eq!(upload_as(55033, client_root.path(), "missing.bin"), 1);
+
+    return Ok(());
+}
+
+#[test]
+fn download_dir_local_errors() -> Result<(), Box<dyn std::error::Error>> {
+    let client_root = assert_fs::TempDir::new().unwrap();
+    //"sub" is a file so the directory of the entry can't be created
+    fs::write(client_root.path().join("sub"), b"")?;
+    fs::write(client_root.path().join("manifest.txt"), b"sub/a.bin\t1\n")?;
+
+    let client = |manifest: &str| {
+        Command::cargo_bin("tftp").unwrap()
+            .current_dir(client_root.path())
+            .arg("client")
+            .arg("--remote").arg("127.0.0.1:55034")
+            .arg("--download-dir").arg("images").arg(".")
+            .arg("--manifest").arg(manifest)
+            .output().unwrap()
+    };
+
+    assert_eq!(client("missing.txt").status.code(), Some(1));
+    assert_eq!(client("manifest.txt").status.code(), Some(1));
+
+    return Ok(());
+}
+
+#[test]
+fn verify_mismatch_keep_partial() -> Result<(), Box<dyn std::error::Error>> {
+    let port = 55035;
+    let server_root = assert_fs::TempDir::new().unwrap().into_persistent();
+    let client_root = assert_fs::TempDir::new().unwrap().into_persistent();
+    fs::write(server_root.path().join("download.bin"), generate_data(1000))?;
+
+    let cmd_path = Command::cargo_bin("tftp").unwrap().get_program().to_os_string();
+    let mut server = std::process::Command::new(cmd_path)
+        .arg("server")
+        .arg("--rootdir").arg(server_root.path())
+        .arg("--port").arg(format!("{}", port))
+        .spawn().unwrap();
+
+    thread::sleep(Duration::from_secs(2));
+
+    let output = Command::cargo_bin("tftp").unwrap()
+        .current_dir(client_root.path())
+        .arg("client")
+        .arg("--remote").arg(format!("127.0.0.1:{}", port))
+        .arg("--download").arg("download.bin")
+        .arg("--verify").arg("0".repeat(64))
+        .arg("--keep-partial")
+        .output().unwrap();
+
+    let _ = server.kill();
+    let _ = server.wait();
+
+    //the part file stays for a later --continue
+    assert_eq!(output.status.code(), Some(7));
+    assert!(!client_root.path().join("download.bin").exists());
+    assert_eq!(fs::read(client_root.path().join("download.bin.part"))?, generate_data(1000));
+
+    Ok(())
+}